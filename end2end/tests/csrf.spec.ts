@@ -0,0 +1,67 @@
+import { test, expect } from "@playwright/test";
+
+// Server functions only take requests from our own pages (or trusted origins), so another site
+// can't log people in or out behind their back.
+
+/** Where the log in form posts to. Server function urls have a hash on the end. */
+async function logInAction(page): Promise<string> {
+  await page.goto("http://localhost:3000/login");
+  const action = await page.locator("form").first().getAttribute("action");
+  return new URL(action!, "http://localhost:3000").toString();
+}
+
+const form = { username: "nobody", password: "wrong" };
+
+test("cross-origin posts are refused", async ({ page, request }) => {
+  const action = await logInAction(page);
+
+  const res = await request.post(action, {
+    form,
+    headers: { Origin: "https://evil.example" },
+  });
+  expect(res.status()).toBe(403);
+});
+
+test("cross-site fetch metadata is refused", async ({ page, request }) => {
+  const action = await logInAction(page);
+
+  const res = await request.post(action, {
+    form,
+    headers: { "Sec-Fetch-Site": "cross-site", Origin: "https://evil.example" },
+  });
+  expect(res.status()).toBe(403);
+
+  // Sibling subdomains aren't trusted either
+  const sameSite = await request.post(action, {
+    form,
+    headers: { "Sec-Fetch-Site": "same-site", Origin: "http://other.localhost:3000" },
+  });
+  expect(sameSite.status()).toBe(403);
+});
+
+test("opaque origins are refused", async ({ page, request }) => {
+  const action = await logInAction(page);
+
+  const res = await request.post(action, { form, headers: { Origin: "null" } });
+  expect(res.status()).toBe(403);
+});
+
+test("same-origin posts get through", async ({ page, request }) => {
+  const action = await logInAction(page);
+
+  const res = await request.post(action, {
+    form,
+    headers: { Origin: "http://localhost:3000", "Sec-Fetch-Site": "same-origin" },
+  });
+  expect(res.status()).not.toBe(403);
+});
+
+test("logging in from the page still works", async ({ page }) => {
+  await page.goto("http://localhost:3000/login");
+  await page.fill("input[name=username]", "nobody");
+  await page.fill("input[name=password]", "wrong");
+  await page.click("input[type=submit][value='Log In']");
+
+  // Gets as far as checking the password, rather than being turned away
+  await expect(page.locator("body")).not.toContainText("Cross-site request refused");
+});
@@ -0,0 +1,2 @@
+-- See auth::Role for what goes in here
+ALTER TABLE user ADD COLUMN role TEXT NOT NULL DEFAULT 'user';
@@ -0,0 +1,2 @@
+-- Which authenticator (local, ldap, htpasswd) let them in last
+ALTER TABLE user ADD COLUMN auth_source TEXT;
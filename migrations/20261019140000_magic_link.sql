@@ -0,0 +1,9 @@
+-- Somewhere to send sign in links. NULLs don't count towards UNIQUE so it stays optional.
+ALTER TABLE user ADD COLUMN email TEXT;
+CREATE UNIQUE INDEX user_email ON user (email);
+
+-- One row per sign in link that's been sent, see magic_link.rs
+CREATE TABLE IF NOT EXISTS magic_link (nonce TEXT PRIMARY KEY,
+                                       user_id INTEGER NOT NULL REFERENCES user (id),
+                                       expires_at INTEGER NOT NULL,
+                                       used_at INTEGER);
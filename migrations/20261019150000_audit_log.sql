@@ -0,0 +1,7 @@
+-- Security relevant things that happened, see audit.rs
+CREATE TABLE IF NOT EXISTS audit_log (id INTEGER PRIMARY KEY AUTOINCREMENT,
+                                      at INTEGER NOT NULL DEFAULT (unixepoch()),
+                                      user_id INTEGER REFERENCES user (id),
+                                      event TEXT NOT NULL,
+                                      detail TEXT NOT NULL DEFAULT '');
+CREATE INDEX audit_log_user ON audit_log (user_id);
@@ -0,0 +1,5 @@
+-- Break glass codes for when the second factor's gone missing. Only the hashes are kept.
+CREATE TABLE IF NOT EXISTS recovery_code (user_id INTEGER NOT NULL REFERENCES user (id),
+                                          code_hash TEXT NOT NULL,
+                                          used_at INTEGER,
+                                          PRIMARY KEY (user_id, code_hash));
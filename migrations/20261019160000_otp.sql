@@ -0,0 +1,14 @@
+-- Where someone's second factor codes go. NULL channel means they haven't turned it on.
+ALTER TABLE user ADD COLUMN otp_channel TEXT;
+ALTER TABLE user ADD COLUMN otp_destination TEXT;
+
+-- The code each user's been sent and not entered yet, see otp.rs
+CREATE TABLE IF NOT EXISTS otp_challenge (user_id INTEGER PRIMARY KEY REFERENCES user (id),
+                                          purpose TEXT NOT NULL,
+                                          channel TEXT NOT NULL,
+                                          destination TEXT NOT NULL,
+                                          code_hash TEXT NOT NULL,
+                                          salt TEXT NOT NULL,
+                                          attempts INTEGER NOT NULL DEFAULT 0,
+                                          sent_at INTEGER NOT NULL,
+                                          expires_at INTEGER NOT NULL);
@@ -0,0 +1,15 @@
+-- Permissions handed to individual users on top of what their role gives them, see permission.rs
+CREATE TABLE IF NOT EXISTS user_permission (user_id INTEGER NOT NULL REFERENCES user (id),
+                                            permission TEXT NOT NULL,
+                                            PRIMARY KEY (user_id, permission));
+
+-- Codes that let people sign up when registration is invite only, see invite.rs. Only the hashes
+-- are kept. NULL max_uses/expires_at means no limit.
+CREATE TABLE IF NOT EXISTS invite (id INTEGER PRIMARY KEY AUTOINCREMENT,
+                                   code_hash TEXT NOT NULL UNIQUE,
+                                   created_by INTEGER REFERENCES user (id),
+                                   role TEXT NOT NULL DEFAULT 'user',
+                                   max_uses INTEGER,
+                                   uses INTEGER NOT NULL DEFAULT 0,
+                                   expires_at INTEGER,
+                                   created_at INTEGER NOT NULL DEFAULT (unixepoch()));
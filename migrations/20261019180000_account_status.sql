@@ -0,0 +1,6 @@
+-- See auth::AccountStatus
+ALTER TABLE user ADD COLUMN status TEXT NOT NULL DEFAULT 'active';
+-- Unix seconds. Only means anything while status is 'locked', which ends by itself after this.
+ALTER TABLE user ADD COLUMN locked_until INTEGER;
+-- Shown to them when they try to log in
+ALTER TABLE user ADD COLUMN status_reason TEXT;
@@ -0,0 +1,8 @@
+-- All optional, they fill them in on their profile page
+ALTER TABLE user ADD COLUMN display_name TEXT;
+-- File name under avatars.dir, changes with every upload so it can be cached forever
+ALTER TABLE user ADD COLUMN avatar TEXT;
+-- An IANA name like Europe/London
+ALTER TABLE user ADD COLUMN timezone TEXT;
+-- A BCP 47 tag like en-GB
+ALTER TABLE user ADD COLUMN locale TEXT;
@@ -0,0 +1,14 @@
+-- Groups of users that get permissions together, see group.rs. Members of a group are also
+-- treated as members of its parent, and so on up.
+CREATE TABLE IF NOT EXISTS user_group (id INTEGER PRIMARY KEY AUTOINCREMENT,
+                                       name TEXT NOT NULL UNIQUE,
+                                       parent_id INTEGER REFERENCES user_group (id) ON DELETE SET NULL,
+                                       created_at INTEGER NOT NULL DEFAULT (unixepoch()));
+
+CREATE TABLE IF NOT EXISTS group_member (group_id INTEGER NOT NULL REFERENCES user_group (id) ON DELETE CASCADE,
+                                         user_id INTEGER NOT NULL REFERENCES user (id),
+                                         PRIMARY KEY (group_id, user_id));
+
+CREATE TABLE IF NOT EXISTS group_permission (group_id INTEGER NOT NULL REFERENCES user_group (id) ON DELETE CASCADE,
+                                             permission TEXT NOT NULL,
+                                             PRIMARY KEY (group_id, permission));
@@ -0,0 +1,14 @@
+-- Customer organizations, see org.rs. People can be in any number of them, with a role in each.
+CREATE TABLE IF NOT EXISTS organization (id INTEGER PRIMARY KEY AUTOINCREMENT,
+                                         slug TEXT NOT NULL UNIQUE,
+                                         name TEXT NOT NULL,
+                                         created_at INTEGER NOT NULL DEFAULT (unixepoch()));
+
+CREATE TABLE IF NOT EXISTS org_member (org_id INTEGER NOT NULL REFERENCES organization (id),
+                                       user_id INTEGER NOT NULL REFERENCES user (id),
+                                       role TEXT NOT NULL DEFAULT 'member',
+                                       PRIMARY KEY (org_id, user_id));
+
+-- Invites can also put whoever uses them into an organization
+ALTER TABLE invite ADD COLUMN org_id INTEGER REFERENCES organization (id);
+ALTER TABLE invite ADD COLUMN org_role TEXT;
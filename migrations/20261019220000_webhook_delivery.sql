@@ -0,0 +1,16 @@
+-- Webhooks waiting to go out, and a log of the ones that have, see webhook.rs. There's a row per
+-- event per endpoint, so each endpoint gets retried on its own.
+CREATE TABLE IF NOT EXISTS webhook_delivery (id INTEGER PRIMARY KEY AUTOINCREMENT,
+                                             event_id TEXT NOT NULL,
+                                             event TEXT NOT NULL,
+                                             url TEXT NOT NULL,
+                                             payload TEXT NOT NULL,
+                                             status TEXT NOT NULL DEFAULT 'pending',
+                                             attempts INTEGER NOT NULL DEFAULT 0,
+                                             next_attempt_at INTEGER NOT NULL DEFAULT (unixepoch()),
+                                             last_response INTEGER,
+                                             last_error TEXT,
+                                             created_at INTEGER NOT NULL DEFAULT (unixepoch()),
+                                             delivered_at INTEGER);
+
+CREATE INDEX IF NOT EXISTS webhook_delivery_due ON webhook_delivery (status, next_attempt_at);
@@ -0,0 +1,14 @@
+-- Each purpose gets its own challenge, so starting to set up two factor doesn't throw away the
+-- code someone's halfway through logging in with. Challenges only last minutes, so there's
+-- nothing worth copying over.
+DROP TABLE otp_challenge;
+CREATE TABLE otp_challenge (user_id INTEGER NOT NULL REFERENCES user (id),
+                            purpose TEXT NOT NULL,
+                            channel TEXT NOT NULL,
+                            destination TEXT NOT NULL,
+                            code_hash TEXT NOT NULL,
+                            salt TEXT NOT NULL,
+                            attempts INTEGER NOT NULL DEFAULT 0,
+                            sent_at INTEGER NOT NULL,
+                            expires_at INTEGER NOT NULL,
+                            PRIMARY KEY (user_id, purpose));
@@ -0,0 +1,2 @@
+-- Whoever was impersonating `user_id` when it happened, see audit.rs
+ALTER TABLE audit_log ADD COLUMN impersonator_id INTEGER REFERENCES user (id);
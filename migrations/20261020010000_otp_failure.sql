@@ -0,0 +1,8 @@
+-- Wrong codes for each user and purpose, across every code they've been sent, so sending a new
+-- one doesn't mean more guesses. See `Lockout` in otp.rs.
+CREATE TABLE IF NOT EXISTS otp_failure (user_id INTEGER NOT NULL REFERENCES user (id),
+                                        purpose TEXT NOT NULL,
+                                        failures INTEGER NOT NULL DEFAULT 0,
+                                        lockouts INTEGER NOT NULL DEFAULT 0,
+                                        locked_until INTEGER,
+                                        PRIMARY KEY (user_id, purpose));
@@ -0,0 +1,509 @@
+use leptos::*;
+use leptos_router::*;
+
+use crate::app::{FieldError, FormError};
+use crate::auth_error::{self, AuthError};
+
+/// Gets the logged in user, or tells them to log in (or finish logging in).
+#[cfg(feature = "ssr")]
+pub async fn require_user() -> Result<crate::auth::User, AuthError> {
+    use crate::auth::AuthSession;
+    use crate::otp::pending_login;
+    use axum_login::tower_sessions::Session;
+
+    if let Some(user) = expect_context::<AuthSession>().user {
+        return Ok(user);
+    }
+    match pending_login(&expect_context::<Session>()).await? {
+        Some(_) => Err(AuthError::NeedsSecondFactor),
+        None => Err(AuthError::NotLoggedIn),
+    }
+}
+
+/// How many times someone can be asked for their password again in an hour
+#[cfg(feature = "ssr")]
+const MAX_PASSWORD_CHECKS: usize = 10;
+
+/// Makes them type their password again before changing how they log in, so finding their
+/// session open somewhere isn't enough. Limited, so it can't be used to guess their password.
+#[cfg(feature = "ssr")]
+async fn confirm_password(user: &crate::auth::User, password: String) -> Result<(), AuthError> {
+    use crate::state::AppState;
+
+    let state = expect_context::<AppState>();
+    if let Err(seconds) = state.password_checks.hit(&user.id.to_string(), MAX_PASSWORD_CHECKS) {
+        return Err(AuthError::TooManyRequests {
+            message: "You've been asked for your password too many times, try again later"
+                .to_owned(),
+            retry_after: Some(seconds),
+        });
+    }
+
+    // Whoever let them in checks it, since people from a directory don't have a password here
+    match state.auth.verify_password(user, &password).await? {
+        true => Ok(()),
+        false => Err(AuthError::validation("password", "That's not your password")),
+    }
+}
+
+/// Refuses to change how someone logs in while an admin's impersonating them. Seeing what they
+/// see is one thing, taking over their account is another.
+#[cfg(feature = "ssr")]
+async fn not_impersonating() -> Result<(), AuthError> {
+    use crate::impersonate;
+    use axum_login::tower_sessions::Session;
+
+    match impersonate::current(&expect_context::<Session>()).await? {
+        Some(_) => Err(AuthError::Forbidden),
+        None => Ok(()),
+    }
+}
+
+/// What the account page shows
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct AccountInfo {
+    /// Where their codes go, e.g. `("email", "me@example.com")`
+    pub second_factor: Option<(String, String)>,
+    /// Which channels codes can be sent by here, empty if two factor isn't set up
+    pub channels: Vec<String>,
+    pub recovery_codes_left: i64,
+    /// Including the ones they're only in through another group
+    pub groups: Vec<String>,
+    /// Whether their password is kept here, so they can change it here
+    pub local_password: bool,
+}
+
+/// Whether `user` logs in with a password we keep, rather than one from a directory
+#[cfg(feature = "ssr")]
+fn has_local_password(user: &crate::auth::User) -> bool {
+    use crate::auth::NO_PASSWORD;
+
+    user.pw_hash != NO_PASSWORD && user.auth_source.as_deref().unwrap_or("local") == "local"
+}
+
+/// Swaps `user`'s password for `new_password`, and lets everyone who needs to know. Gives them
+/// back with the new hash, which their other sessions don't have anymore.
+#[cfg(feature = "ssr")]
+pub(crate) async fn set_password(
+    state: &crate::state::AppState,
+    user: crate::auth::User,
+    new_password: &str,
+) -> Result<crate::auth::User, AuthError> {
+    use crate::audit;
+    use crate::auth::{User, BCRYPT_COST};
+    use crate::metrics;
+    use crate::webhook;
+
+    let pw_hash = metrics::time_hash("hash", || bcrypt::hash(new_password, BCRYPT_COST))?;
+    state.auth.store.update_password(user.id, &pw_hash).await?;
+    let user = User { pw_hash, ..user };
+
+    audit::record(&state.pool, Some(user.id), audit::PASSWORD_CHANGED, "").await;
+    webhook::password_changed(state, &user).await;
+    Ok(user)
+}
+
+/// `None` if they're not logged in
+#[server]
+async fn account_details() -> Result<Option<AccountInfo>, ServerFnError<AuthError>> {
+    use crate::group;
+    use crate::recovery;
+    use crate::state::AppState;
+
+    let user = match require_user().await {
+        Ok(user) => user,
+        Err(AuthError::NotLoggedIn | AuthError::NeedsSecondFactor) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let state = expect_context::<AppState>();
+    let local_password = has_local_password(&user);
+    Ok(Some(AccountInfo {
+        second_factor: user
+            .otp_channel
+            .zip(user.otp_destination)
+            .map(|(channel, destination)| (channel.as_str().to_owned(), destination)),
+        channels: state
+            .otp
+            .as_ref()
+            .map(|otp| {
+                otp.channels()
+                    .into_iter()
+                    .map(|channel| channel.as_str().to_owned())
+                    .collect()
+            })
+            .unwrap_or_default(),
+        recovery_codes_left: recovery::remaining(&state.pool, user.id)
+            .await
+            .map_err(AuthError::from)?,
+        groups: group::of_user(&state.pool, user.id)
+            .await
+            .map_err(AuthError::from)?,
+        local_password,
+    }))
+}
+
+/// Sends a code to check they've got the right address/number before turning it on
+#[server(StartTwoFactor)]
+async fn start_two_factor(
+    channel: String,
+    destination: String,
+    password: String,
+) -> Result<(), ServerFnError<AuthError>> {
+    use crate::otp::{OtpChannel, Purpose};
+    use crate::state::AppState;
+
+    let user = require_user().await?;
+    let state = expect_context::<AppState>();
+    let Some(otp) = &state.otp else {
+        return Err(AuthError::rejected("Two factor isn't set up here").into());
+    };
+    not_impersonating().await?;
+    confirm_password(&user, password).await?;
+
+    let channel = match channel.as_str() {
+        "email" => OtpChannel::Email,
+        "sms" => OtpChannel::Sms,
+        _ => return Err(AuthError::validation("channel", "Pick email or SMS").into()),
+    };
+    let destination = destination.trim();
+    if destination.is_empty() {
+        return Err(
+            AuthError::validation("destination", "Where should we send your codes?").into(),
+        );
+    }
+    if channel == OtpChannel::Email && destination.parse::<lettre::Address>().is_err() {
+        return Err(AuthError::validation(
+            "destination",
+            "That doesn't look like an email address",
+        )
+        .into());
+    }
+
+    otp.send_code(user.id, Purpose::Enroll, channel, destination)
+        .await
+        .map_err(AuthError::from)?;
+    Ok(())
+}
+
+/// Turns two factor on once they've shown they got the code, and hands out recovery codes
+#[server(ConfirmTwoFactor)]
+async fn confirm_two_factor(code: String) -> Result<Vec<String>, ServerFnError<AuthError>> {
+    use crate::audit;
+    use crate::otp::Purpose;
+    use crate::recovery;
+    use crate::state::AppState;
+
+    let user = require_user().await?;
+    let state = expect_context::<AppState>();
+    let Some(otp) = &state.otp else {
+        return Err(AuthError::rejected("Two factor isn't set up here").into());
+    };
+    not_impersonating().await?;
+
+    let challenge = otp
+        .verify(user.id, Purpose::Enroll, &code)
+        .await
+        .map_err(AuthError::from)?;
+    state
+        .auth
+        .store
+        .set_second_factor(user.id, Some((challenge.channel, &challenge.destination)))
+        .await
+        .map_err(AuthError::from)?;
+    audit::record(
+        &state.pool,
+        Some(user.id),
+        audit::SECOND_FACTOR_ENABLED,
+        challenge.channel.as_str(),
+    )
+    .await;
+
+    Ok(recovery::regenerate(&state.pool, user.id)
+        .await
+        .map_err(AuthError::from)?)
+}
+
+#[server(DisableTwoFactor)]
+async fn disable_two_factor(password: String) -> Result<(), ServerFnError<AuthError>> {
+    use crate::audit;
+    use crate::recovery;
+    use crate::state::AppState;
+
+    let user = require_user().await?;
+    let state = expect_context::<AppState>();
+    not_impersonating().await?;
+    confirm_password(&user, password).await?;
+
+    state
+        .auth
+        .store
+        .set_second_factor(user.id, None)
+        .await
+        .map_err(AuthError::from)?;
+    // They're no use without a second factor to recover
+    recovery::clear(&state.pool, user.id)
+        .await
+        .map_err(AuthError::from)?;
+    audit::record(
+        &state.pool,
+        Some(user.id),
+        audit::SECOND_FACTOR_DISABLED,
+        "",
+    )
+    .await;
+
+    Ok(())
+}
+
+#[server(RegenerateRecoveryCodes)]
+async fn regenerate_recovery_codes() -> Result<Vec<String>, ServerFnError<AuthError>> {
+    use crate::recovery;
+    use crate::state::AppState;
+
+    let user = require_user().await?;
+    let state = expect_context::<AppState>();
+    not_impersonating().await?;
+
+    Ok(recovery::regenerate(&state.pool, user.id)
+        .await
+        .map_err(AuthError::from)?)
+}
+
+#[server(ChangePassword)]
+async fn change_password(
+    password: String,
+    new_password: String,
+) -> Result<(), ServerFnError<AuthError>> {
+    use crate::auth::AuthSession;
+    use crate::state::AppState;
+
+    let user = require_user().await?;
+    let state = expect_context::<AppState>();
+    not_impersonating().await?;
+    if !has_local_password(&user) {
+        return Err(AuthError::rejected(
+            "Your password isn't kept here, change it wherever you got it from",
+        )
+        .into());
+    }
+    if new_password.is_empty() {
+        return Err(AuthError::validation("new_password", "Pick a new password").into());
+    }
+    confirm_password(&user, password).await?;
+
+    let user = set_password(&state, user, &new_password).await?;
+    // The new hash logs out every other session, but this one should stay
+    expect_context::<AuthSession>()
+        .login(&user)
+        .await
+        .map_err(AuthError::from)?;
+    Ok(())
+}
+
+#[component]
+fn ChangePassword() -> impl IntoView {
+    let change_action = create_server_action::<ChangePassword>();
+    let pending = change_action.pending();
+    let ret = change_action.value();
+
+    view! {
+        <h2>"Password"</h2>
+
+        <ActionForm class="credential-form" action=change_action>
+            <label for="password">Current password </label>
+            <input type="password" name="password" autocomplete="current-password"/>
+            <FieldError ret field="password"/>
+            <label for="new_password">New password </label>
+            <input type="password" name="new_password" autocomplete="new-password"/>
+            <FieldError ret field="new_password"/>
+            <input type="submit" value="Change password"/>
+        </ActionForm>
+
+        <p>{move || pending.get().then_some("Working... 🛌")}</p>
+        <FormError ret fields=&["password", "new_password"]/>
+        {move || matches!(ret.get(), Some(Ok(())))
+            .then(|| view! { <p>"Changed! You've been logged out everywhere else."</p> })}
+    }
+}
+
+#[component]
+fn RecoveryCodes(left: i64) -> impl IntoView {
+    let regenerate_action = create_server_action::<RegenerateRecoveryCodes>();
+    let pending = regenerate_action.pending();
+    let ret = regenerate_action.value();
+
+    view! {
+        <h2>"Recovery codes"</h2>
+        <p>"If you lose your second factor, you can log in with one of these instead. Each one works once."</p>
+        <p>"You have " {left} " unused codes."</p>
+
+        <ActionForm class="credential-form" action=regenerate_action>
+            <input type="submit" value="Get new codes"/>
+        </ActionForm>
+
+        <p>{move || pending.get().then_some("Working... 🛌")}</p>
+        {move || match ret.get() {
+            Some(Ok(codes)) => view! {
+                <p>"Write these down somewhere safe, you won't see them again. Your old codes don't work anymore."</p>
+                <ul class="recovery-codes">
+                    {codes.into_iter().map(|code| view! { <li><code>{code}</code></li> }).collect_view()}
+                </ul>
+            }.into_view(),
+            Some(Err(err)) => view! { <p>{auth_error::message(&err)}</p> }.into_view(),
+            None => ().into_view(),
+        }}
+    }
+}
+
+#[component]
+fn EnableTwoFactor(channels: Vec<String>) -> impl IntoView {
+    let start_action = create_server_action::<StartTwoFactor>();
+    let start_ret = start_action.value();
+    let confirm_action = create_server_action::<ConfirmTwoFactor>();
+    let pending =
+        Signal::derive(move || start_action.pending().get() || confirm_action.pending().get());
+    let confirm_ret = confirm_action.value();
+
+    view! {
+        <h2>"Two factor"</h2>
+        <p>"Get a code sent to you each time you log in, so your password alone isn't enough."</p>
+
+        <ActionForm class="credential-form" action=start_action>
+            <label for="channel">Send codes by </label>
+            <select name="channel">
+                {channels.into_iter().map(|channel| view! {
+                    <option value=channel.clone()>{channel.to_uppercase()}</option>
+                }).collect_view()}
+            </select>
+            <label for="destination">To </label>
+            <input type="text" name="destination" placeholder="Email address or phone number"/>
+            <label for="password">Your password </label>
+            <input type="password" name="password"/>
+            <input type="submit" value="Send me a code"/>
+        </ActionForm>
+
+        {move || match start_ret.get() {
+            Some(Ok(())) => view! {
+                <ActionForm class="credential-form" action=confirm_action>
+                    <label for="code">Code </label>
+                    <input type="text" name="code" autocomplete="one-time-code"/>
+                    <input type="submit" value="Turn on two factor"/>
+                </ActionForm>
+            }.into_view(),
+            Some(Err(err)) => view! { <p>{auth_error::message(&err)}</p> }.into_view(),
+            None => ().into_view(),
+        }}
+
+        <p>{move || pending.get().then_some("Working... 🛌")}</p>
+        {move || match confirm_ret.get() {
+            Some(Ok(codes)) => view! {
+                <p>"Two factor is on! If you lose access to your codes, you can log in with one of these instead. \
+                    Write them down somewhere safe, you won't see them again."</p>
+                <ul class="recovery-codes">
+                    {codes.into_iter().map(|code| view! { <li><code>{code}</code></li> }).collect_view()}
+                </ul>
+            }.into_view(),
+            Some(Err(err)) => view! { <p>{auth_error::message(&err)}</p> }.into_view(),
+            None => ().into_view(),
+        }}
+    }
+}
+
+#[component]
+fn TwoFactorEnabled(channel: String, destination: String) -> impl IntoView {
+    let disable_action = create_server_action::<DisableTwoFactor>();
+    let ret = disable_action.value();
+
+    view! {
+        <h2>"Two factor"</h2>
+        <p>"Your codes are sent by " {channel} " to " <code>{destination}</code> "."</p>
+
+        <ActionForm class="credential-form" action=disable_action>
+            <label for="password">Your password </label>
+            <input type="password" name="password"/>
+            <input type="submit" value="Turn off two factor"/>
+        </ActionForm>
+
+        {move || match ret.get() {
+            Some(Ok(())) => view! { <p>"Two factor is off, and your recovery codes are gone."</p> }.into_view(),
+            Some(Err(err)) => view! { <p>{auth_error::message(&err)}</p> }.into_view(),
+            None => ().into_view(),
+        }}
+    }
+}
+
+/// Where users look after their own account
+#[component]
+pub fn Account() -> impl IntoView {
+    let details = create_blocking_resource(|| (), |_| async { account_details().await });
+
+    view! {
+        <h1>"Your account"</h1>
+        <p><A href="/account/profile">"Edit your profile"</A></p>
+        <Suspense fallback=||()>
+        {move || details.get().and_then(|details| details.ok().flatten())
+            .filter(|details| !details.groups.is_empty())
+            .map(|details| view! { <p>"You're in " {details.groups.join(", ")} "."</p> })}
+        {move || details.get().and_then(|details| details.ok().flatten())
+            .filter(|details| details.local_password)
+            .map(|_| view! { <ChangePassword/> })}
+        {move || details.get().map(|details| match details {
+            Ok(Some(AccountInfo { second_factor: Some((channel, destination)), recovery_codes_left, .. })) => view! {
+                <TwoFactorEnabled channel destination/>
+                <RecoveryCodes left=recovery_codes_left/>
+            }.into_view(),
+            Ok(Some(AccountInfo { channels, .. })) if !channels.is_empty() => view! {
+                <EnableTwoFactor channels/>
+            }.into_view(),
+            Ok(Some(_)) => ().into_view(),
+            Ok(None) => view! {
+                <p>"You need to " <A href="/login">"log in"</A> " first."</p>
+            }.into_view(),
+            Err(err) => view! { <p>{auth_error::message(&err)}</p> }.into_view(),
+        })}
+        </Suspense>
+        <A href="/"> Back to homepage </A>
+    }
+}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+    use crate::auth::Credentials;
+    use crate::state::test_state;
+    use crate::webhook;
+    use axum_login::AuthnBackend;
+
+    #[tokio::test]
+    async fn changing_passwords() {
+        let state = test_state(
+            r#"
+            [[webhooks]]
+            url = "http://127.0.0.1:9/hook"
+            secret = "hunter2"
+            events = ["user.password_changed"]
+            "#,
+        )
+        .await;
+        let alice = state
+            .auth
+            .store
+            .create("alice", &bcrypt::hash("hunter2", 4).unwrap())
+            .await
+            .unwrap();
+
+        let alice = set_password(&state, alice, "correct horse").await.unwrap();
+        assert!(bcrypt::verify("correct horse", &alice.pw_hash).unwrap());
+        let creds = |password: &str| Credentials {
+            username: "alice".to_owned(),
+            password: password.to_owned(),
+        };
+        assert!(state.auth.authenticate(creds("hunter2")).await.unwrap().is_none());
+        assert!(state.auth.authenticate(creds("correct horse")).await.unwrap().is_some());
+
+        let deliveries = webhook::list(&state, 10).await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].event, "user.password_changed");
+    }
+}
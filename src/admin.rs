@@ -0,0 +1,925 @@
+use leptos::*;
+use leptos_router::*;
+use serde::{Deserialize, Serialize};
+
+use crate::app::{FieldError, FormError};
+use crate::auth_error::{self, AuthError};
+use crate::error_template::{AppError, ErrorTemplate};
+use crate::import::ImportReport;
+use crate::permission::Permission;
+
+/// An invite as shown on the admin page
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InviteSummary {
+    pub id: i64,
+    pub role: String,
+    /// Slug of the organization it puts them in, and as what
+    pub org: Option<(String, String)>,
+    pub created_by: Option<String>,
+    /// e.g. "2 of 5" or "3 of unlimited"
+    pub uses: String,
+    pub expires: String,
+    /// Expired or used up
+    pub spent: bool,
+}
+
+/// A group as shown on the admin page
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GroupSummary {
+    pub id: i64,
+    pub name: String,
+    /// The group it's inside, if any
+    pub parent: Option<String>,
+    pub members: Vec<String>,
+    /// Only its own, not what it gets from its parent
+    pub permissions: Vec<Permission>,
+}
+
+/// A webhook delivery as shown on the admin page
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeliverySummary {
+    pub id: i64,
+    pub event: String,
+    pub url: String,
+    /// pending, delivered or failed
+    pub status: String,
+    pub attempts: i64,
+    /// e.g. "5 minutes ago"
+    pub created: String,
+    /// When it'll go again, if it's still pending
+    pub next_attempt: Option<String>,
+    /// The status code, or what went wrong, from the last go
+    pub last_result: Option<String>,
+}
+
+/// Gets the logged in user, as long as they're an admin.
+#[cfg(feature = "ssr")]
+pub async fn require_admin() -> Result<crate::auth::User, AuthError> {
+    use crate::account::require_user;
+    use crate::auth::Role;
+
+    let user = require_user().await?;
+    if user.role == Role::Admin {
+        Ok(user)
+    } else {
+        Err(AuthError::Forbidden)
+    }
+}
+
+/// Gets the logged in user, as long as they've got `permission` from their role or a grant.
+#[cfg(feature = "ssr")]
+pub async fn require_permission(permission: Permission) -> Result<crate::auth::User, AuthError> {
+    use crate::account::require_user;
+    use crate::auth::AuthSession;
+    use axum_login::AuthzBackend;
+
+    let user = require_user().await?;
+    let session = expect_context::<AuthSession>();
+    if session.backend.has_perm(&user, permission).await? {
+        Ok(user)
+    } else {
+        Err(AuthError::Forbidden)
+    }
+}
+
+/// Everything the logged in user can do here, so the page knows what to show
+#[server]
+async fn my_permissions() -> Result<Vec<Permission>, ServerFnError<AuthError>> {
+    use crate::account::require_user;
+    use crate::auth::AuthSession;
+    use axum_login::AuthzBackend;
+
+    let user = require_user().await?;
+    let session = expect_context::<AuthSession>();
+    Ok(session
+        .backend
+        .get_all_permissions(&user)
+        .await
+        .map_err(AuthError::from)?
+        .into_iter()
+        .collect())
+}
+
+#[server(ImportUsers)]
+async fn import_users(
+    format: String,
+    contents: String,
+) -> Result<ImportReport, ServerFnError<AuthError>> {
+    use crate::import::{self, ImportFormat};
+    use crate::state::AppState;
+
+    let admin = require_permission(Permission::ImportUsers).await?;
+    let state = expect_context::<AppState>();
+    let format: ImportFormat = match format.parse() {
+        Ok(f) => f,
+        Err(err) => return Err(AuthError::validation("format", err).into()),
+    };
+
+    let report = import::import_users(&*state.auth.store, format, &contents, Some(admin.role))
+        .await
+        .map_err(AuthError::from)?;
+    tracing::info!(
+        admin = admin.username,
+        imported = report.imported.len(),
+        conflicts = report.conflicts.len(),
+        skipped = report.skipped.len(),
+        "Imported users"
+    );
+
+    Ok(report)
+}
+
+/// Renders a list under a heading, or nothing if there's nothing in it.
+fn report_section(title: &'static str, items: Vec<String>) -> impl IntoView {
+    (!items.is_empty()).then(|| {
+        view! {
+            <h3>{title} " (" {items.len()} ")"</h3>
+            <ul>{items.into_iter().map(|item| view! { <li>{item}</li> }).collect_view()}</ul>
+        }
+    })
+}
+
+#[component]
+fn ImportForm() -> impl IntoView {
+    let import_action = create_server_action::<ImportUsers>();
+    let pending = import_action.pending();
+    let ret = import_action.value();
+
+    view! {
+        <h2>"Import users"</h2>
+        <p>"Paste an htpasswd file, or a CSV with username, password_hash or password, and role columns."</p>
+
+        <ActionForm class="credential-form" action=import_action>
+            <label for="format">Format </label>
+            <select name="format">
+                <option value="htpasswd">htpasswd</option>
+                <option value="csv">CSV</option>
+            </select>
+
+            <label for="contents">Users </label>
+            <textarea name="contents" rows="10" cols="60"></textarea>
+
+            <input type="submit" value="Import"/>
+        </ActionForm>
+
+        <p>{move || pending.get().then_some("Working... 🛌")}</p>
+        {move || match ret.get() {
+            Some(Ok(report)) => view! {
+                {report_section("Imported", report.imported)}
+                {report_section("Already exist", report.conflicts)}
+                {report_section("Skipped", report.skipped)}
+            }.into_view(),
+            Some(Err(err)) => view! { <p>{auth_error::message(&err)}</p> }.into_view(),
+            None => ().into_view(),
+        }}
+    }
+}
+
+/// Fills in the names for showing invites on a page
+#[cfg(feature = "ssr")]
+pub async fn summarise_invites(
+    invites: Vec<crate::invite::Invite>,
+) -> Result<Vec<InviteSummary>, AuthError> {
+    use crate::org;
+    use crate::otp::now;
+    use crate::state::AppState;
+
+    let state = expect_context::<AppState>();
+    let mut summaries = Vec::new();
+    for invite in invites {
+        let created_by = match invite.created_by {
+            Some(id) => state
+                .auth
+                .store
+                .find_by_id(id)
+                .await
+                .map_err(AuthError::from)?
+                .map(|u| u.username),
+            None => None,
+        };
+        let used_up = invite.max_uses.is_some_and(|max| invite.uses >= max);
+        let expired = invite.expires_at.is_some_and(|at| at <= now());
+
+        let org = match invite.org_id {
+            Some(id) => Some(org::find_by_id(&state.pool, id).await?.slug),
+            None => None,
+        };
+
+        summaries.push(InviteSummary {
+            id: invite.id,
+            role: invite.role.as_str().to_owned(),
+            org: org.zip(invite.org_role.map(|role| role.as_str().to_owned())),
+            created_by,
+            uses: match invite.max_uses {
+                Some(max) => format!("{} of {max}", invite.uses),
+                None => format!("{} of unlimited", invite.uses),
+            },
+            expires: match invite.expires_at {
+                None => "Never".to_owned(),
+                Some(_) if expired => "Expired".to_owned(),
+                Some(at) => format!("In {} hours", (at - now() + 3599) / 3600),
+            },
+            spent: used_up || expired,
+        });
+    }
+
+    Ok(summaries)
+}
+
+#[server]
+async fn list_invites() -> Result<Vec<InviteSummary>, ServerFnError<AuthError>> {
+    use crate::invite;
+    use crate::state::AppState;
+
+    require_permission(Permission::CreateInvites).await?;
+    let state = expect_context::<AppState>();
+
+    let invites = invite::list(&state.pool, None).await.map_err(AuthError::from)?;
+    Ok(summarise_invites(invites).await?)
+}
+
+/// Gives back the link to send to whoever's invited. Empty fields mean no limit.
+#[server(CreateInvite)]
+async fn create_invite(
+    role: String,
+    max_uses: String,
+    ttl_hours: String,
+) -> Result<String, ServerFnError<AuthError>> {
+    use crate::auth::Role;
+    use crate::invite;
+    use crate::state::AppState;
+
+    let user = require_permission(Permission::CreateInvites).await?;
+    let state = expect_context::<AppState>();
+
+    let role = match role.as_str() {
+        "user" => Role::User,
+        "admin" => Role::Admin,
+        _ => return Err(AuthError::validation("role", format!("Unknown role {role:?}")).into()),
+    };
+    invite::check_role(user.role, role).map_err(|err| AuthError::validation("role", err))?;
+
+    let max_uses =
+        invite::parse_limit(&max_uses).map_err(|err| AuthError::validation("max_uses", err))?;
+    let ttl_hours =
+        invite::parse_limit(&ttl_hours).map_err(|err| AuthError::validation("ttl_hours", err))?;
+
+    let code = invite::create(&state.pool, Some(user.id), role, None, max_uses, ttl_hours)
+        .await
+        .map_err(AuthError::from)?;
+    tracing::info!(user.username, ?role, "Created an invite");
+
+    Ok(invite::link(&state.config.public_url(), &code))
+}
+
+#[server(RevokeInvite)]
+async fn revoke_invite(id: i64) -> Result<(), ServerFnError<AuthError>> {
+    use crate::auth::Role;
+    use crate::invite;
+    use crate::state::AppState;
+
+    let user = require_permission(Permission::CreateInvites).await?;
+    let state = expect_context::<AppState>();
+
+    // Being allowed to make invites doesn't mean being allowed to cancel everyone else's
+    let Some(invite) = invite::find_by_id(&state.pool, id)
+        .await
+        .map_err(AuthError::from)?
+    else {
+        return Err(AuthError::rejected("No such invite").into());
+    };
+    if invite.created_by != Some(user.id) && user.role != Role::Admin {
+        return Err(AuthError::Forbidden.into());
+    }
+
+    if !invite::revoke(&state.pool, id, Some(user.id))
+        .await
+        .map_err(AuthError::from)?
+    {
+        return Err(AuthError::rejected("No such invite").into());
+    }
+    Ok(())
+}
+
+#[component]
+fn Invites() -> impl IntoView {
+    let create_action = create_server_action::<CreateInvite>();
+    let pending = create_action.pending();
+    let ret = create_action.value();
+    let revoke_action = create_server_action::<RevokeInvite>();
+
+    let invites = create_resource(
+        move || (create_action.version().get(), revoke_action.version().get()),
+        |_| async { list_invites().await },
+    );
+
+    view! {
+        <h2>"Invites"</h2>
+        <p>"Send someone an invite link so they can sign up. Leave a limit empty for no limit."</p>
+
+        <ActionForm class="credential-form" action=create_action>
+            <label for="role">Role </label>
+            <select name="role">
+                <option value="user">User</option>
+                <option value="admin">Admin</option>
+            </select>
+
+            <label for="max_uses">Uses </label>
+            <input type="number" name="max_uses" min="1" value="1"/>
+
+            <label for="ttl_hours">Expires after (hours) </label>
+            <input type="number" name="ttl_hours" min="1" value="168"/>
+
+            <input type="submit" value="Create invite"/>
+        </ActionForm>
+
+        <p>{move || pending.get().then_some("Working... 🛌")}</p>
+        {move || match ret.get() {
+            Some(Ok(link)) => view! {
+                <p>"Here's the link, it won't be shown again: " <code>{link}</code></p>
+            }.into_view(),
+            Some(Err(err)) => view! { <p>{auth_error::message(&err)}</p> }.into_view(),
+            None => ().into_view(),
+        }}
+
+        <Transition fallback=||()>
+        {move || invites.get().map(|invites| match invites {
+            Ok(invites) => view! {
+                <table class="invites">
+                    <tr><th>"Role"</th><th>"Organization"</th><th>"Made by"</th><th>"Used"</th><th>"Expires"</th><th></th></tr>
+                    {invites.into_iter().map(|invite| view! {
+                        <tr class:spent=invite.spent>
+                            <td>{invite.role}</td>
+                            <td>{invite.org.map(|(org, role)| format!("{org} ({role})"))}</td>
+                            <td>{invite.created_by.unwrap_or_else(|| "CLI".to_owned())}</td>
+                            <td>{invite.uses}</td>
+                            <td>{invite.expires}</td>
+                            <td>
+                                <ActionForm action=revoke_action>
+                                    <input type="hidden" name="id" value=invite.id/>
+                                    <input type="submit" value="Revoke"/>
+                                </ActionForm>
+                            </td>
+                        </tr>
+                    }).collect_view()}
+                </table>
+            }.into_view(),
+            Err(err) => view! { <p>{auth_error::message(&err)}</p> }.into_view(),
+        })}
+        </Transition>
+    }
+}
+
+/// Stops anyone who can manage groups from handing out (or getting themselves) permissions they
+/// haven't got.
+#[cfg(feature = "ssr")]
+async fn check_can_hand_out(
+    user: &crate::auth::User,
+    permissions: impl IntoIterator<Item = Permission>,
+) -> Result<(), AuthError> {
+    use crate::auth::AuthSession;
+    use axum_login::AuthzBackend;
+
+    let session = expect_context::<AuthSession>();
+    let have = session.backend.get_all_permissions(user).await?;
+    match permissions.into_iter().find(|p| !have.contains(p)) {
+        Some(missing) => Err(AuthError::rejected(format!(
+            "You can't hand out {} since you haven't got it yourself",
+            missing.as_str()
+        ))),
+        None => Ok(()),
+    }
+}
+
+#[server]
+async fn list_groups() -> Result<Vec<GroupSummary>, ServerFnError<AuthError>> {
+    use crate::group;
+    use crate::state::AppState;
+
+    require_permission(Permission::ManageGroups).await?;
+    let state = expect_context::<AppState>();
+
+    let groups = group::list(&state.pool).await.map_err(AuthError::from)?;
+    let mut summaries = Vec::new();
+    for g in &groups {
+        summaries.push(GroupSummary {
+            id: g.id,
+            name: g.name.clone(),
+            parent: g
+                .parent_id
+                .and_then(|id| groups.iter().find(|p| p.id == id))
+                .map(|p| p.name.clone()),
+            members: group::members(&state.pool, g.id).await.map_err(AuthError::from)?,
+            permissions: group::permissions(&state.pool, g.id).await.map_err(AuthError::from)?,
+        });
+    }
+
+    Ok(summaries)
+}
+
+/// An empty `parent` means it's not inside anything
+#[server(CreateGroup)]
+async fn create_group(name: String, parent: String) -> Result<(), ServerFnError<AuthError>> {
+    use crate::group;
+    use crate::state::AppState;
+
+    let user = require_permission(Permission::ManageGroups).await?;
+    let state = expect_context::<AppState>();
+
+    let name = group::normalise_name(&name).map_err(|err| AuthError::validation("name", err))?;
+    let parent = match parent.as_str() {
+        "" => None,
+        parent => Some(group::find(&state.pool, parent).await.map_err(AuthError::from)?),
+    };
+
+    group::create(&state.pool, &name, parent.map(|p| p.id), Some(user.id))
+        .await
+        .map_err(AuthError::from)?;
+    tracing::info!(user.username, name, "Created a group");
+    Ok(())
+}
+
+#[server(DeleteGroup)]
+async fn delete_group(id: i64) -> Result<(), ServerFnError<AuthError>> {
+    use crate::group;
+    use crate::state::AppState;
+
+    let user = require_permission(Permission::ManageGroups).await?;
+    let state = expect_context::<AppState>();
+
+    let group = group::find_by_id(&state.pool, id).await.map_err(AuthError::from)?;
+    group::delete(&state.pool, &group, Some(user.id))
+        .await
+        .map_err(AuthError::from)?;
+    tracing::info!(user.username, group.name, "Deleted a group");
+    Ok(())
+}
+
+#[server(AddGroupMember)]
+async fn add_group_member(id: i64, username: String) -> Result<(), ServerFnError<AuthError>> {
+    use crate::group;
+    use crate::state::AppState;
+
+    let user = require_permission(Permission::ManageGroups).await?;
+    let state = expect_context::<AppState>();
+
+    let group = group::find_by_id(&state.pool, id).await.map_err(AuthError::from)?;
+    let member = state
+        .auth
+        .store
+        .find_by_username(&username.trim().to_lowercase())
+        .await
+        .map_err(AuthError::from)?
+        .ok_or_else(|| AuthError::validation("username", "No one's called that"))?;
+    let permissions = group::effective_permissions(&state.pool, group.id)
+        .await
+        .map_err(AuthError::from)?;
+    check_can_hand_out(&user, permissions).await?;
+
+    group::add_member(&state.pool, &group, member.id, Some(user.id))
+        .await
+        .map_err(AuthError::from)?;
+    Ok(())
+}
+
+#[server(RemoveGroupMember)]
+async fn remove_group_member(id: i64, username: String) -> Result<(), ServerFnError<AuthError>> {
+    use crate::group;
+    use crate::state::AppState;
+
+    let user = require_permission(Permission::ManageGroups).await?;
+    let state = expect_context::<AppState>();
+
+    let group = group::find_by_id(&state.pool, id).await.map_err(AuthError::from)?;
+    if let Some(member) = state
+        .auth
+        .store
+        .find_by_username(&username.trim().to_lowercase())
+        .await
+        .map_err(AuthError::from)?
+    {
+        group::remove_member(&state.pool, &group, member.id, Some(user.id))
+            .await
+            .map_err(AuthError::from)?;
+    }
+    Ok(())
+}
+
+#[server(GrantGroupPermission)]
+async fn grant_group_permission(
+    id: i64,
+    permission: Permission,
+) -> Result<(), ServerFnError<AuthError>> {
+    use crate::group;
+    use crate::state::AppState;
+
+    let user = require_permission(Permission::ManageGroups).await?;
+    let state = expect_context::<AppState>();
+
+    let group = group::find_by_id(&state.pool, id).await.map_err(AuthError::from)?;
+    check_can_hand_out(&user, [permission]).await?;
+    group::grant(&state.pool, &group, permission, Some(user.id))
+        .await
+        .map_err(AuthError::from)?;
+    Ok(())
+}
+
+#[server(RevokeGroupPermission)]
+async fn revoke_group_permission(
+    id: i64,
+    permission: Permission,
+) -> Result<(), ServerFnError<AuthError>> {
+    use crate::group;
+    use crate::state::AppState;
+
+    let user = require_permission(Permission::ManageGroups).await?;
+    let state = expect_context::<AppState>();
+
+    let group = group::find_by_id(&state.pool, id).await.map_err(AuthError::from)?;
+    group::revoke(&state.pool, &group, permission, Some(user.id))
+        .await
+        .map_err(AuthError::from)?;
+    Ok(())
+}
+
+#[component]
+fn Groups() -> impl IntoView {
+    let create_action = create_server_action::<CreateGroup>();
+    let create_ret = create_action.value();
+    let delete_action = create_server_action::<DeleteGroup>();
+    let add_action = create_server_action::<AddGroupMember>();
+    let remove_action = create_server_action::<RemoveGroupMember>();
+    let grant_action = create_server_action::<GrantGroupPermission>();
+    let revoke_action = create_server_action::<RevokeGroupPermission>();
+
+    // However the last of the table's buttons went
+    let ret = create_rw_signal(None);
+    for value in [
+        delete_action.value(),
+        add_action.value(),
+        remove_action.value(),
+        grant_action.value(),
+        revoke_action.value(),
+    ] {
+        create_effect(move |_| {
+            if let Some(value) = value.get() {
+                ret.set(Some(value));
+            }
+        });
+    }
+
+    let groups = create_resource(
+        move || {
+            (
+                create_action.version().get(),
+                delete_action.version().get(),
+                add_action.version().get(),
+                remove_action.version().get(),
+                grant_action.version().get(),
+                revoke_action.version().get(),
+            )
+        },
+        |_| async { list_groups().await },
+    );
+
+    view! {
+        <h2>"Groups"</h2>
+        <p>"Everyone in a group gets its permissions, and those of any group it's inside."</p>
+
+        <ActionForm class="credential-form" action=create_action>
+            <label for="name">Name </label>
+            <input type="text" name="name" maxlength="64"/>
+            <FieldError ret=create_ret field="name"/>
+
+            <label for="parent">Inside </label>
+            <select name="parent">
+                <option value="">"Nothing"</option>
+                {move || groups.get().and_then(Result::ok).unwrap_or_default().into_iter().map(|g| view! {
+                    <option value=g.name.clone()>{g.name}</option>
+                }).collect_view()}
+            </select>
+
+            <input type="submit" value="Create group"/>
+        </ActionForm>
+        <FormError ret=create_ret fields=&["name"]/>
+        <FormError ret fields=&[]/>
+
+        <Transition fallback=||()>
+        {move || groups.get().map(|groups| match groups {
+            Ok(groups) => view! {
+                <table class="groups">
+                    <tr><th>"Group"</th><th>"Inside"</th><th>"Members"</th><th>"Permissions"</th><th></th></tr>
+                    {groups.into_iter().map(|group| view! {
+                        <tr>
+                            <td>{group.name}</td>
+                            <td>{group.parent.unwrap_or_default()}</td>
+                            <td>
+                                {group.members.into_iter().map(|member| view! {
+                                    <ActionForm action=remove_action>
+                                        {member.clone()} " "
+                                        <input type="hidden" name="id" value=group.id/>
+                                        <input type="hidden" name="username" value=member/>
+                                        <input type="submit" value="Remove"/>
+                                    </ActionForm>
+                                }).collect_view()}
+                                <ActionForm action=add_action>
+                                    <input type="hidden" name="id" value=group.id/>
+                                    <input type="text" name="username" placeholder="Username"/>
+                                    <input type="submit" value="Add"/>
+                                </ActionForm>
+                            </td>
+                            <td>
+                                {group.permissions.into_iter().map(|permission| view! {
+                                    <ActionForm action=revoke_action>
+                                        {permission.as_str()} " "
+                                        <input type="hidden" name="id" value=group.id/>
+                                        <input type="hidden" name="permission" value=permission.as_str()/>
+                                        <input type="submit" value="Revoke"/>
+                                    </ActionForm>
+                                }).collect_view()}
+                                <ActionForm action=grant_action>
+                                    <input type="hidden" name="id" value=group.id/>
+                                    <select name="permission">
+                                        {Permission::ALL.iter().map(|p| view! {
+                                            <option value=p.as_str()>{p.as_str()}</option>
+                                        }).collect_view()}
+                                    </select>
+                                    <input type="submit" value="Grant"/>
+                                </ActionForm>
+                            </td>
+                            <td>
+                                <ActionForm action=delete_action>
+                                    <input type="hidden" name="id" value=group.id/>
+                                    <input type="submit" value="Delete"/>
+                                </ActionForm>
+                            </td>
+                        </tr>
+                    }).collect_view()}
+                </table>
+            }.into_view(),
+            Err(err) => view! { <p>{auth_error::message(&err)}</p> }.into_view(),
+        })}
+        </Transition>
+    }
+}
+
+/// Logs them in as `username` until they go back, or it runs out. Sends them to the home page as
+/// that user.
+#[server(StartImpersonating)]
+async fn start_impersonating(username: String) -> Result<(), ServerFnError<AuthError>> {
+    use crate::auth::AuthSession;
+    use crate::impersonate;
+    use crate::state::AppState;
+    use axum_login::tower_sessions::Session;
+    use axum_login::AuthzBackend;
+
+    let admin = require_permission(Permission::ImpersonateUsers).await?;
+    let state = expect_context::<AppState>();
+    let mut auth_session = expect_context::<AuthSession>();
+    let session = expect_context::<Session>();
+
+    if impersonate::current(&session).await.map_err(AuthError::from)?.is_some() {
+        return Err(AuthError::rejected("Go back to your own account first").into());
+    }
+    let user = state
+        .auth
+        .store
+        .find_by_username(&username.trim().to_lowercase())
+        .await
+        .map_err(AuthError::from)?
+        .ok_or_else(|| AuthError::validation("username", "No one's called that"))?;
+    if user.id == admin.id {
+        return Err(AuthError::validation("username", "That's you").into());
+    }
+    if let Err(blocked) = user.check_status() {
+        return Err(AuthError::validation(
+            "username",
+            format!("They can't log in at the moment: {blocked}"),
+        )
+        .into());
+    }
+    // Otherwise it'd be a way to get permissions they haven't got
+    let theirs = auth_session
+        .backend
+        .get_all_permissions(&user)
+        .await
+        .map_err(AuthError::from)?;
+    let ours = auth_session
+        .backend
+        .get_all_permissions(&admin)
+        .await
+        .map_err(AuthError::from)?;
+    if user.role > admin.role || !theirs.is_subset(&ours) {
+        return Err(AuthError::validation(
+            "username",
+            "They can do things you can't, so you can't log in as them",
+        )
+        .into());
+    }
+
+    let minutes = state.live.get().auth.impersonation_minutes;
+    impersonate::start(&state.pool, &mut auth_session, &session, &admin, &user, minutes)
+        .await
+        .map_err(AuthError::from)?;
+
+    leptos_axum::redirect("/");
+    Ok(())
+}
+
+#[component]
+fn Impersonate() -> impl IntoView {
+    let start_action = create_server_action::<StartImpersonating>();
+    let ret = start_action.value();
+
+    view! {
+        <h2>"Impersonate"</h2>
+        <p>"See the site as someone else does. It's logged, and you'll be sent back after a while."</p>
+
+        <ActionForm class="credential-form" action=start_action>
+            <label for="username">Username </label>
+            <input type="text" name="username"/>
+            <FieldError ret field="username"/>
+            <input type="submit" value="Log in as them"/>
+        </ActionForm>
+        <FormError ret fields=&["username"]/>
+    }
+}
+
+/// Roughly how far `at` is from now, e.g. "5 minutes ago" or "in 30 seconds"
+#[cfg(feature = "ssr")]
+fn relative_time(at: i64) -> String {
+    use crate::otp::now;
+
+    let diff = at - now();
+    let secs = diff.abs();
+    let amount = match secs {
+        0 => return "now".to_owned(),
+        1..=59 => format!("{secs} seconds"),
+        60..=3599 => format!("{} minutes", secs / 60),
+        3600..=86399 => format!("{} hours", secs / 3600),
+        _ => format!("{} days", secs / 86400),
+    };
+    if diff < 0 {
+        format!("{amount} ago")
+    } else {
+        format!("in {amount}")
+    }
+}
+
+/// The latest 100 webhook deliveries, newest first
+#[server]
+async fn list_webhook_deliveries() -> Result<Vec<DeliverySummary>, ServerFnError<AuthError>> {
+    use crate::state::AppState;
+    use crate::webhook::{self, DeliveryStatus};
+
+    require_permission(Permission::ManageWebhooks).await?;
+    let state = expect_context::<AppState>();
+
+    let deliveries = webhook::list(&state, 100).await.map_err(AuthError::from)?;
+    Ok(deliveries
+        .into_iter()
+        .map(|delivery| DeliverySummary {
+            id: delivery.id,
+            event: delivery.event,
+            url: delivery.url,
+            status: delivery.status.as_str().to_owned(),
+            attempts: delivery.attempts,
+            created: relative_time(delivery.created_at),
+            next_attempt: (delivery.status == DeliveryStatus::Pending)
+                .then(|| relative_time(delivery.next_attempt_at)),
+            last_result: delivery
+                .last_error
+                .or(delivery.last_response.map(|status| status.to_string())),
+        })
+        .collect())
+}
+
+/// Sends it again straight away, with all its tries back. Works for ones that already went too.
+#[server(RetryWebhookDelivery)]
+async fn retry_webhook_delivery(id: i64) -> Result<(), ServerFnError<AuthError>> {
+    use crate::state::AppState;
+    use crate::webhook;
+
+    let user = require_permission(Permission::ManageWebhooks).await?;
+    let state = expect_context::<AppState>();
+
+    if !webhook::retry(&state, id).await.map_err(AuthError::from)? {
+        return Err(AuthError::rejected("No such delivery").into());
+    }
+    tracing::info!(user.username, id, "Retrying webhook delivery");
+    Ok(())
+}
+
+/// Queues a `webhook.test` event for every configured endpoint
+#[server(SendTestWebhook)]
+async fn send_test_webhook() -> Result<(), ServerFnError<AuthError>> {
+    use crate::state::AppState;
+    use crate::webhook::{self, WebhookEvent};
+
+    let user = require_permission(Permission::ManageWebhooks).await?;
+    let state = expect_context::<AppState>();
+
+    if state.live.get().webhooks.is_empty() {
+        return Err(AuthError::rejected("There aren't any webhooks in the config to send to").into());
+    }
+    let data = serde_json::json!({ "sent_by": user.username });
+    webhook::enqueue(&state, WebhookEvent::Test, data).await;
+    Ok(())
+}
+
+#[component]
+fn WebhookDeliveries() -> impl IntoView {
+    let retry_action = create_server_action::<RetryWebhookDelivery>();
+    let retry_ret = retry_action.value();
+    let test_action = create_server_action::<SendTestWebhook>();
+    let test_ret = test_action.value();
+
+    // Deliveries happen in the background, so this is only as fresh as the last refresh
+    let (refreshed, refresh) = create_signal(0);
+    let deliveries = create_resource(
+        move || (retry_action.version().get(), test_action.version().get(), refreshed.get()),
+        |_| async { list_webhook_deliveries().await },
+    );
+
+    view! {
+        <h2>"Webhooks"</h2>
+        <p>"What's been sent to other services, and what's still waiting to go."</p>
+
+        <ActionForm action=test_action>
+            <input type="submit" value="Send a test event"/>
+        </ActionForm>
+        <button on:click=move |_| refresh.update(|n| *n += 1)>"Refresh"</button>
+        {move || match test_ret.get() {
+            Some(Ok(())) => view! { <p>"Queued, refresh to see how it went"</p> }.into_view(),
+            Some(Err(err)) => view! { <p>{auth_error::message(&err)}</p> }.into_view(),
+            None => ().into_view(),
+        }}
+        {move || retry_ret.get().and_then(Result::err).map(|err| view! {
+            <p>{auth_error::message(&err)}</p>
+        })}
+
+        <Transition fallback=||()>
+        {move || deliveries.get().map(|deliveries| match deliveries {
+            Ok(deliveries) if deliveries.is_empty() => view! { <p>"Nothing's been sent yet"</p> }.into_view(),
+            Ok(deliveries) => view! {
+                <table class="webhook-deliveries">
+                    <tr><th>"Event"</th><th>"Endpoint"</th><th>"Status"</th><th>"Tries"</th><th>"Last result"</th><th>"Queued"</th><th>"Next try"</th><th></th></tr>
+                    {deliveries.into_iter().map(|delivery| {
+                        let label = if delivery.status == "pending" { "Send now" } else { "Retry" };
+                        view! {
+                            <tr class=format!("delivery-{}", delivery.status)>
+                                <td>{delivery.event}</td>
+                                <td><code>{delivery.url}</code></td>
+                                <td>{delivery.status}</td>
+                                <td>{delivery.attempts}</td>
+                                <td>{delivery.last_result}</td>
+                                <td>{delivery.created}</td>
+                                <td>{delivery.next_attempt}</td>
+                                <td>
+                                    <ActionForm action=retry_action>
+                                        <input type="hidden" name="id" value=delivery.id/>
+                                        <input type="submit" value=label/>
+                                    </ActionForm>
+                                </td>
+                            </tr>
+                        }
+                    }).collect_view()}
+                </table>
+            }.into_view(),
+            Err(err) => view! { <p>{auth_error::message(&err)}</p> }.into_view(),
+        })}
+        </Transition>
+    }
+}
+
+/// Renders `error` and sets the response status to go with it.
+fn error_view(error: AppError) -> View {
+    let mut outside_errors = Errors::default();
+    outside_errors.insert_with_default_key(error);
+    view! { <ErrorTemplate outside_errors/> }.into_view()
+}
+
+/// For admins, and anyone who's been given one of the admin-ish permissions
+#[component]
+pub fn Admin() -> impl IntoView {
+    let permissions = create_blocking_resource(|| (), |_| async { my_permissions().await });
+
+    view! {
+        <h1>"Admin"</h1>
+        <Suspense fallback=||()>
+        {move || permissions.with(|permissions| match permissions {
+            Some(Ok(permissions)) if !permissions.is_empty() => view! {
+                {permissions.contains(&Permission::ImportUsers).then(|| view! { <ImportForm/> })}
+                {permissions.contains(&Permission::CreateInvites).then(|| view! { <Invites/> })}
+                {permissions.contains(&Permission::ManageGroups).then(|| view! { <Groups/> })}
+                {permissions.contains(&Permission::ImpersonateUsers).then(|| view! { <Impersonate/> })}
+                {permissions.contains(&Permission::ManageWebhooks).then(|| view! { <WebhookDeliveries/> })}
+            }.into_view(),
+            Some(Ok(_)) => error_view(AppError::Forbidden),
+            Some(Err(err)) => error_view(AppError::from(err)),
+            None => ().into_view(),
+        })}
+        </Suspense>
+        <A href="/"> Back to homepage </A>
+    }
+}
@@ -1,4 +1,9 @@
+use crate::account::Account;
+use crate::admin::Admin;
+use crate::auth_error::{self, AuthError};
 use crate::error_template::{AppError, ErrorTemplate};
+use crate::org_page::{OrgSwitcher, Organization};
+use crate::profile::Profile;
 use leptos::*;
 use leptos_meta::*;
 use leptos_router::*;
@@ -26,13 +31,20 @@ pub fn App() -> impl IntoView {
             .into_view()
         }>
             <main>
+                <ImpersonationBanner/>
                 <Routes>
                     // This mode makes it so suspenses with blocking resources are forced to render
                     // on the server
                     <Route ssr=SsrMode::PartiallyBlocked path="/" view=HomePage/>
                     <Route path="/login" view=LogIn/>
+                    <Route path="/login/verify" view=VerifyLogIn/>
+                    <Route path="/login/magic" view=ConfirmMagicLink/>
                     <Route path="/signup" view=SignUp/>
                     <Route ssr=SsrMode::PartiallyBlocked path="/secret" view=Secret/>
+                    <Route ssr=SsrMode::PartiallyBlocked path="/account" view=Account/>
+                    <Route ssr=SsrMode::PartiallyBlocked path="/account/profile" view=Profile/>
+                    <Route ssr=SsrMode::PartiallyBlocked path="/admin" view=Admin/>
+                    <Route ssr=SsrMode::PartiallyBlocked path="/org" view=Organization/>
                 </Routes>
             </main>
         </Router>
@@ -40,18 +52,108 @@ pub fn App() -> impl IntoView {
 }
 
 #[server]
-async fn get_username() -> Result<Option<String>, ServerFnError> {
+async fn get_username() -> Result<Option<String>, ServerFnError<AuthError>> {
     use crate::auth::AuthSession;
     Ok(expect_context::<AuthSession>().user.map(|u| u.username))
 }
 
+/// Who's looking at the site as who, for the banner
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ImpersonationInfo {
+    pub admin: String,
+    pub user: String,
+    pub minutes_left: i64,
+}
+
+/// `None` unless someone's being impersonated in this session
 #[server]
-async fn logout() -> Result<(), ServerFnError> {
+async fn impersonation_status() -> Result<Option<ImpersonationInfo>, ServerFnError<AuthError>> {
+    use crate::auth::AuthSession;
+    use crate::impersonate;
+    use crate::otp::now;
+    use axum_login::tower_sessions::Session;
+
+    let auth_session = expect_context::<AuthSession>();
+    let Some(impersonation) = impersonate::current(&expect_context::<Session>())
+        .await
+        .map_err(AuthError::from)?
+    else {
+        return Ok(None);
+    };
+    let (Some(user), Some(admin)) = (
+        auth_session.user,
+        auth_session
+            .backend
+            .store
+            .find_by_id(impersonation.admin_id)
+            .await
+            .map_err(AuthError::from)?,
+    ) else {
+        return Ok(None);
+    };
+
+    Ok(Some(ImpersonationInfo {
+        admin: admin.username,
+        user: user.username,
+        minutes_left: ((impersonation.expires_at - now()) + 59) / 60,
+    }))
+}
+
+#[server(StopImpersonating)]
+async fn stop_impersonating() -> Result<(), ServerFnError<AuthError>> {
+    use crate::auth::AuthSession;
+    use crate::impersonate;
+    use crate::state::AppState;
+    use axum_login::tower_sessions::Session;
+
+    let state = expect_context::<AppState>();
+    let mut auth_session = expect_context::<AuthSession>();
+    impersonate::stop(&state.pool, &mut auth_session, &expect_context::<Session>(), "returned")
+        .await
+        .map_err(AuthError::from)?;
+
+    leptos_axum::redirect("/admin");
+    Ok(())
+}
+
+/// Shown on every page while an admin's logged in as someone else, so they don't forget
+#[component]
+fn ImpersonationBanner() -> impl IntoView {
+    let stop_action = create_server_action::<StopImpersonating>();
+    let ret = stop_action.value();
+    // Checked again on every page, since it could have run out
+    let location = use_location();
+    let status = create_resource(
+        move || (location.pathname.get(), stop_action.version().get()),
+        |_| async { impersonation_status().await },
+    );
+
+    view! {
+        <Transition fallback=||()>
+        {move || status.get().and_then(Result::ok).flatten().map(|info| view! {
+            <div class="impersonation-banner">
+                "You're " {info.admin} ", logged in as " {info.user} " for another "
+                {info.minutes_left} " minutes. "
+                <ActionForm action=stop_action>
+                    <input type="submit" value="Return to my account"/>
+                </ActionForm>
+            </div>
+        })}
+        </Transition>
+        {move || match ret.get() {
+            Some(Err(err)) => view! { <p>{auth_error::message(&err)}</p> }.into_view(),
+            _ => ().into_view(),
+        }}
+    }
+}
+
+#[server]
+async fn logout() -> Result<(), ServerFnError<AuthError>> {
     use crate::auth::AuthSession;
 
     match expect_context::<AuthSession>().logout().await {
         Ok(_) => Ok(()),
-        Err(e) => Err(ServerFnError::from(e)),
+        Err(e) => Err(AuthError::from(e).into()),
     }
 }
 
@@ -81,15 +183,18 @@ fn HomePage() -> impl IntoView {
                     Some(Ok(Some(username))) => view! {
                         <p>
                             "Logged in as " {username}". "
+                            <A href="/account">"Account"</A> " "
+                            <A href="/org">"Organization"</A> " "
                             <button on:click=reload_or >Log out</button>
-                        </p> }.into_view(),
+                        </p>
+                        <OrgSwitcher/> }.into_view(),
                     Some(Ok(None)) => view! {
 
                             <A href="/login"> "Login" </A>
                                 <br />
                                 <A href="/signup"> "Sign Up" </A>
                         }.into_view(),
-                    Some(Err(err)) => view!{<p>{err.to_string()}</p>}.into_view(),
+                    Some(Err(err)) => view!{<p>{auth_error::message(err)}</p>}.into_view(),
                     None => ().into_view(),
                 }
             })
@@ -102,8 +207,12 @@ fn HomePage() -> impl IntoView {
 }
 
 #[server(LogInDetails)]
-async fn log_in(username: String, password: String) -> Result<(), ServerFnError> {
+async fn log_in(username: String, password: String) -> Result<(), ServerFnError<AuthError>> {
     use crate::auth::{AuthSession, Credentials};
+    use crate::metrics;
+    use crate::otp::begin_second_factor;
+    use crate::state::AppState;
+    use crate::webhook;
 
     // Don't sign up if we're already logged in
     let mut session: AuthSession = expect_context();
@@ -112,19 +221,99 @@ async fn log_in(username: String, password: String) -> Result<(), ServerFnError>
         return Ok(());
     }
 
+    if username.trim().is_empty() {
+        return Err(AuthError::validation("username", "What's your username?").into());
+    }
+    if password.is_empty() {
+        return Err(AuthError::validation("password", "What's your password?").into());
+    }
+
     let user = session
         .authenticate(Credentials { username, password })
-        .await?;
+        .await
+        .map_err(AuthError::from)?;
 
     if let Some(user) = user {
-        session.login(&user).await?;
+        // Not logged in until they've done their second factor too
+        let state = expect_context::<AppState>();
+        if begin_second_factor(state.otp.as_ref(), &expect_context(), &user)
+            .await
+            .map_err(AuthError::from)?
+        {
+            leptos_axum::redirect("/login/verify");
+            return Ok(());
+        }
+
+        session.login(&user).await.map_err(AuthError::from)?;
+        metrics::login(metrics::LOGIN_SUCCESS);
+        webhook::logged_in(&state, &user, "password").await;
         leptos_axum::redirect("/");
         Ok(())
     } else {
-        Err(ServerFnError::ServerError(
-            "Invalid login details".to_owned(),
-        ))
+        Err(AuthError::InvalidCredentials.into())
+    }
+}
+
+#[server(MagicLinkDetails)]
+async fn request_magic_link(email: String) -> Result<(), ServerFnError<AuthError>> {
+    use crate::client_info::ClientInfo;
+    use crate::state::AppState;
+
+    let state = expect_context::<AppState>();
+    let client = expect_context::<ClientInfo>();
+    let (Some(links), Some(mailer)) = (state.magic_links.clone(), state.mailer.clone()) else {
+        return Err(AuthError::rejected("Sign in links aren't set up here, sorry").into());
+    };
+    if email.trim().parse::<lettre::Address>().is_err() {
+        return Err(AuthError::validation("email", "That's not an email address").into());
     }
+
+    // Keeps anyone from filling someone's inbox, or ours
+    let email = email.trim().to_lowercase();
+    if let Err(wait) = links.allow_request(&state.live.get(), &email, client.ip) {
+        tracing::warn!(ip = %client.ip, "Too many sign in links asked for");
+        return Err(AuthError::TooManyRequests {
+            message: "Too many sign in links asked for, try again later".to_owned(),
+            retry_after: Some(wait),
+        }
+        .into());
+    }
+
+    // Done in the background so the response takes as long whether or not they have an account,
+    // otherwise this could be used to check who's signed up.
+    let tasks = state.tasks.clone();
+    let task = async move {
+        let user = match state.auth.store.find_by_email(&email).await {
+            Ok(Some(user)) if user.check_status().is_ok() => user,
+            Ok(_) => return,
+            Err(err) => {
+                tracing::error!(%err, "Couldn't look up who a sign in link is for");
+                return;
+            }
+        };
+
+        let token = match links.issue(user.id).await {
+            Ok(token) => token,
+            Err(err) => {
+                tracing::error!(%err, user.username, "Couldn't make a sign in link");
+                return;
+            }
+        };
+
+        let link = format!("{}/login/magic?token={token}", state.config.public_url());
+        let body = format!(
+            "Hi {},\n\nUse this link to sign in, it works once in the next {} minutes:\n\n{link}\n\n\
+             If you didn't ask for this you can ignore it.\n",
+            user.username, links.config.ttl_minutes
+        );
+        if let Err(err) = mailer.send(&email, "Your sign in link", body).await {
+            tracing::error!(%err, user.username, "Couldn't email a sign in link");
+        }
+    };
+    // Keeps the request id on anything it logs
+    tasks.spawn(tracing::Instrument::in_current_span(task));
+
+    Ok(())
 }
 
 #[component]
@@ -133,7 +322,21 @@ fn LogIn() -> impl IntoView {
     let pending = log_in_action.pending();
     let ret = log_in_action.value();
 
-    // TODO: Force https
+    let magic_link_action = create_server_action::<MagicLinkDetails>();
+    let magic_link_pending = magic_link_action.pending();
+    let magic_link_ret = magic_link_action.value();
+
+    // Set when a sign in link didn't work out
+    let query = use_query_map();
+    let bad_link = move || {
+        query.with(|q| match q.get("magic").map(String::as_str) {
+            None => None,
+            Some("disabled") => Some("Your account has been disabled."),
+            Some("locked") => Some("Your account is locked for now, try again later."),
+            Some("pending-verification") => Some("Your account hasn't been verified yet."),
+            Some(_) => Some("That sign in link has expired or already been used."),
+        })
+    };
 
     view! {
         <h1>"Log In"</h1>
@@ -142,33 +345,199 @@ fn LogIn() -> impl IntoView {
         <ActionForm class="credential-form" action=log_in_action>
                 <label for="username">Username </label>
                 <input type="text" name="username"/>
+                <FieldError ret field="username"/>
 
                 <label for="password">Password </label>
                 <input type="password" name="password"/>
+                <FieldError ret field="password"/>
 
             <input type="submit" value="Log In"/>
         </ActionForm>
 
 
         <p>{move || pending.get().then_some("Working... 🛌")}</p>
+        <FormError ret fields=&["username", "password"]/>
+
+        <h2>"Forgot your password?"</h2>
+        <p>{bad_link}</p>
+        <ActionForm class="credential-form" action=magic_link_action>
+                <label for="email">Email </label>
+                <input type="email" name="email"/>
+                <FieldError ret=magic_link_ret field="email"/>
+
+            <input type="submit" value="Email me a sign in link"/>
+        </ActionForm>
+
+        <p>{move || magic_link_pending.get().then_some("Working... 🛌")}</p>
         <p>
-            {move || {
-                if let Some(Err(v)) = ret.get() {
-                    view! { {v.to_string()} }.into_view()
-                } else {
-                    ().into_view()
-                }
+            {move || magic_link_ret.get().and_then(|ret| ret.ok()).map(|()| {
+                "If that address has an account, a link is on its way 📬"
+            })}
+        </p>
+        <FormError ret=magic_link_ret fields=&["email"]/>
+    }
+}
+
+/// Where sign in links point. It takes a click to use one, so mail scanners and link previews
+/// opening it first don't use it up.
+#[component]
+fn ConfirmMagicLink() -> impl IntoView {
+    let query = use_query_map();
+    let token = move || query.with(|q| q.get("token").cloned().unwrap_or_default());
+
+    view! {
+        <h1>"Log In"</h1>
+        <p>"Welcome back! One more click and you're in."</p>
+
+        <form class="credential-form" method="post" action="/login/magic">
+            <input type="hidden" name="token" value=token/>
+            <input type="submit" value="Log me in"/>
+        </form>
+    }
+}
+
+#[server(VerifyLogInDetails)]
+async fn verify_log_in(code: String) -> Result<(), ServerFnError<AuthError>> {
+    use crate::auth::AuthSession;
+    use crate::metrics;
+    use crate::otp::{finish_pending_login, pending_login, Purpose};
+    use crate::recovery;
+    use crate::state::AppState;
+    use crate::webhook;
+    use axum_login::tower_sessions::Session;
+
+    let state = expect_context::<AppState>();
+    let session: Session = expect_context();
+    let Some(user_id) = pending_login(&session).await.map_err(AuthError::from)? else {
+        return Err(AuthError::rejected("Your log in has timed out, please start again").into());
+    };
+
+    // Codes we send are all digits, recovery codes never are
+    let code = code.trim();
+    if code.chars().all(|c| c.is_ascii_digit()) {
+        let Some(otp) = &state.otp else {
+            return Err(AuthError::validation(
+                "code",
+                "Codes can't be checked right now, use a recovery code",
+            )
+            .into());
+        };
+        if let Err(err) = otp.verify(user_id, Purpose::Login, code).await {
+            metrics::login(metrics::LOGIN_WRONG_CODE);
+            return Err(AuthError::from(err).into());
+        }
+    } else {
+        // Recovery codes still work with two factor turned off in the config
+        let lockout = state.otp.as_ref().map(|otp| otp.config().lockout()).unwrap_or_default();
+        if let Err(err) = recovery::redeem(&state.pool, &lockout, user_id, code).await {
+            metrics::login(metrics::LOGIN_WRONG_CODE);
+            return Err(AuthError::from(err).into());
+        }
+    }
+
+    // Could have been disabled or locked while they were looking for the code
+    let Some(user) = state.auth.store.find_by_id(user_id).await.map_err(AuthError::from)? else {
+        return Err(AuthError::rejected("Your account doesn't exist anymore").into());
+    };
+    user.check_status().map_err(AuthError::from)?;
+
+    finish_pending_login(&session).await.map_err(AuthError::from)?;
+    expect_context::<AuthSession>()
+        .login(&user)
+        .await
+        .map_err(AuthError::from)?;
+    metrics::login(metrics::LOGIN_SUCCESS);
+    webhook::logged_in(&state, &user, "second-factor").await;
+    leptos_axum::redirect("/");
+    Ok(())
+}
+
+#[server(ResendLogInCode)]
+async fn resend_log_in_code() -> Result<(), ServerFnError<AuthError>> {
+    use crate::otp::{pending_login, Purpose};
+    use crate::state::AppState;
+    use axum_login::tower_sessions::Session;
+
+    let state = expect_context::<AppState>();
+    let session = expect_context::<Session>();
+    let Some(user_id) = pending_login(&session).await.map_err(AuthError::from)? else {
+        return Err(AuthError::rejected("Your log in has timed out, please start again").into());
+    };
+    let Some(otp) = &state.otp else {
+        return Err(AuthError::rejected("Codes can't be sent right now, use a recovery code").into());
+    };
+
+    otp.resend(user_id, Purpose::Login).await.map_err(AuthError::from)?;
+    Ok(())
+}
+
+/// The second half of logging in, for people with a second factor
+#[component]
+fn VerifyLogIn() -> impl IntoView {
+    let verify_action = create_server_action::<VerifyLogInDetails>();
+    let pending = verify_action.pending();
+    let ret = verify_action.value();
+
+    let resend_action = create_server_action::<ResendLogInCode>();
+    let resend_ret = resend_action.value();
+
+    view! {
+        <h1>"One more thing"</h1>
+        <p>"We've sent you a code. Pop it in below, or use one of your recovery codes."</p>
+
+        <ActionForm class="credential-form" action=verify_action>
+                <label for="code">Code </label>
+                <input type="text" name="code" autocomplete="one-time-code"/>
+                <FieldError ret field="code"/>
+
+            <input type="submit" value="Log In"/>
+        </ActionForm>
+
+        <ActionForm class="credential-form" action=resend_action>
+            <input type="submit" value="Send me another code"/>
+        </ActionForm>
+
+        <p>{move || pending.get().then_some("Working... 🛌")}</p>
+        <p>
+            {move || match (ret.get(), resend_ret.get()) {
+                (Some(Err(v)), _) => auth_error::form_message(&v, &["code"]).into_view(),
+                (_, Some(Err(v))) => auth_error::message(&v).into_view(),
+                (_, Some(Ok(()))) => view! { "Sent!" }.into_view(),
+                _ => ().into_view(),
             }}
         </p>
-        // <p>{move || ret.get().and_then(|res| res.is_err().then_some(res.err().unwrap().to_string()))}</p>
     }
 }
 
+/// "open", "invite-only" or "closed"
+#[server]
+async fn registration_mode() -> Result<String, ServerFnError<AuthError>> {
+    use crate::state::{AppState, Registration};
+
+    let state = expect_context::<AppState>();
+    Ok(match state.live.get().auth.registration {
+        Registration::Open => "open",
+        Registration::InviteOnly => "invite-only",
+        Registration::Closed => "closed",
+    }
+    .to_owned())
+}
+
 #[server(SignUpDetails)]
-async fn sign_up(username: String, password: String) -> Result<(), ServerFnError> {
+async fn sign_up(
+    username: String,
+    password: String,
+    email: String,
+    invite: String,
+) -> Result<(), ServerFnError<AuthError>> {
+    use crate::audit;
     use crate::auth;
-    use crate::auth::{AuthSession, Credentials};
-    use crate::state::AppState;
+    use crate::auth::{AuthSession, Credentials, Role};
+    use crate::invite;
+    use crate::metrics::{self, METRICS};
+    use crate::org;
+    use crate::state::{AppState, BackendKind, Registration};
+    use crate::webhook;
     use bcrypt::hash;
 
     // Don't sign up if we're already logged in
@@ -184,45 +553,145 @@ async fn sign_up(username: String, password: String) -> Result<(), ServerFnError
     ))
     .await; */
 
-    // TODO: Change this to expect context.
-    let Some(state) = use_context::<AppState>() else {
-        return Err(ServerFnError::ServerError(
-            "Couldn't receive state from context".to_owned(),
-        ));
-    };
+    let state = expect_context::<AppState>();
+
+    // Accounts from elsewhere get made on their first log in
+    if !state.config.auth.backends.contains(&BackendKind::Local) {
+        return Err(AuthError::rejected(
+            "Sign up is disabled, log in with your directory account instead",
+        )
+        .into());
+    }
 
-    // TODO: Validate password and username.
+    // TODO: Validate password and username properly.
 
     let username = username.trim().to_lowercase().to_string();
+    if username.is_empty() {
+        return Err(AuthError::validation("username", "Pick a username").into());
+    }
+    if password.is_empty() {
+        return Err(AuthError::validation("password", "Pick a password").into());
+    }
 
-    let user_exists = sqlx::query("SELECT id FROM user WHERE username = ?")
-        .bind(&username)
-        .fetch_optional(&state.pool)
-        .await?
-        .is_some();
+    // Optional, but it's the only way to get a sign in link
+    let email = email.trim().to_lowercase();
+    let email = if email.is_empty() {
+        None
+    } else if email.parse::<lettre::Address>().is_err() {
+        return Err(AuthError::validation("email", "That's not an email address").into());
+    } else {
+        Some(email)
+    };
 
-    if user_exists {
-        return Err(ServerFnError::ServerError("User already exists".to_owned()));
-    }
+    // Invites still count when anyone can sign up, for the role that comes with them
+    let invite = invite.trim();
+    let invite = match (state.live.get().auth.registration, invite.is_empty()) {
+        (Registration::Closed, _) => {
+            return Err(AuthError::rejected("Sign ups are closed").into());
+        }
+        (Registration::InviteOnly, true) => {
+            return Err(AuthError::validation("invite", "You need an invite to sign up").into());
+        }
+        (_, true) => None,
+        (_, false) => match invite::redeem(&state.pool, invite)
+            .await
+            .map_err(AuthError::from)?
+        {
+            Some(invite) => Some(invite),
+            None => {
+                return Err(AuthError::validation(
+                    "invite",
+                    "That invite doesn't exist, has expired or has been used up",
+                )
+                .into());
+            }
+        },
+    };
 
-    let pw_hash = hash(&password, auth::BCRYPT_COST).expect("password should hash correctly.");
+    let pw_hash = match metrics::time_hash("hash", || hash(&password, auth::BCRYPT_COST)) {
+        Ok(pw_hash) => pw_hash,
+        Err(err) => {
+            if let Some(invite) = &invite {
+                invite::give_back(&state.pool, invite.id)
+                    .await
+                    .map_err(AuthError::from)?;
+            }
+            return Err(AuthError::from(err).into());
+        }
+    };
 
-    println!("Registering {username:?}");
+    tracing::info!(username, "Registering");
 
     // NOTE: We don't need to store the hash.. It's in the bcrypt hash.. Should be parsing the
     // bcrypt hash to generate it from login ig.
-    sqlx::query("INSERT INTO user (username, password_hash) VALUES (?, ?)")
-        .bind(&username)
-        .bind(pw_hash.to_string())
-        .execute(&state.pool)
-        .await?;
+    let user = match state
+        .auth
+        .store
+        .create_with_email(&username, &pw_hash, email.as_deref())
+        .await
+    {
+        Ok(user) => user,
+        Err(err) => {
+            // Didn't get an account out of it, so it shouldn't use up the invite
+            if let Some(invite) = &invite {
+                invite::give_back(&state.pool, invite.id)
+                    .await
+                    .map_err(AuthError::from)?;
+            }
+            return Err(AuthError::from(err).into());
+        }
+    };
+    METRICS.sign_ups.inc();
+
+    if let Some(invite) = invite {
+        let applied = async {
+            if invite.role != Role::User {
+                state
+                    .auth
+                    .store
+                    .set_role(user.id, invite.role)
+                    .await
+                    .map_err(AuthError::from)?;
+            }
+            if let (Some(org_id), Some(org_role)) = (invite.org_id, invite.org_role) {
+                let org = org::find_by_id(&state.pool, org_id)
+                    .await
+                    .map_err(AuthError::from)?;
+                org::set_member(&state.pool, &org, user.id, org_role, None)
+                    .await
+                    .map_err(AuthError::from)?;
+            }
+            Ok::<_, AuthError>(())
+        }
+        .await;
+        // They keep the account, but the invite didn't do what it's for so it shouldn't count
+        if let Err(err) = applied {
+            invite::give_back(&state.pool, invite.id)
+                .await
+                .map_err(AuthError::from)?;
+            return Err(err.into());
+        }
+        audit::record(
+            &state.pool,
+            Some(user.id),
+            audit::INVITE_USED,
+            &format!("invite {}", invite.id),
+        )
+        .await;
+    }
 
-    let res = session
+    // They were only just added, so this'd only fail if something's very wrong
+    let Some(res) = session
         .authenticate(Credentials { username, password })
-        .await?
-        .expect("user should authenticate correctly because they were just added to the database");
+        .await
+        .map_err(AuthError::from)?
+    else {
+        tracing::error!(user.username, "Couldn't log in a user we just signed up");
+        return Err(AuthError::Internal.into());
+    };
 
-    session.login(&res).await?;
+    session.login(&res).await.map_err(AuthError::from)?;
+    webhook::signed_up(&state, &res).await;
 
     leptos_axum::redirect("/");
     Ok(())
@@ -234,7 +703,11 @@ fn SignUp() -> impl IntoView {
     let pending = sign_up_action.pending();
     let ret = sign_up_action.value();
 
-    // TODO: Force https
+    let mode = create_resource(|| (), |_| async { registration_mode().await });
+    // Invite links come with the code already in them
+    let query = use_query_map();
+    let invite_code = move || query.with(|q| q.get("invite").cloned().unwrap_or_default());
+
     // TODO: Inform the user that passwords are truncated at 72 chars.
     view! {
         <h1>"Sign Up"</h1>
@@ -243,25 +716,66 @@ fn SignUp() -> impl IntoView {
         <ActionForm class="credential-form" action=sign_up_action>
                 <label for="username">Username </label>
                 <input type="text" name="username"/>
+                <FieldError ret field="username"/>
 
                 <label for="password">Password </label>
                 <input type="password" name="password"/>
+                <FieldError ret field="password"/>
+
+                <label for="email">Email (optional) </label>
+                <input type="email" name="email"/>
+                <FieldError ret field="email"/>
+
+                <Suspense fallback=||()>
+                {move || match mode.get() {
+                    Some(Ok(mode)) if mode == "invite-only" => view! {
+                        <label for="invite">Invite code </label>
+                        <input type="text" name="invite" prop:value=invite_code/>
+                    }.into_view(),
+                    Some(Ok(mode)) if mode == "closed" => view! {
+                        <p>"Sorry, sign ups are closed."</p>
+                        <input type="hidden" name="invite" value=""/>
+                    }.into_view(),
+                    _ => view! { <input type="hidden" name="invite" prop:value=invite_code/> }.into_view(),
+                }}
+                </Suspense>
 
             <input type="submit" value="Sign Up"/>
         </ActionForm>
 
 
         <p>{move || pending.get().then_some("Working... 🛌")}</p>
-        <p>
-            {move || {
-                if let Some(Err(v)) = ret.get() {
-                    view! { {v.to_string()} }.into_view()
-                } else {
-                    ().into_view()
-                }
-            }}
-        </p>
-        // <p>{move || ret.get().and_then(|res| res.is_err().then_some(res.err().unwrap().to_string()))}</p>
+        // Invite codes usually come in the link rather than the box, so anything wrong with one
+        // goes down here
+        <FormError ret fields=&["username", "password", "email"]/>
+    }
+}
+
+/// What went wrong with `field`, shown right under it.
+#[component]
+pub fn FieldError(
+    ret: RwSignal<Option<Result<(), ServerFnError<AuthError>>>>,
+    field: &'static str,
+) -> impl IntoView {
+    move || {
+        ret.get()
+            .and_then(|ret| ret.err())
+            .and_then(|err| auth_error::field_message(&err, field))
+            .map(|message| view! { <span class="field-error">{message}</span> })
+    }
+}
+
+/// Whatever went wrong that isn't about one of `fields`.
+#[component]
+pub fn FormError(
+    ret: RwSignal<Option<Result<(), ServerFnError<AuthError>>>>,
+    fields: &'static [&'static str],
+) -> impl IntoView {
+    move || {
+        ret.get()
+            .and_then(|ret| ret.err())
+            .and_then(|err| auth_error::form_message(&err, fields))
+            .map(|message| view! { <p class="form-error">{message}</p> })
     }
 }
 
@@ -0,0 +1,68 @@
+use axum_login::tower_sessions::Session;
+use leptos::{use_context, Owner};
+use sqlx::SqlitePool;
+
+use crate::impersonate;
+
+// Everything that ends up in the `event` column
+pub const RECOVERY_CODES_GENERATED: &str = "recovery_codes_generated";
+pub const RECOVERY_CODE_USED: &str = "recovery_code_used";
+pub const SECOND_FACTOR_ENABLED: &str = "second_factor_enabled";
+pub const SECOND_FACTOR_DISABLED: &str = "second_factor_disabled";
+pub const SECOND_FACTOR_FAILED: &str = "second_factor_failed";
+pub const PASSWORD_CHANGED: &str = "password_changed";
+pub const INVITE_CREATED: &str = "invite_created";
+pub const INVITE_USED: &str = "invite_used";
+pub const INVITE_REVOKED: &str = "invite_revoked";
+pub const PERMISSION_GRANTED: &str = "permission_granted";
+pub const PERMISSION_REVOKED: &str = "permission_revoked";
+pub const STATUS_CHANGED: &str = "status_changed";
+pub const GROUP_CREATED: &str = "group_created";
+pub const GROUP_DELETED: &str = "group_deleted";
+pub const GROUP_MEMBER_ADDED: &str = "group_member_added";
+pub const GROUP_MEMBER_REMOVED: &str = "group_member_removed";
+pub const GROUP_PERMISSION_GRANTED: &str = "group_permission_granted";
+pub const GROUP_PERMISSION_REVOKED: &str = "group_permission_revoked";
+pub const ORG_CREATED: &str = "org_created";
+pub const ORG_MEMBER_SET: &str = "org_member_set";
+pub const ORG_MEMBER_REMOVED: &str = "org_member_removed";
+pub const IMPERSONATION_STARTED: &str = "impersonation_started";
+pub const IMPERSONATION_ENDED: &str = "impersonation_ended";
+
+/// Writes down something security relevant that happened to (or was done by) a user.
+///
+/// If it's a server function doing it while an admin's impersonating someone, the admin's
+/// recorded too, so it's clear who really did it.
+///
+/// Failing to write to the audit log shouldn't stop whatever it's recording, so errors are only
+/// printed.
+pub async fn record(pool: &SqlitePool, user_id: Option<i64>, event: &str, detail: &str) {
+    let impersonator_id = impersonator().await;
+    let res = sqlx::query(
+        "INSERT INTO audit_log (user_id, impersonator_id, event, detail) VALUES (?, ?, ?, ?)",
+    )
+    .bind(user_id)
+    .bind(impersonator_id)
+    .bind(event)
+    .bind(detail)
+    .execute(pool)
+    .await;
+
+    if let Err(err) = res {
+        tracing::error!(%err, event, user_id, "Couldn't write to the audit log");
+    }
+}
+
+/// Who's impersonating someone in the session of the server function we're in, if anyone.
+async fn impersonator() -> Option<i64> {
+    // The command line and background tasks don't have a session to look in
+    Owner::current()?;
+    let session = use_context::<Session>()?;
+    match impersonate::current(&session).await {
+        Ok(impersonation) => impersonation.map(|impersonation| impersonation.admin_id),
+        Err(err) => {
+            tracing::error!(%err, "Couldn't check for impersonation");
+            None
+        }
+    }
+}
@@ -1,12 +1,108 @@
 use axum::async_trait;
 use axum_login::UserId;
-use axum_login::{AuthUser, AuthnBackend};
+use axum_login::{AuthUser, AuthnBackend, AuthzBackend};
+use serde::Deserialize;
 use sqlx::prelude::FromRow;
-use sqlx::SqlitePool;
+use std::collections::HashSet;
 use std::fmt::Debug;
+use std::str::FromStr;
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::htpasswd::{self, HashKind};
+use crate::metrics;
+use crate::otp::{now, OtpChannel};
+use crate::permission::Permission;
+use crate::store::{StoreError, UserStore};
 
 pub const BCRYPT_COST: u32 = 12;
 
+/// Stored in place of a hash for users who can't log in with a local password (e.g. they come
+/// from LDAP). Like `!` in /etc/shadow, nothing hashes to it.
+pub const NO_PASSWORD: &str = "!";
+
+/// What someone is allowed to do. Later variants trump earlier ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    User,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Admin => "admin",
+        }
+    }
+
+    /// What everyone with this role can do, without being granted anything
+    pub fn permissions(self) -> &'static [Permission] {
+        match self {
+            Role::User => &[],
+            Role::Admin => Permission::ALL,
+        }
+    }
+}
+
+/// Whether someone can use their account. Anything but `Active` keeps them from logging in and
+/// drops any sessions they've already got.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, sqlx::Type)]
+#[sqlx(rename_all = "kebab-case")]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    /// Until someone turns them back on
+    Disabled,
+    /// Until `locked_until`, then they're active again without anyone doing anything
+    Locked,
+    /// Signed up, but we're waiting on something before they can use it
+    PendingVerification,
+}
+
+impl AccountStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AccountStatus::Active => "active",
+            AccountStatus::Disabled => "disabled",
+            AccountStatus::Locked => "locked",
+            AccountStatus::PendingVerification => "pending-verification",
+        }
+    }
+}
+
+impl FromStr for AccountStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "active" => Ok(AccountStatus::Active),
+            "disabled" => Ok(AccountStatus::Disabled),
+            "locked" => Ok(AccountStatus::Locked),
+            "pending-verification" => Ok(AccountStatus::PendingVerification),
+            _ => Err(format!(
+                "Unknown status {s:?}, expected active, disabled, locked or pending-verification"
+            )),
+        }
+    }
+}
+
+/// Why someone who's got their password right still can't come in.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Blocked {
+    #[error("Account is disabled")]
+    Disabled { reason: Option<String> },
+
+    #[error("Account is locked until {until}")]
+    Locked { until: i64, reason: Option<String> },
+
+    #[error("Account is waiting to be verified")]
+    PendingVerification,
+}
+
 // Could have more fields, and be able to be constructed From an sqlx row.
 // Actually is it ok to clone if it has that many fields? Might want to keep a smaller substruct
 // for this if that's a concern.
@@ -17,6 +113,48 @@ pub struct User {
 
     #[sqlx(rename = "password_hash")]
     pub pw_hash: String,
+
+    pub status: AccountStatus,
+    pub locked_until: Option<i64>,
+    /// Why they're not active, for telling them
+    pub status_reason: Option<String>,
+
+    pub role: Role,
+
+    /// Which [`Authenticator`] let them in last
+    pub auth_source: Option<String>,
+
+    pub email: Option<String>,
+
+    /// Their profile, all of which they can leave empty
+    pub display_name: Option<String>,
+    /// File name under `avatars.dir`, see [`crate::avatar`]
+    pub avatar: Option<String>,
+    pub timezone: Option<String>,
+    pub locale: Option<String>,
+
+    /// Where second factor codes go. They don't have one if this is `None`.
+    pub otp_channel: Option<OtpChannel>,
+    pub otp_destination: Option<String>,
+}
+
+impl User {
+    /// Whether they can use their account right now. Everywhere someone gets in, or stays in,
+    /// goes through this.
+    pub fn check_status(&self) -> Result<(), Blocked> {
+        let reason = self.status_reason.clone();
+        match self.status {
+            AccountStatus::Active => Ok(()),
+            AccountStatus::Disabled => Err(Blocked::Disabled { reason }),
+            // A lock without an end would be a disable, so treat it like one
+            AccountStatus::Locked => match self.locked_until {
+                Some(until) if until <= now() => Ok(()),
+                Some(until) => Err(Blocked::Locked { until, reason }),
+                None => Err(Blocked::Disabled { reason }),
+            },
+            AccountStatus::PendingVerification => Err(Blocked::PendingVerification),
+        }
+    }
 }
 
 impl AuthUser for User {
@@ -40,6 +178,18 @@ impl Debug for User {
             .field("id", &self.id)
             .field("name", &self.username)
             .field("pw_hash", &"Wouldn't you like to know")
+            .field("status", &self.status)
+            .field("locked_until", &self.locked_until)
+            .field("status_reason", &self.status_reason)
+            .field("role", &self.role)
+            .field("auth_source", &self.auth_source)
+            .field("email", &self.email)
+            .field("display_name", &self.display_name)
+            .field("avatar", &self.avatar)
+            .field("timezone", &self.timezone)
+            .field("locale", &self.locale)
+            .field("otp_channel", &self.otp_channel)
+            .field("otp_destination", &self.otp_destination)
             .finish()
     }
 }
@@ -62,63 +212,436 @@ impl Debug for Credentials {
     }
 }
 
+#[derive(Error, Debug)]
+pub enum BackendError {
+    #[error(transparent)]
+    Store(#[from] StoreError),
+
+    #[error("LDAP error: {0}")]
+    Ldap(#[from] ldap3::LdapError),
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Hashing error: {0}")]
+    Hash(#[from] bcrypt::BcryptError),
+
+    /// Config that slipped past validation
+    #[error("Misconfigured: {0}")]
+    Config(&'static str),
+
+    /// Right password, but they can't come in
+    #[error(transparent)]
+    Blocked(#[from] Blocked),
+}
+
+/// One way of checking a password. [`AuthBackend`] tries a list of these in order.
+#[async_trait]
+pub trait Authenticator: Debug + Send + Sync {
+    /// What gets recorded as the user's `auth_source`.
+    fn name(&self) -> &'static str;
+
+    /// `Ok(None)` means "not me", so the next one gets a go.
+    async fn authenticate(&self, creds: &Credentials) -> Result<Option<User>, BackendError>;
+
+    /// Checks the password of someone who's already logged in, e.g. before they change how they
+    /// log in. Unlike [`Authenticator::authenticate`] this never changes anything: no new users,
+    /// no hash upgrades, and it doesn't count as a log in.
+    async fn verify(&self, user: &User, password: &str) -> Result<bool, BackendError>;
+}
+
+/// Checks against the hash in the user store.
+#[derive(Debug, Clone)]
+pub struct LocalAuthenticator {
+    pub store: Arc<dyn UserStore>,
+}
+
+#[async_trait]
+impl Authenticator for LocalAuthenticator {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    async fn authenticate(&self, creds: &Credentials) -> Result<Option<User>, BackendError> {
+        let Some(user) = self.store.find_by_username(&creds.username).await? else {
+            return Ok(None);
+        };
+
+        if user.pw_hash == NO_PASSWORD {
+            return Ok(None);
+        }
+
+        // Imported users might still have whatever hash they came with
+        if HashKind::of(&user.pw_hash) != Some(HashKind::Bcrypt) {
+            if !metrics::time_hash("verify", || htpasswd::verify(&creds.password, &user.pw_hash)) {
+                return Ok(None);
+            }
+
+            // Now that we know their password we can swap it for a proper hash
+            tracing::info!(user.username, "Upgrading password hash");
+            let pw_hash =
+                metrics::time_hash("hash", || bcrypt::hash(&creds.password, BCRYPT_COST))?;
+            self.store.update_password(user.id, &pw_hash).await?;
+            return Ok(Some(User { pw_hash, ..user }));
+        }
+
+        if metrics::time_hash("verify", || bcrypt::verify(&creds.password, &user.pw_hash))? {
+            Ok(Some(user))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn verify(&self, user: &User, password: &str) -> Result<bool, BackendError> {
+        if user.pw_hash == NO_PASSWORD {
+            return Ok(false);
+        }
+
+        match HashKind::of(&user.pw_hash) {
+            Some(HashKind::Bcrypt) => Ok(bcrypt::verify(password, &user.pw_hash)?),
+            _ => Ok(htpasswd::verify(password, &user.pw_hash)),
+        }
+    }
+}
+
+/// For authenticators whose users live somewhere else. Gets the matching local user, making one
+/// without a password if this is their first time here. Only users that came from `source`, or
+/// that never had a local password, get linked up: anyone else with the same username would be
+/// walking into their account. Those come back as `None`.
+pub async fn find_or_provision(
+    store: &dyn UserStore,
+    username: &str,
+    source: &str,
+) -> Result<Option<User>, StoreError> {
+    let linkable = |user: User| {
+        if user.auth_source.as_deref() == Some(source) || user.pw_hash == NO_PASSWORD {
+            return Some(user);
+        }
+        tracing::warn!(
+            username,
+            source,
+            auth_source = user.auth_source,
+            "Username belongs to another account, not linking"
+        );
+        None
+    };
+
+    if let Some(user) = store.find_by_username(username).await? {
+        return Ok(linkable(user));
+    }
+
+    tracing::info!(username, "Provisioning user");
+    match store.create(username, NO_PASSWORD).await {
+        // Someone beat us to it
+        Err(StoreError::UsernameTaken) => Ok(store.find_by_username(username).await?.and_then(linkable)),
+        res => res.map(Some),
+    }
+}
+
+/// Tries each [`Authenticator`] in turn until one of them knows the user. Whoever lets them in
+/// gets recorded, which makes it easy to see who's left to migrate off an old one. Users are
+/// always looked up in the store.
 #[derive(Debug, Clone)]
 pub struct AuthBackend {
-    pub pool: SqlitePool,
+    pub store: Arc<dyn UserStore>,
+    pub chain: Vec<Arc<dyn Authenticator>>,
 }
 
-/* #[derive(Error, Debug)]
-struct AuthError(String);
-impl std::fmt::Display for AuthError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        std::fmt::Display::fmt(&self.0, f)
+impl AuthBackend {
+    /// Starts off only checking local passwords.
+    pub fn new(store: impl UserStore + 'static) -> Self {
+        let store: Arc<dyn UserStore> = Arc::new(store);
+        Self {
+            chain: vec![Arc::new(LocalAuthenticator {
+                store: store.clone(),
+            })],
+            store,
+        }
+    }
+
+    pub fn with_chain(mut self, chain: Vec<Arc<dyn Authenticator>>) -> Self {
+        self.chain = chain;
+        self
     }
-} */
+
+    /// Whether `password` is `user`'s, going by whichever authenticator let them in last. The
+    /// rest aren't asked, since the same username somewhere else could be somebody else.
+    pub async fn verify_password(&self, user: &User, password: &str) -> Result<bool, BackendError> {
+        let source = user.auth_source.as_deref().unwrap_or("local");
+        match self.chain.iter().find(|authenticator| authenticator.name() == source) {
+            Some(authenticator) => authenticator.verify(user, password).await,
+            None => Ok(false),
+        }
+    }
+}
 
 #[async_trait]
 impl AuthnBackend for AuthBackend {
     type User = User;
     type Credentials = Credentials;
-    type Error = sqlx::Error;
+    type Error = BackendError;
 
     async fn authenticate(
         &self,
         creds: Self::Credentials,
     ) -> Result<Option<Self::User>, Self::Error> {
-        let Some(user): Option<User> = sqlx::query_as("SELECT * FROM user WHERE username = ?")
-            .bind(creds.username)
-            .fetch_optional(&self.pool)
+        // If something's down we still want to give the rest a chance, so only complain if no
+        // one could let them in.
+        let mut last_err = None;
+
+        for authenticator in &self.chain {
+            let user = match authenticator.authenticate(&creds).await {
+                Ok(Some(user)) => user,
+                Ok(None) => continue,
+                Err(err) => {
+                    tracing::warn!(%err, authenticator = authenticator.name(), "Authenticator failed");
+                    last_err = Some(err);
+                    continue;
+                }
+            };
+
+            // Other sources don't know we've disabled them. Only said once they've got the
+            // password right, so it doesn't give away who has an account.
+            if let Err(blocked) = user.check_status() {
+                metrics::login(match blocked {
+                    Blocked::Disabled { .. } => metrics::LOGIN_DISABLED,
+                    Blocked::Locked { .. } => metrics::LOGIN_LOCKED,
+                    Blocked::PendingVerification => metrics::LOGIN_PENDING_VERIFICATION,
+                });
+                return Err(blocked.into());
+            }
+
+            let source = authenticator.name();
+            if user.auth_source.as_deref() != Some(source) {
+                tracing::info!(user.username, source, "Authenticated by a different backend");
+                self.store.set_auth_source(user.id, source).await?;
+            }
+
+            return Ok(Some(User {
+                auth_source: Some(source.to_owned()),
+                ..user
+            }));
+        }
+
+        match last_err {
+            Some(err) => {
+                metrics::login(metrics::LOGIN_BACKEND_ERROR);
+                Err(err)
+            }
+            None => {
+                metrics::login(metrics::LOGIN_INVALID_CREDENTIALS);
+                Ok(None)
+            }
+        }
+    }
+
+    async fn get_user(&self, user_id: &UserId<Self>) -> Result<Option<Self::User>, Self::Error> {
+        tracing::trace!(user_id, "get_user");
+
+        // Returning None for anyone who isn't active logs them out of any sessions they still have
+        Ok(self
+            .store
+            .find_by_id(*user_id)
             .await?
-        else {
-            return Ok(None);
-        };
+            .filter(|user| user.check_status().is_ok()))
+    }
+}
+#[async_trait]
+impl AuthzBackend for AuthBackend {
+    type Permission = Permission;
 
-        // Idfk how to do error types ok
-        if bcrypt::verify(creds.password, &user.pw_hash)
-            .expect("database's hash should be formatted ok")
-        {
-            Ok(Some(user))
-        } else {
-            Ok(None)
+    async fn get_user_permissions(
+        &self,
+        user: &Self::User,
+    ) -> Result<HashSet<Self::Permission>, Self::Error> {
+        let mut permissions: HashSet<_> = user.role.permissions().iter().copied().collect();
+        permissions.extend(self.store.permissions(user.id).await?);
+        Ok(permissions)
+    }
+
+    async fn get_group_permissions(
+        &self,
+        user: &Self::User,
+    ) -> Result<HashSet<Self::Permission>, Self::Error> {
+        Ok(self.store.group_permissions(user.id).await?.into_iter().collect())
+    }
+}
+
+pub type AuthSession = axum_login::AuthSession<AuthBackend>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryUserStore;
+
+    /// Lets anyone in whose password is "remote", like a directory would.
+    #[derive(Debug)]
+    struct Remote {
+        store: Arc<dyn UserStore>,
+    }
+
+    #[async_trait]
+    impl Authenticator for Remote {
+        fn name(&self) -> &'static str {
+            "remote"
         }
 
+        async fn authenticate(&self, creds: &Credentials) -> Result<Option<User>, BackendError> {
+            if creds.password != "remote" {
+                return Ok(None);
+            }
+            Ok(find_or_provision(&*self.store, &creds.username, self.name()).await?)
+        }
 
-        // dbg!(
-        //     sqlx::query_as("SELECT * FROM user WHERE username = ? AND password_hash = ?")
-        //         .bind(creds.username)
-        //         .bind(creds.password)
-        //         .fetch_optional(&self.pool)
-        //         .await
-        // )
+        async fn verify(&self, _: &User, password: &str) -> Result<bool, BackendError> {
+            Ok(password == "remote")
+        }
     }
 
-    async fn get_user(&self, user_id: &UserId<Self>) -> Result<Option<Self::User>, Self::Error> {
-        println!("[get_user] Received id: {:?}", user_id);
+    fn creds(username: &str, password: &str) -> Credentials {
+        Credentials {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        }
+    }
 
-        sqlx::query_as("SELECT * FROM user WHERE id = ?")
-            .bind(user_id)
-            .fetch_optional(&self.pool)
+    /// A backend with a local `alice` (password "hunter2") in it.
+    async fn backend() -> (AuthBackend, User) {
+        let backend = AuthBackend::new(MemoryUserStore::new());
+        // Cheap hash, these aren't testing bcrypt
+        let alice = backend
+            .store
+            .create("alice", &bcrypt::hash("hunter2", 4).unwrap())
             .await
+            .unwrap();
+        (backend, alice)
+    }
+
+    fn with_remote(backend: AuthBackend) -> AuthBackend {
+        let chain: Vec<Arc<dyn Authenticator>> = vec![
+            Arc::new(LocalAuthenticator { store: backend.store.clone() }),
+            Arc::new(Remote { store: backend.store.clone() }),
+        ];
+        backend.with_chain(chain)
+    }
+
+    #[tokio::test]
+    async fn logs_in_with_the_right_password() {
+        let (backend, alice) = backend().await;
+
+        let user = backend.authenticate(creds("alice", "hunter2")).await.unwrap().unwrap();
+        assert_eq!(user.id, alice.id);
+        assert_eq!(user.auth_source.as_deref(), Some("local"));
+        assert_eq!(backend.get_user(&alice.id).await.unwrap().unwrap().id, alice.id);
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_passwords_and_strangers() {
+        let (backend, _) = backend().await;
+
+        assert!(backend.authenticate(creds("alice", "hunter3")).await.unwrap().is_none());
+        assert!(backend.authenticate(creds("alice", "")).await.unwrap().is_none());
+        assert!(backend.authenticate(creds("bob", "hunter2")).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn no_password_means_no_local_login() {
+        let (backend, _) = backend().await;
+        backend.store.create("carol", NO_PASSWORD).await.unwrap();
+
+        assert!(backend.authenticate(creds("carol", NO_PASSWORD)).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn upgrades_imported_hashes() {
+        let (backend, _) = backend().await;
+        // {SHA} of "secret"
+        let dave = backend.store.create("dave", "{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=").await.unwrap();
+
+        assert!(backend.authenticate(creds("dave", "public")).await.unwrap().is_none());
+        backend.authenticate(creds("dave", "secret")).await.unwrap().unwrap();
+
+        let dave = backend.store.find_by_id(dave.id).await.unwrap().unwrap();
+        assert_eq!(HashKind::of(&dave.pw_hash), Some(HashKind::Bcrypt));
+        assert!(backend.authenticate(creds("dave", "secret")).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn blocked_users_stay_out() {
+        let (backend, alice) = backend().await;
+        let store = &backend.store;
+
+        store.set_status(alice.id, AccountStatus::Disabled, None, Some("Spam")).await.unwrap();
+        let err = backend.authenticate(creds("alice", "hunter2")).await.unwrap_err();
+        assert!(matches!(
+            err,
+            BackendError::Blocked(Blocked::Disabled { reason: Some(ref r) }) if r == "Spam"
+        ));
+        // Which also ends any sessions they had
+        assert!(backend.get_user(&alice.id).await.unwrap().is_none());
+
+        // Wrong passwords don't find out they're blocked
+        assert!(backend.authenticate(creds("alice", "hunter3")).await.unwrap().is_none());
+
+        // Locks run out
+        store.set_status(alice.id, AccountStatus::Locked, Some(now() + 60), None).await.unwrap();
+        assert!(backend.authenticate(creds("alice", "hunter2")).await.is_err());
+        store.set_status(alice.id, AccountStatus::Locked, Some(now() - 60), None).await.unwrap();
+        assert!(backend.authenticate(creds("alice", "hunter2")).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn provisions_users_from_other_backends() {
+        let (backend, _) = backend().await;
+        let backend = with_remote(backend);
+
+        let erin = backend.authenticate(creds("erin", "remote")).await.unwrap().unwrap();
+        assert_eq!(erin.pw_hash, NO_PASSWORD);
+        assert_eq!(erin.auth_source.as_deref(), Some("remote"));
+
+        let again = backend.authenticate(creds("erin", "remote")).await.unwrap().unwrap();
+        assert_eq!(again.id, erin.id);
+    }
+
+    #[tokio::test]
+    async fn other_backends_cant_take_over_local_accounts() {
+        let (backend, alice) = backend().await;
+        backend.store.set_role(alice.id, Role::Admin).await.unwrap();
+        let backend = with_remote(backend);
+
+        // Local says no, and remote mustn't be allowed to say yes instead
+        assert!(backend.authenticate(creds("alice", "remote")).await.unwrap().is_none());
+
+        let alice = backend.store.find_by_id(alice.id).await.unwrap().unwrap();
+        assert_eq!(alice.auth_source, None);
+        assert!(backend.authenticate(creds("alice", "hunter2")).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn checking_a_password_changes_nothing() {
+        let (backend, _) = backend().await;
+        // {SHA} of "secret"
+        let dave = backend.store.create("dave", "{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=").await.unwrap();
+
+        assert!(!backend.verify_password(&dave, "public").await.unwrap());
+        assert!(backend.verify_password(&dave, "secret").await.unwrap());
+
+        let after = backend.store.find_by_id(dave.id).await.unwrap().unwrap();
+        assert_eq!(after.pw_hash, dave.pw_hash);
+        assert_eq!(after.auth_source, None);
+    }
+
+    #[tokio::test]
+    async fn passwords_are_checked_where_they_came_from() {
+        let (backend, alice) = backend().await;
+        let backend = with_remote(backend);
+        let erin = backend.authenticate(creds("erin", "remote")).await.unwrap().unwrap();
+
+        assert!(backend.verify_password(&alice, "hunter2").await.unwrap());
+        // Remote would say yes, but it didn't let alice in
+        assert!(!backend.verify_password(&alice, "remote").await.unwrap());
+
+        assert!(backend.verify_password(&erin, "remote").await.unwrap());
+        assert!(!backend.verify_password(&erin, NO_PASSWORD).await.unwrap());
     }
 }
-pub type AuthSession = axum_login::AuthSession<AuthBackend>;
@@ -0,0 +1,337 @@
+use http::StatusCode;
+use leptos::ServerFnError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Everything that can go wrong in a server function, in a form the client can make sense of.
+/// It goes over the wire as JSON, so pages can tell which field a message is about, and the
+/// server function handler uses [`AuthError::status_code`] for the response.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum AuthError {
+    /// Wrong username or password. Deliberately doesn't say which.
+    InvalidCredentials,
+    NotLoggedIn,
+    /// They've got the password right but haven't done their second factor yet
+    NeedsSecondFactor,
+    /// With whatever reason whoever disabled them gave
+    Disabled { reason: Option<String> },
+    /// Signed up, but not allowed in yet
+    PendingVerification,
+    Forbidden,
+    /// Logged in, but needs to be in an organization for this
+    NoOrganization,
+    /// Too many wrong tries. `retry_after` is in seconds, if there's a set time to wait.
+    Locked {
+        message: String,
+        retry_after: Option<i64>,
+    },
+    /// Asking too often, rather than getting anything wrong
+    TooManyRequests {
+        message: String,
+        retry_after: Option<i64>,
+    },
+    /// Something wrong with what they put in `field`, so the page can show it next to it
+    Validation { field: String, message: String },
+    /// Can't be done, for a reason that isn't down to any one field
+    Rejected { message: String },
+    /// Our fault. The details get logged, not sent.
+    Internal,
+}
+
+impl AuthError {
+    pub fn validation(field: &str, message: impl Into<String>) -> Self {
+        AuthError::Validation {
+            field: field.to_owned(),
+            message: message.into(),
+        }
+    }
+
+    pub fn rejected(message: impl Into<String>) -> Self {
+        AuthError::Rejected {
+            message: message.into(),
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::InvalidCredentials
+            | AuthError::NotLoggedIn
+            | AuthError::NeedsSecondFactor => StatusCode::UNAUTHORIZED,
+            AuthError::Disabled { .. }
+            | AuthError::PendingVerification
+            | AuthError::Forbidden
+            | AuthError::NoOrganization => StatusCode::FORBIDDEN,
+            AuthError::Locked { .. } | AuthError::TooManyRequests { .. } => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            AuthError::Validation { .. } | AuthError::Rejected { .. } => StatusCode::BAD_REQUEST,
+            AuthError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// How long to wait before trying again, for `Retry-After`
+    pub fn retry_after(&self) -> Option<i64> {
+        match self {
+            AuthError::Locked { retry_after, .. }
+            | AuthError::TooManyRequests { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// Which form field it's about, if any
+    pub fn field(&self) -> Option<&str> {
+        match self {
+            AuthError::Validation { field, .. } => Some(field),
+            _ => None,
+        }
+    }
+
+    /// What to show the user
+    pub fn message(&self) -> String {
+        match self {
+            AuthError::InvalidCredentials => "Invalid login details".to_owned(),
+            AuthError::NotLoggedIn => "You need to log in".to_owned(),
+            AuthError::NeedsSecondFactor => "You need to finish logging in with your code".to_owned(),
+            AuthError::Disabled { reason: None } => "Your account has been disabled".to_owned(),
+            AuthError::Disabled { reason: Some(reason) } => {
+                format!("Your account has been disabled: {reason}")
+            }
+            AuthError::PendingVerification => "Your account hasn't been verified yet".to_owned(),
+            AuthError::Forbidden => "You're not allowed to do that".to_owned(),
+            AuthError::NoOrganization => "You're not in an organization yet".to_owned(),
+            AuthError::Locked { message, .. }
+            | AuthError::TooManyRequests { message, .. }
+            | AuthError::Validation { message, .. }
+            | AuthError::Rejected { message } => message.clone(),
+            AuthError::Internal => "Something went wrong on our end, try again later".to_owned(),
+        }
+    }
+}
+
+/// The JSON, since this is what server functions send errors as. Use [`AuthError::message`] for
+/// anything a person's going to read.
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        f.write_str(&json)
+    }
+}
+
+impl FromStr for AuthError {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s)
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// What to show for a failed server function, whether it failed on our end or never got to us.
+pub fn message(err: &ServerFnError<AuthError>) -> String {
+    match err {
+        ServerFnError::WrappedServerError(err) => err.message(),
+        ServerFnError::Request(_) => "Couldn't reach the server, try again".to_owned(),
+        err => err.to_string(),
+    }
+}
+
+/// The message if it's about `field`, for showing next to it.
+pub fn field_message(err: &ServerFnError<AuthError>, field: &str) -> Option<String> {
+    match err {
+        ServerFnError::WrappedServerError(err) if err.field() == Some(field) => Some(err.message()),
+        _ => None,
+    }
+}
+
+/// The message if it isn't about any one of `fields`, for showing under the whole form.
+pub fn form_message(err: &ServerFnError<AuthError>, fields: &[&str]) -> Option<String> {
+    match err {
+        ServerFnError::WrappedServerError(err) if err.field().is_some_and(|f| fields.contains(&f)) => {
+            None
+        }
+        err => Some(message(err)),
+    }
+}
+
+/// Server functions that fail all come back as a 500, so this swaps in the status that goes with
+/// the [`AuthError`], plus `Retry-After` if there's a wait. Anything else is left alone.
+#[cfg(feature = "ssr")]
+pub async fn with_status(res: axum::response::Response) -> axum::response::Response {
+    use axum::body::{to_bytes, Body};
+    use axum::http::header::RETRY_AFTER;
+    use leptos::server_fn::error::SERVER_FN_ERROR_HEADER;
+
+    if res.status() != StatusCode::INTERNAL_SERVER_ERROR
+        || !res.headers().contains_key(SERVER_FN_ERROR_HEADER)
+    {
+        return res;
+    }
+
+    // Errors are tiny, anything this big isn't one of ours
+    let (mut parts, body) = res.into_parts();
+    let Ok(body) = to_bytes(body, 64 * 1024).await else {
+        return axum::response::Response::from_parts(parts, Body::empty());
+    };
+    let err = std::str::from_utf8(&body)
+        .ok()
+        .and_then(|body| body.strip_prefix("WrappedServerFn|"))
+        .and_then(|err| err.parse::<AuthError>().ok());
+
+    if let Some(err) = err {
+        parts.status = err.status_code();
+        if let Some(seconds) = err.retry_after() {
+            parts.headers.insert(RETRY_AFTER, seconds.max(0).into());
+        }
+    }
+    axum::response::Response::from_parts(parts, Body::from(body))
+}
+
+#[cfg(feature = "ssr")]
+mod convert {
+    use super::AuthError;
+    use crate::auth::{AuthBackend, BackendError, Blocked};
+    use crate::group::GroupError;
+    use crate::impersonate::ImpersonateError;
+    use crate::org::OrgError;
+    use crate::otp::now;
+    use crate::otp::OtpError;
+    use crate::store::StoreError;
+    use axum_login::tower_sessions::session;
+
+    /// Logs what actually happened, since the client only hears that something did.
+    fn internal(err: impl std::fmt::Display) -> AuthError {
+        tracing::error!(%err, "Server function failed");
+        AuthError::Internal
+    }
+
+    impl From<sqlx::Error> for AuthError {
+        fn from(err: sqlx::Error) -> Self {
+            internal(err)
+        }
+    }
+
+    impl From<session::Error> for AuthError {
+        fn from(err: session::Error) -> Self {
+            internal(err)
+        }
+    }
+
+    impl From<bcrypt::BcryptError> for AuthError {
+        fn from(err: bcrypt::BcryptError) -> Self {
+            internal(err)
+        }
+    }
+
+    impl From<StoreError> for AuthError {
+        fn from(err: StoreError) -> Self {
+            match err {
+                StoreError::UsernameTaken => AuthError::validation("username", err.to_string()),
+                StoreError::EmailTaken => AuthError::validation("email", err.to_string()),
+                err => internal(err),
+            }
+        }
+    }
+
+    impl From<GroupError> for AuthError {
+        fn from(err: GroupError) -> Self {
+            match err {
+                GroupError::NameTaken(_) => AuthError::validation("name", err.to_string()),
+                GroupError::Loop(..) => AuthError::validation("parent", err.to_string()),
+                GroupError::NoSuchGroup(_) => AuthError::rejected(err.to_string()),
+                GroupError::Database(err) => internal(err),
+            }
+        }
+    }
+
+    impl From<OrgError> for AuthError {
+        fn from(err: OrgError) -> Self {
+            match err {
+                OrgError::SlugTaken(_) => AuthError::validation("slug", err.to_string()),
+                OrgError::NoSuchOrg(_) | OrgError::LastOwner => AuthError::rejected(err.to_string()),
+                OrgError::NotAMember => AuthError::Forbidden,
+                OrgError::Database(err) => internal(err),
+                OrgError::Session(err) => internal(err),
+            }
+        }
+    }
+
+    impl From<ImpersonateError> for AuthError {
+        fn from(err: ImpersonateError) -> Self {
+            match err {
+                ImpersonateError::Login(err) => err.into(),
+                err => internal(err),
+            }
+        }
+    }
+
+    impl From<BackendError> for AuthError {
+        fn from(err: BackendError) -> Self {
+            match err {
+                BackendError::Blocked(blocked) => blocked.into(),
+                err => internal(err),
+            }
+        }
+    }
+
+    impl From<Blocked> for AuthError {
+        fn from(blocked: Blocked) -> Self {
+            match blocked {
+                Blocked::Disabled { reason } => AuthError::Disabled { reason },
+                Blocked::Locked { until, reason } => {
+                    let wait = (until - now()).max(1);
+                    let minutes = (wait + 59) / 60;
+                    let message = match reason {
+                        Some(reason) => format!(
+                            "Your account is locked for another {minutes} minutes: {reason}"
+                        ),
+                        None => format!("Your account is locked for another {minutes} minutes"),
+                    };
+                    AuthError::Locked {
+                        message,
+                        retry_after: Some(wait),
+                    }
+                }
+                Blocked::PendingVerification => AuthError::PendingVerification,
+            }
+        }
+    }
+
+    impl From<axum_login::Error<AuthBackend>> for AuthError {
+        fn from(err: axum_login::Error<AuthBackend>) -> Self {
+            match err {
+                axum_login::Error::Backend(err) => err.into(),
+                axum_login::Error::Session(err) => err.into(),
+            }
+        }
+    }
+
+    impl From<OtpError> for AuthError {
+        fn from(err: OtpError) -> Self {
+            let message = err.to_string();
+            match err {
+                OtpError::TooSoon(seconds) => AuthError::TooManyRequests {
+                    message,
+                    retry_after: Some(seconds),
+                },
+                OtpError::TooManyAttempts => AuthError::Locked {
+                    message,
+                    retry_after: None,
+                },
+                OtpError::LockedOut(seconds) => AuthError::Locked {
+                    message,
+                    retry_after: Some(seconds),
+                },
+                OtpError::WrongCode(_) | OtpError::Expired | OtpError::WrongRecoveryCode => {
+                    AuthError::validation("code", message)
+                }
+                OtpError::NoChallenge | OtpError::ChannelUnavailable(_) => {
+                    AuthError::rejected(message)
+                }
+                OtpError::Send(_) | OtpError::Database(_) | OtpError::Session(_) => internal(err),
+            }
+        }
+    }
+}
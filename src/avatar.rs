@@ -0,0 +1,227 @@
+use axum::extract::{DefaultBodyLimit, Multipart, State};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Redirect, Response};
+use image::imageops::FilterType;
+use image::{ImageFormat, ImageReader, Limits};
+use rand::Rng;
+use serde::Deserialize;
+use std::io::Cursor;
+use std::path::PathBuf;
+use thiserror::Error;
+use tokio::sync::Semaphore;
+
+use crate::auth::AuthSession;
+use crate::csrf;
+use crate::state::AppState;
+
+/// Where avatars are served from, and what goes in front of the file name to make their URL
+pub const URL_PREFIX: &str = "/avatars/";
+
+/// Only the formats browsers all show, and we can decode
+const FORMATS: &[ImageFormat] = &[
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+    ImageFormat::Gif,
+    ImageFormat::WebP,
+];
+
+/// Anything bigger than this is almost certainly trying to run us out of memory. Avatars are
+/// only ever shown small, so there's no need for more.
+const MAX_DIMENSION: u32 = 4096;
+
+/// What a decoder can allocate, enough for a `MAX_DIMENSION` square image with 8 bit RGBA
+const MAX_ALLOC: u64 = MAX_DIMENSION as u64 * MAX_DIMENSION as u64 * 4;
+
+/// Decoding takes a lot of memory, so only this many uploads get decoded at once. The rest wait
+/// their turn.
+static DECODING: Semaphore = Semaphore::const_new(2);
+
+fn default_dir() -> PathBuf {
+    PathBuf::from("avatars")
+}
+
+fn default_max_upload_kb() -> usize {
+    2048
+}
+
+fn default_size() -> u32 {
+    256
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct AvatarConfig {
+    /// Made if it isn't there
+    #[serde(default = "default_dir")]
+    pub dir: PathBuf,
+
+    #[serde(default = "default_max_upload_kb")]
+    pub max_upload_kb: usize,
+
+    /// Avatars are cropped square and scaled to this many pixels a side
+    #[serde(default = "default_size")]
+    pub size: u32,
+}
+
+impl Default for AvatarConfig {
+    fn default() -> Self {
+        Self {
+            dir: default_dir(),
+            max_upload_kb: default_max_upload_kb(),
+            size: default_size(),
+        }
+    }
+}
+
+impl AvatarConfig {
+    /// For the upload route, so oversized bodies are cut off before they're all read in. A bit
+    /// over the limit for the rest of the form.
+    pub fn body_limit(&self) -> DefaultBodyLimit {
+        DefaultBodyLimit::max(self.max_upload_kb * 1024 + 16 * 1024)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum AvatarError {
+    #[error("That's bigger than {0} KB")]
+    TooBig(usize),
+
+    #[error("Avatars need to be PNG, JPEG, GIF or WebP")]
+    WrongType,
+
+    #[error("Couldn't read that image")]
+    Unreadable(#[from] image::ImageError),
+
+    #[error("No image in the upload")]
+    Missing,
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl AvatarError {
+    /// What goes in the query string when sending them back to the profile page
+    fn kind(&self) -> &'static str {
+        match self {
+            AvatarError::TooBig(_) => "too-big",
+            AvatarError::WrongType => "wrong-type",
+            AvatarError::Unreadable(_) => "unreadable",
+            AvatarError::Missing => "missing",
+            AvatarError::Io(_) => "failed",
+        }
+    }
+}
+
+/// Where the browser can get an avatar from
+pub fn url(name: &str) -> String {
+    format!("{URL_PREFIX}{name}")
+}
+
+/// Checks it's an image we take, crops and scales it, and writes it out as a PNG. Gives back its
+/// new file name, which is different every time so it can be cached forever.
+pub async fn save(config: &AvatarConfig, user_id: i64, upload: Vec<u8>) -> Result<String, AvatarError> {
+    if upload.len() > config.max_upload_kb * 1024 {
+        return Err(AvatarError::TooBig(config.max_upload_kb));
+    }
+
+    // Decoding's slow enough to hold up everyone else on the thread
+    let size = config.size;
+    let _permit = DECODING.acquire().await.map_err(std::io::Error::other)?;
+    let png = tokio::task::spawn_blocking(move || -> Result<Vec<u8>, AvatarError> {
+        // Going by what's actually in the file, not whatever the browser said it was
+        let mut reader = ImageReader::new(Cursor::new(upload)).with_guessed_format()?;
+        if !reader.format().is_some_and(|format| FORMATS.contains(&format)) {
+            return Err(AvatarError::WrongType);
+        }
+
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(MAX_DIMENSION);
+        limits.max_image_height = Some(MAX_DIMENSION);
+        limits.max_alloc = Some(MAX_ALLOC);
+        reader.limits(limits);
+
+        let image = reader.decode()?.resize_to_fill(size, size, FilterType::Lanczos3);
+        let mut png = Vec::new();
+        image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+        Ok(png)
+    })
+    .await
+    .map_err(std::io::Error::other)??;
+
+    let name = format!("{user_id}-{:016x}.png", rand::thread_rng().gen::<u64>());
+    tokio::fs::create_dir_all(&config.dir).await?;
+    tokio::fs::write(config.dir.join(&name), png).await?;
+    Ok(name)
+}
+
+/// Deletes an old avatar. It's only wasted space if this fails, so it's just logged.
+pub async fn remove(config: &AvatarConfig, name: &str) {
+    // Names only ever come from `save`, but never follow one out of the directory
+    if name.contains(['/', '\\']) || name.starts_with('.') {
+        tracing::warn!(name, "Not deleting an avatar with a strange name");
+        return;
+    }
+
+    if let Err(err) = tokio::fs::remove_file(config.dir.join(name)).await {
+        tracing::warn!(%err, name, "Couldn't delete old avatar");
+    }
+}
+
+/// Takes the `avatar` field of a multipart form post from the profile page, and sends them back
+/// there. A plain handler rather than a server function since it's a file.
+pub async fn upload_handler(
+    State(state): State<AppState>,
+    auth_session: AuthSession,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    const PROFILE: &str = "/account/profile";
+
+    if let Err(reason) = csrf::check(&state.live.get(), &headers) {
+        tracing::warn!(reason, "Refused cross-site avatar upload");
+        return (axum::http::StatusCode::FORBIDDEN, "Cross-site request refused").into_response();
+    }
+
+    let Some(user) = auth_session.user else {
+        return Redirect::to("/login").into_response();
+    };
+
+    let upload = loop {
+        match multipart.next_field().await {
+            Ok(Some(field)) if field.name() == Some("avatar") => break field.bytes().await,
+            Ok(Some(_)) => continue,
+            Ok(None) => break Ok(Default::default()),
+            Err(err) => break Err(err),
+        }
+    };
+    let res = match upload {
+        // Browsers send an empty file if nothing was picked
+        Ok(bytes) if bytes.is_empty() => Err(AvatarError::Missing),
+        Ok(bytes) => save(&state.config.avatars, user.id, bytes.to_vec()).await,
+        // Mostly the body limit being hit
+        Err(err) => {
+            tracing::info!(%err, user.username, "Avatar upload didn't make it");
+            Err(AvatarError::TooBig(state.config.avatars.max_upload_kb))
+        }
+    };
+
+    let name = match res {
+        Ok(name) => name,
+        Err(err) => {
+            tracing::info!(%err, user.username, "Rejected avatar");
+            return Redirect::to(&format!("{PROFILE}?avatar={}", err.kind())).into_response();
+        }
+    };
+
+    if let Err(err) = state.auth.store.set_avatar(user.id, Some(&name)).await {
+        tracing::error!(%err, user.username, "Couldn't save avatar");
+        remove(&state.config.avatars, &name).await;
+        return Redirect::to(&format!("{PROFILE}?avatar=failed")).into_response();
+    }
+    if let Some(old) = &user.avatar {
+        remove(&state.config.avatars, old).await;
+    }
+    tracing::info!(user.username, name, "Changed avatar");
+
+    Redirect::to(&format!("{PROFILE}?avatar=saved")).into_response()
+}
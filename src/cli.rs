@@ -0,0 +1,540 @@
+use axum::http::StatusCode;
+use std::fs::read_to_string;
+use std::path::Path;
+
+use crate::audit;
+use crate::auth::{AccountStatus, Role, User};
+use crate::group::{self, Group};
+use crate::import::{import_users, ImportFormat};
+use crate::invite;
+use crate::org;
+use crate::org::OrgRole;
+use crate::otp::now;
+use crate::permission::Permission;
+use crate::state::AppState;
+use crate::webhook;
+
+pub const USAGE: &str = "\
+Usage: rust-auth [--config <PATH>] [COMMAND]
+
+With no command, runs the server. The config is read from --config, RUST_AUTH_CONFIG or
+config.toml, with RUST_AUTH__SECTION__KEY environment variables overriding what's in it.
+
+Commands:
+    import-users <FILE> [--format htpasswd|csv]
+        Add users from an htpasswd file or CSV export. The format is guessed from the extension
+        if it's not given.
+    set-role <USERNAME> <user|admin>
+        Change what someone's allowed to do.
+    set-status <USERNAME> <active|disabled|locked|pending-verification> [--hours N] [--reason TEXT]
+        Suspend someone, or let them back in. Locks need --hours and end by themselves. The
+        reason is shown to them when they try to log in. Their sessions end straight away.
+    grant <USERNAME> <PERMISSION>
+    revoke <USERNAME> <PERMISSION>
+        Give someone a permission on top of their role, or take it away. Permissions are
+        create-invites, import-users, manage-groups, impersonate-users and manage-webhooks.
+    list-groups
+        Show every group, what it's inside, who's in it and what it can do.
+    create-group <NAME> [--parent <GROUP>]
+    delete-group <NAME>
+    set-group-parent <NAME> <GROUP|none>
+        Groups can go inside other groups. Everyone in a group counts as being in the groups
+        it's inside too, and gets their permissions.
+    add-to-group <GROUP> <USERNAME>
+    remove-from-group <GROUP> <USERNAME>
+    grant-group <GROUP> <PERMISSION>
+    revoke-group <GROUP> <PERMISSION>
+        Change who's in a group, or what everyone in it can do.
+    list-orgs
+        Show every organization and who's in it.
+    create-org <SLUG> [--name TEXT] [--owner USERNAME]
+        Make an organization. The slug is its short name for here and in URLs.
+    add-to-org <SLUG> <USERNAME> [member|admin|owner]
+        Put someone in an organization, member by default, or change their role in it.
+    remove-from-org <SLUG> <USERNAME>
+    create-invite [--role user|admin] [--org SLUG] [--org-role member|admin|owner] [--max-uses N] [--ttl-hours N]
+        Print a sign up link. Works however registration is configured. No limits by default.
+        With --org, whoever uses it is put in that organization, as a member unless --org-role
+        says otherwise.
+    receive-webhooks [--listen ADDR] [--secret SECRET] [--status CODE]
+        Run a receiver to point webhooks at while trying them out, on 127.0.0.1:4000 unless
+        --listen says otherwise. Prints what it gets and checks the signature against --secret,
+        or the secrets of the configured webhooks. Answers with --status, 200 by default, so
+        failures and retries can be tried too.";
+
+/// Runs a command from the command line. The database is all set up by the time we get here.
+pub async fn run(state: &AppState, args: &[String]) -> Result<(), String> {
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["import-users", path] => import(state, path, None).await,
+        ["import-users", path, "--format", format] | ["import-users", "--format", format, path] => {
+            import(state, path, Some(format.parse()?)).await
+        }
+        ["set-role", username, role] => set_role(state, username, role).await,
+        ["set-status", username, status, ref options @ ..] => {
+            set_status(state, username, status, options).await
+        }
+        ["grant", username, permission] => set_permission(state, username, permission, true).await,
+        ["revoke", username, permission] => set_permission(state, username, permission, false).await,
+        ["list-groups"] => list_groups(state).await,
+        ["create-group", name] => create_group(state, name, None).await,
+        ["create-group", name, "--parent", parent] => create_group(state, name, Some(parent)).await,
+        ["delete-group", name] => delete_group(state, name).await,
+        ["set-group-parent", name, parent] => set_group_parent(state, name, parent).await,
+        ["add-to-group", group, username] => set_membership(state, group, username, true).await,
+        ["remove-from-group", group, username] => {
+            set_membership(state, group, username, false).await
+        }
+        ["grant-group", group, permission] => {
+            set_group_permission(state, group, permission, true).await
+        }
+        ["revoke-group", group, permission] => {
+            set_group_permission(state, group, permission, false).await
+        }
+        ["list-orgs"] => list_orgs(state).await,
+        ["create-org", slug, ref options @ ..] => create_org(state, slug, options).await,
+        ["add-to-org", slug, username] => add_to_org(state, slug, username, "member").await,
+        ["add-to-org", slug, username, role] => add_to_org(state, slug, username, role).await,
+        ["remove-from-org", slug, username] => remove_from_org(state, slug, username).await,
+        ["create-invite", ref options @ ..] => create_invite(state, options).await,
+        ["receive-webhooks", ref options @ ..] => receive_webhooks(state, options).await,
+        ["help" | "--help" | "-h"] => {
+            println!("{USAGE}");
+            Ok(())
+        }
+        _ => Err(USAGE.to_owned()),
+    }
+}
+
+async fn import(state: &AppState, path: &str, format: Option<ImportFormat>) -> Result<(), String> {
+    let path = Path::new(path);
+    let format = format.unwrap_or(match path.extension().and_then(|e| e.to_str()) {
+        Some("csv") => ImportFormat::Csv,
+        _ => ImportFormat::Htpasswd,
+    });
+
+    let contents = read_to_string(path).map_err(|err| format!("Error reading {path:?}: {err}"))?;
+    let report = import_users(&*state.auth.store, format, &contents, None)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    for username in &report.imported {
+        println!("Imported {username}");
+    }
+    for username in &report.conflicts {
+        println!("Conflict: {username} already exists, left it alone");
+    }
+    for reason in &report.skipped {
+        println!("Skipped {reason}");
+    }
+    println!(
+        "{} imported, {} conflicts, {} skipped",
+        report.imported.len(),
+        report.conflicts.len(),
+        report.skipped.len()
+    );
+
+    Ok(())
+}
+
+fn parse_role(role: &str) -> Result<Role, String> {
+    match role {
+        "user" => Ok(Role::User),
+        "admin" => Ok(Role::Admin),
+        _ => Err(format!("Unknown role {role:?}")),
+    }
+}
+
+async fn find_user(state: &AppState, username: &str) -> Result<User, String> {
+    state
+        .auth
+        .store
+        .find_by_username(&username.to_lowercase())
+        .await
+        .map_err(|err| err.to_string())?
+        .ok_or_else(|| format!("No user called {username:?}"))
+}
+
+async fn set_role(state: &AppState, username: &str, role: &str) -> Result<(), String> {
+    let role = parse_role(role)?;
+    let user = find_user(state, username).await?;
+
+    state
+        .auth
+        .store
+        .set_role(user.id, role)
+        .await
+        .map_err(|err| err.to_string())?;
+    println!("{} is now {role:?}", user.username);
+
+    Ok(())
+}
+
+async fn set_status(
+    state: &AppState,
+    username: &str,
+    status: &str,
+    options: &[&str],
+) -> Result<(), String> {
+    let status: AccountStatus = status.parse()?;
+    let user = find_user(state, username).await?;
+
+    let mut hours = None;
+    let mut reason = None;
+    for option in options.chunks(2) {
+        match option {
+            ["--hours", value] => match value.parse::<i64>() {
+                Ok(n) if n > 0 => hours = Some(n),
+                _ => return Err(format!("Expected a whole number above 0, got {value:?}")),
+            },
+            ["--reason", value] => reason = Some(*value),
+            _ => return Err(USAGE.to_owned()),
+        }
+    }
+    let locked_until = match (status, hours) {
+        (AccountStatus::Locked, Some(hours)) => Some(now() + hours * 3600),
+        (AccountStatus::Locked, None) => return Err("Locks need --hours".to_owned()),
+        (_, Some(_)) => return Err("--hours only makes sense for locked".to_owned()),
+        (_, None) => None,
+    };
+
+    state
+        .auth
+        .store
+        .set_status(user.id, status, locked_until, reason)
+        .await
+        .map_err(|err| err.to_string())?;
+    let detail = match reason {
+        Some(reason) => format!("{}: {reason}", status.as_str()),
+        None => status.as_str().to_owned(),
+    };
+    audit::record(&state.pool, Some(user.id), audit::STATUS_CHANGED, &detail).await;
+    if status == AccountStatus::Disabled {
+        webhook::disabled(state, &user, reason).await;
+    }
+
+    match hours {
+        Some(hours) => println!("{} is now {} for {hours} hours", user.username, status.as_str()),
+        None => println!("{} is now {}", user.username, status.as_str()),
+    }
+
+    Ok(())
+}
+
+async fn set_permission(
+    state: &AppState,
+    username: &str,
+    permission: &str,
+    granted: bool,
+) -> Result<(), String> {
+    let permission: Permission = permission.parse()?;
+    let user = find_user(state, username).await?;
+
+    let store = &state.auth.store;
+    let (res, event) = if granted {
+        (store.grant(user.id, permission).await, audit::PERMISSION_GRANTED)
+    } else {
+        (store.revoke(user.id, permission).await, audit::PERMISSION_REVOKED)
+    };
+    res.map_err(|err| err.to_string())?;
+    audit::record(&state.pool, Some(user.id), event, permission.as_str()).await;
+
+    if granted {
+        println!("{} can now {}", user.username, permission.as_str());
+    } else {
+        println!("{} no longer has {}", user.username, permission.as_str());
+    }
+    if !granted && user.role.permissions().contains(&permission) {
+        println!("They still get it from being {:?} though", user.role);
+    }
+
+    Ok(())
+}
+
+async fn create_invite(state: &AppState, options: &[&str]) -> Result<(), String> {
+    let mut role = Role::User;
+    let mut org = None;
+    let mut org_role = None;
+    let mut max_uses = None;
+    let mut ttl_hours = None;
+
+    for option in options.chunks(2) {
+        match option {
+            ["--role", value] => role = parse_role(value)?,
+            ["--org", value] => {
+                let found = org::find(&state.pool, &value.to_lowercase()).await;
+                org = Some(found.map_err(|err| err.to_string())?);
+            }
+            ["--org-role", value] => org_role = Some(value.parse::<OrgRole>()?),
+            ["--max-uses", value] => max_uses = invite::parse_limit(value)?,
+            ["--ttl-hours", value] => ttl_hours = invite::parse_limit(value)?,
+            _ => return Err(USAGE.to_owned()),
+        }
+    }
+
+    let org = match (org, org_role) {
+        (Some(org), role) => Some((org.id, role.unwrap_or(OrgRole::Member))),
+        (None, Some(_)) => return Err("--org-role needs --org".to_owned()),
+        (None, None) => None,
+    };
+
+    let code = invite::create(&state.pool, None, role, org, max_uses, ttl_hours)
+        .await
+        .map_err(|err| err.to_string())?;
+    println!("{}", invite::link(&state.config.public_url(), &code));
+
+    Ok(())
+}
+
+async fn find_group(state: &AppState, name: &str) -> Result<Group, String> {
+    group::find(&state.pool, &name.to_lowercase())
+        .await
+        .map_err(|err| err.to_string())
+}
+
+async fn list_groups(state: &AppState) -> Result<(), String> {
+    let groups = group::list(&state.pool).await.map_err(|err| err.to_string())?;
+    if groups.is_empty() {
+        println!("No groups yet");
+    }
+
+    for g in &groups {
+        let parent = g.parent_id.and_then(|id| groups.iter().find(|p| p.id == id));
+        let members = group::members(&state.pool, g.id).await.map_err(|err| err.to_string())?;
+        let permissions = group::permissions(&state.pool, g.id)
+            .await
+            .map_err(|err| err.to_string())?;
+        let permissions: Vec<_> = permissions.iter().map(|p| p.as_str()).collect();
+
+        match parent {
+            Some(parent) => println!("{} (inside {})", g.name, parent.name),
+            None => println!("{}", g.name),
+        }
+        println!("    members: {}", members.join(", "));
+        println!("    permissions: {}", permissions.join(", "));
+    }
+
+    Ok(())
+}
+
+async fn create_group(state: &AppState, name: &str, parent: Option<&str>) -> Result<(), String> {
+    let name = group::normalise_name(name)?;
+    let parent = match parent {
+        Some(parent) => Some(find_group(state, parent).await?),
+        None => None,
+    };
+
+    group::create(&state.pool, &name, parent.as_ref().map(|p| p.id), None)
+        .await
+        .map_err(|err| err.to_string())?;
+    match parent {
+        Some(parent) => println!("Made {name} inside {}", parent.name),
+        None => println!("Made {name}"),
+    }
+
+    Ok(())
+}
+
+async fn delete_group(state: &AppState, name: &str) -> Result<(), String> {
+    let group = find_group(state, name).await?;
+    group::delete(&state.pool, &group, None)
+        .await
+        .map_err(|err| err.to_string())?;
+    println!("Deleted {}", group.name);
+
+    Ok(())
+}
+
+async fn set_group_parent(state: &AppState, name: &str, parent: &str) -> Result<(), String> {
+    let group = find_group(state, name).await?;
+    let parent = match parent {
+        "none" => None,
+        parent => Some(find_group(state, parent).await?),
+    };
+
+    group::set_parent(&state.pool, &group, parent.as_ref())
+        .await
+        .map_err(|err| err.to_string())?;
+    match parent {
+        Some(parent) => println!("{} is now inside {}", group.name, parent.name),
+        None => println!("{} isn't inside anything now", group.name),
+    }
+
+    Ok(())
+}
+
+async fn set_membership(state: &AppState, group: &str, username: &str, member: bool) -> Result<(), String> {
+    let group = find_group(state, group).await?;
+    let user = find_user(state, username).await?;
+
+    let changed = if member {
+        group::add_member(&state.pool, &group, user.id, None).await
+    } else {
+        group::remove_member(&state.pool, &group, user.id, None).await
+    }
+    .map_err(|err| err.to_string())?;
+
+    match (member, changed) {
+        (true, true) => println!("Added {} to {}", user.username, group.name),
+        (true, false) => println!("{} was already in {}", user.username, group.name),
+        (false, true) => println!("Took {} out of {}", user.username, group.name),
+        (false, false) => println!("{} wasn't in {}", user.username, group.name),
+    }
+
+    Ok(())
+}
+
+async fn set_group_permission(
+    state: &AppState,
+    group: &str,
+    permission: &str,
+    granted: bool,
+) -> Result<(), String> {
+    let permission: Permission = permission.parse()?;
+    let group = find_group(state, group).await?;
+
+    if granted {
+        group::grant(&state.pool, &group, permission, None).await
+    } else {
+        group::revoke(&state.pool, &group, permission, None).await
+    }
+    .map_err(|err| err.to_string())?;
+
+    if granted {
+        println!("Everyone in {} can now {}", group.name, permission.as_str());
+    } else {
+        println!("{} no longer has {}", group.name, permission.as_str());
+    }
+
+    Ok(())
+}
+
+async fn list_orgs(state: &AppState) -> Result<(), String> {
+    let orgs = org::list(&state.pool).await.map_err(|err| err.to_string())?;
+    if orgs.is_empty() {
+        println!("No organizations yet");
+    }
+
+    for o in orgs {
+        println!("{} ({})", o.slug, o.name);
+        for member in org::members(&state.pool, o.id).await.map_err(|err| err.to_string())? {
+            println!("    {} {}", member.username, member.role.as_str());
+        }
+    }
+
+    Ok(())
+}
+
+async fn create_org(state: &AppState, slug: &str, options: &[&str]) -> Result<(), String> {
+    let slug = org::normalise_slug(slug)?;
+    let mut name = None;
+    let mut owner = None;
+    for option in options.chunks(2) {
+        match option {
+            ["--name", value] => name = Some(value.trim()),
+            ["--owner", value] => owner = Some(find_user(state, value).await?),
+            _ => return Err(USAGE.to_owned()),
+        }
+    }
+
+    let org = org::create(&state.pool, &slug, name.unwrap_or(&slug), None)
+        .await
+        .map_err(|err| err.to_string())?;
+    println!("Made {} ({})", org.slug, org.name);
+
+    if let Some(owner) = owner {
+        org::set_member(&state.pool, &org, owner.id, OrgRole::Owner, None)
+            .await
+            .map_err(|err| err.to_string())?;
+        println!("{} owns it", owner.username);
+    }
+
+    Ok(())
+}
+
+async fn add_to_org(state: &AppState, slug: &str, username: &str, role: &str) -> Result<(), String> {
+    let role: OrgRole = role.parse()?;
+    let org = org::find(&state.pool, &slug.to_lowercase())
+        .await
+        .map_err(|err| err.to_string())?;
+    let user = find_user(state, username).await?;
+
+    org::set_member(&state.pool, &org, user.id, role, None)
+        .await
+        .map_err(|err| err.to_string())?;
+    println!("{} is now {} of {}", user.username, role.as_str(), org.slug);
+
+    Ok(())
+}
+
+async fn remove_from_org(state: &AppState, slug: &str, username: &str) -> Result<(), String> {
+    let org = org::find(&state.pool, &slug.to_lowercase())
+        .await
+        .map_err(|err| err.to_string())?;
+    let user = find_user(state, username).await?;
+
+    org::remove_member(&state.pool, &org, user.id, None)
+        .await
+        .map_err(|err| err.to_string())?;
+    println!("Took {} out of {}", user.username, org.slug);
+
+    Ok(())
+}
+
+async fn receive_webhooks(state: &AppState, options: &[&str]) -> Result<(), String> {
+    let mut listen = "127.0.0.1:4000";
+    let mut secret = None;
+    let mut status = "200";
+    for option in options.chunks(2) {
+        match option {
+            ["--listen", value] => listen = value,
+            ["--secret", value] => secret = Some(value.to_string()),
+            ["--status", value] => status = value,
+            _ => return Err(USAGE.to_owned()),
+        }
+    }
+    let listen = listen
+        .parse()
+        .map_err(|err| format!("{listen:?} isn't an address like 127.0.0.1:4000: {err}"))?;
+    let status = status
+        .parse::<u16>()
+        .ok()
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .ok_or_else(|| format!("{status:?} isn't an HTTP status code"))?;
+    let secrets = match secret {
+        Some(secret) => vec![secret],
+        None => state.config.webhooks.iter().map(|w| w.secret.clone()).collect(),
+    };
+
+    webhook::receive(listen, secrets, status)
+        .await
+        .map_err(|err| format!("Couldn't receive webhooks: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::NO_PASSWORD;
+    use crate::state::test_state;
+
+    #[tokio::test]
+    async fn disabling_someone_tells_webhooks() {
+        let state = test_state(
+            r#"
+            [[webhooks]]
+            url = "http://127.0.0.1:9/hook"
+            secret = "hunter2"
+            "#,
+        )
+        .await;
+        state.auth.store.create("alice", NO_PASSWORD).await.unwrap();
+
+        set_status(&state, "alice", "locked", &["--hours", "1"]).await.unwrap();
+        assert!(webhook::list(&state, 10).await.unwrap().is_empty());
+
+        set_status(&state, "alice", "disabled", &["--reason", "Spam"]).await.unwrap();
+        let deliveries = webhook::list(&state, 10).await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].event, "user.disabled");
+        assert!(deliveries[0].payload.contains(r#""reason":"Spam""#));
+    }
+}
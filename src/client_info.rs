@@ -0,0 +1,313 @@
+use axum::async_trait;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, FromRequestParts, Request, State};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use crate::state::AppState;
+
+/// An address or CIDR range, e.g. `10.0.0.0/8` or `::1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // An IPv4 client talking to an IPv6 socket shows up as ::ffff:a.b.c.d
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("{s:?} isn't an IP address or CIDR range"))?;
+
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            None => max,
+            Some(prefix) => match prefix.parse() {
+                Ok(prefix) if prefix <= max => prefix,
+                _ => return Err(format!("{s:?} has a bad prefix length")),
+            },
+        };
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl<'de> Deserialize<'de> for IpRange {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Who's on the other end, as best we can tell. Behind a trusted proxy it's whoever the proxy
+/// says it's talking to, otherwise it's whoever connected to us.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub ip: IpAddr,
+    /// "http" or "https"
+    pub scheme: String,
+}
+
+/// The pieces of `Forwarded`/`X-Forwarded-*` we care about, nearest proxy last.
+struct Forwarded {
+    /// `None` for hops that hid their address ("unknown" or an obfuscated name)
+    chain: Vec<Option<IpAddr>>,
+    proto: Option<String>,
+}
+
+/// Gets the IP out of a `for=` value, e.g. `192.0.2.60`, `"[2001:db8::1]:4711"` or `1.2.3.4:80`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.parse()
+        .ok()
+        .or_else(|| node.rsplit_once(':')?.0.parse().ok())
+}
+
+fn forwarded(headers: &HeaderMap) -> Option<Forwarded> {
+    // The standard header wins if both are there
+    let standard: Vec<&str> = headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    if !standard.is_empty() {
+        let mut chain = Vec::new();
+        let mut proto = None;
+        for element in standard.iter().flat_map(|v| v.split(',')) {
+            for pair in element.split(';') {
+                let Some((key, value)) = pair.split_once('=') else {
+                    continue;
+                };
+                match key.trim().to_ascii_lowercase().as_str() {
+                    "for" => chain.push(parse_node(value)),
+                    "proto" => proto = Some(value.trim().trim_matches('"').to_ascii_lowercase()),
+                    _ => {}
+                }
+            }
+        }
+        return Some(Forwarded { chain, proto });
+    }
+
+    let chain: Vec<Option<IpAddr>> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(parse_node)
+        .collect();
+    let proto = headers
+        .get("x-forwarded-proto")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.rsplit(',').next())
+        .map(|v| v.trim().to_ascii_lowercase());
+
+    (!chain.is_empty() || proto.is_some()).then_some(Forwarded { chain, proto })
+}
+
+impl ClientInfo {
+    /// Only believes forwarding headers as far back as the proxies are trusted, so clients can't
+    /// pretend to be someone else by sending their own.
+    pub fn from_request(
+        peer: SocketAddr,
+        headers: &HeaderMap,
+        trusted: &[IpRange],
+        default_scheme: &str,
+    ) -> Self {
+        let is_trusted = |ip: IpAddr| trusted.iter().any(|range| range.contains(ip));
+
+        let mut info = ClientInfo {
+            ip: peer.ip(),
+            scheme: default_scheme.to_owned(),
+        };
+        if !is_trusted(info.ip) {
+            return info;
+        }
+        let Some(forwarded) = forwarded(headers) else {
+            return info;
+        };
+
+        if let Some(proto @ ("http" | "https")) = forwarded.proto.as_deref() {
+            info.scheme = proto.to_owned();
+        }
+
+        // Walk back from the nearest proxy until we reach someone we don't trust
+        for hop in forwarded.chain.iter().rev() {
+            match hop {
+                Some(ip) => {
+                    info.ip = *ip;
+                    if !is_trusted(*ip) {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+
+        info
+    }
+}
+
+/// Works out the [`ClientInfo`] and leaves it in the request extensions for handlers to pick up.
+pub async fn layer(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    let default_scheme = if state.config.tls.is_some() { "https" } else { "http" };
+    let info = ClientInfo::from_request(
+        peer,
+        request.headers(),
+        &state.live.get().server.trusted_proxies,
+        default_scheme,
+    );
+    request.extensions_mut().insert(info);
+
+    next.run(request).await
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientInfo {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<ClientInfo>().cloned().ok_or((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "ClientInfo needs client_info::layer in front of it",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::header::HeaderName;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn ranges(ranges: &[&str]) -> Vec<IpRange> {
+        ranges.iter().map(|r| r.parse().unwrap()).collect()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (HeaderName::from_static(name), value.parse().unwrap()))
+            .collect()
+    }
+
+    fn client(peer: &str, pairs: &[(&'static str, &str)], trusted: &[&str]) -> ClientInfo {
+        ClientInfo::from_request(
+            SocketAddr::new(ip(peer), 4711),
+            &headers(pairs),
+            &ranges(trusted),
+            "http",
+        )
+    }
+
+    #[test]
+    fn cidr_ranges() {
+        let private: IpRange = "10.0.0.0/8".parse().unwrap();
+        assert!(private.contains(ip("10.1.2.3")));
+        assert!(!private.contains(ip("11.0.0.1")));
+        // However it gets to us
+        assert!(private.contains(ip("::ffff:10.1.2.3")));
+
+        let local: IpRange = "::1".parse().unwrap();
+        assert!(local.contains(ip("::1")));
+        assert!(!local.contains(ip("::2")));
+        assert!("2001:db8::/32".parse::<IpRange>().unwrap().contains(ip("2001:db8:1::1")));
+        assert!("0.0.0.0/0".parse::<IpRange>().unwrap().contains(ip("203.0.113.7")));
+
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("::/129".parse::<IpRange>().is_err());
+        assert!("10.0.0.0/x".parse::<IpRange>().is_err());
+        assert!("localhost".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn only_trusted_proxies_are_believed() {
+        let forwarded = [("x-forwarded-for", "203.0.113.7"), ("x-forwarded-proto", "https")];
+
+        let direct = client("198.51.100.1", &forwarded, &["10.0.0.0/8"]);
+        assert_eq!(direct.ip, ip("198.51.100.1"));
+        assert_eq!(direct.scheme, "http");
+
+        let proxied = client("10.0.0.2", &forwarded, &["10.0.0.0/8"]);
+        assert_eq!(proxied.ip, ip("203.0.113.7"));
+        assert_eq!(proxied.scheme, "https");
+    }
+
+    #[test]
+    fn walks_back_through_trusted_hops() {
+        let trusted = ["10.0.0.0/8"];
+        // Whatever the client put first is made up, the first hop we don't trust is the client
+        let chain = [("x-forwarded-for", "1.1.1.1, 203.0.113.7, 10.0.0.3")];
+        assert_eq!(client("10.0.0.2", &chain, &trusted).ip, ip("203.0.113.7"));
+
+        // As far back as it goes if every hop's trusted
+        let chain = [("x-forwarded-for", "10.0.0.4, 10.0.0.3")];
+        assert_eq!(client("10.0.0.2", &chain, &trusted).ip, ip("10.0.0.4"));
+
+        // Or as far as the nearest hop that hid itself
+        let chain = [("forwarded", "for=203.0.113.7, for=unknown, for=10.0.0.3")];
+        assert_eq!(client("10.0.0.2", &chain, &trusted).ip, ip("10.0.0.3"));
+    }
+
+    #[test]
+    fn the_standard_header() {
+        let trusted = ["10.0.0.0/8", "fd00::/8"];
+        let from = |value| client("10.0.0.2", &[("forwarded", value)], &trusted);
+
+        let info = from(r#"for="[2001:db8::1]:4711";proto=https"#);
+        assert_eq!(info.ip, ip("2001:db8::1"));
+        assert_eq!(info.scheme, "https");
+        assert_eq!(from("for=192.0.2.60:80").ip, ip("192.0.2.60"));
+        assert_eq!(from(r#"For="[fd00::1]", for=192.0.2.60"#).ip, ip("192.0.2.60"));
+        // Only http and https
+        assert_eq!(from("for=192.0.2.60;proto=gopher").scheme, "http");
+
+        // The standard one wins
+        let both = [("forwarded", "for=192.0.2.60"), ("x-forwarded-for", "192.0.2.61")];
+        assert_eq!(client("10.0.0.2", &both, &trusted).ip, ip("192.0.2.60"));
+    }
+}
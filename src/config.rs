@@ -0,0 +1,306 @@
+use std::fs::read_to_string;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+use crate::state::Config;
+
+/// Used if neither `--config` nor `RUST_AUTH_CONFIG` say otherwise. Fine for it not to exist.
+pub const DEFAULT_PATH: &str = "config.toml";
+
+/// Where to find the config file instead of `--config`
+const PATH_ENV: &str = "RUST_AUTH_CONFIG";
+
+/// `RUST_AUTH__SECTION__KEY=value` overrides `key` under `[section]`
+const ENV_PREFIX: &str = "RUST_AUTH__";
+
+/// `foo-file = "/run/secrets/foo"` sets `foo` to what's in the file, so secrets don't have to
+/// live in the config or the environment.
+const FILE_SUFFIX: &str = "-file";
+
+/// Takes `--config <PATH>` (or `--config=<PATH>`) out of the arguments, leaving whatever command
+/// there is.
+pub fn take_path_arg(args: &mut Vec<String>) -> Result<Option<PathBuf>, String> {
+    let Some(i) = args
+        .iter()
+        .position(|arg| arg == "--config" || arg.starts_with("--config="))
+    else {
+        return Ok(None);
+    };
+
+    let arg = args.remove(i);
+    match arg.strip_prefix("--config=") {
+        Some(path) => Ok(Some(PathBuf::from(path))),
+        None if i < args.len() => Ok(Some(PathBuf::from(args.remove(i)))),
+        None => Err("--config needs a path".to_owned()),
+    }
+}
+
+/// `--config` if it was given, otherwise `RUST_AUTH_CONFIG`
+fn given_path(path: Option<&Path>) -> Option<PathBuf> {
+    path.map(Path::to_owned)
+        .or_else(|| std::env::var_os(PATH_ENV).map(PathBuf::from))
+}
+
+/// Where [`load`] will look for the config file
+pub fn path(path: Option<&Path>) -> PathBuf {
+    given_path(path).unwrap_or_else(|| PathBuf::from(DEFAULT_PATH))
+}
+
+/// Builds the config up from, in order of who wins:
+///
+/// - `RUST_AUTH__*` environment variables
+/// - the file at `path`, `RUST_AUTH_CONFIG` or [`DEFAULT_PATH`]
+/// - the defaults
+///
+/// with any `*-file` keys swapped for what's in the files they name.
+pub fn load(path: Option<&Path>) -> Result<Config, String> {
+    let given = given_path(path);
+    let path = self::path(path);
+
+    // Only complain about a missing file if someone asked for it, everything could be coming from
+    // the environment
+    let mut table = match read_to_string(&path) {
+        Ok(raw) => raw
+            .parse::<Table>()
+            .map_err(|err| format!("Error parsing {}: {err}", path.display()))?,
+        Err(err) if given.is_none() && err.kind() == ErrorKind::NotFound => Table::new(),
+        Err(err) => return Err(format!("Error reading {}: {err}", path.display())),
+    };
+
+    // Sorted so the same environment always comes out the same way
+    let mut vars: Vec<(String, String)> = std::env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect();
+    vars.sort();
+    for (name, value) in vars {
+        apply_env(&mut table, &name, &value)?;
+    }
+
+    resolve_files(&mut table, "")?;
+
+    serde_path_to_error::deserialize(Value::Table(table)).map_err(|err| {
+        match err.path().to_string().as_str() {
+            "." => format!("Invalid config: {}", err.inner()),
+            key => format!("Invalid config at {key}: {}", err.inner()),
+        }
+    })
+}
+
+/// The key `segment` of an environment variable refers to. Config keys are kebab-case, which
+/// can't go in a variable name, so `PUBLIC_URL` means `public-url` unless there's already a
+/// `public_url`.
+fn key_for(table: &Table, segment: &str) -> String {
+    let snake = segment.to_lowercase();
+    let kebab = snake.replace('_', "-");
+    if table.contains_key(&snake) && !table.contains_key(&kebab) {
+        snake
+    } else {
+        kebab
+    }
+}
+
+/// Values are TOML if they parse as it (`30`, `true`, `["local", "ldap"]`), otherwise they're
+/// taken as a plain string. Quote numbers that are meant to be strings.
+fn parse_value(raw: &str) -> Value {
+    format!("value = {raw}")
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_owned()))
+}
+
+fn apply_env(root: &mut Table, name: &str, raw: &str) -> Result<(), String> {
+    let segments: Vec<&str> = name[ENV_PREFIX.len()..].split("__").collect();
+    if segments.iter().any(|segment| segment.is_empty()) {
+        return Err(format!(
+            "{name} isn't a valid override, it should look like {ENV_PREFIX}SECTION__KEY"
+        ));
+    }
+
+    let (last, sections) = segments.split_last().expect("split always gives at least one");
+    let mut table = root;
+    let mut at = vec![];
+    for section in sections {
+        let key = key_for(table, section);
+        at.push(key.clone());
+        table = table
+            .entry(key)
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| format!("{name} goes inside {}, which isn't a section", at.join(".")))?;
+    }
+
+    // Whichever way the file set it, this wins
+    let key = key_for(table, last);
+    match key.strip_suffix(FILE_SUFFIX) {
+        Some(target) => table.remove(target),
+        None => table.remove(&format!("{key}{FILE_SUFFIX}")),
+    };
+    table.insert(key, parse_value(raw));
+    Ok(())
+}
+
+/// Swaps every `foo-file = "<path>"` for `foo = "<what's in path>"`, all the way down.
+fn resolve_files(table: &mut Table, at: &str) -> Result<(), String> {
+    let keys: Vec<String> = table.keys().cloned().collect();
+    for key in keys {
+        let path = match at {
+            "" => key.clone(),
+            at => format!("{at}.{key}"),
+        };
+
+        let file = match table.get_mut(&key) {
+            Some(Value::Table(inner)) => {
+                resolve_files(inner, &path)?;
+                continue;
+            }
+            // Arrays of tables, like `[[webhooks]]`
+            Some(Value::Array(items)) => {
+                for (i, item) in items.iter_mut().enumerate() {
+                    if let Value::Table(inner) = item {
+                        resolve_files(inner, &format!("{path}[{i}]"))?;
+                    }
+                }
+                continue;
+            }
+            Some(Value::String(file)) if key.ends_with(FILE_SUFFIX) => file.clone(),
+            _ => continue,
+        };
+
+        let target = key[..key.len() - FILE_SUFFIX.len()].to_owned();
+        let target_path = &path[..path.len() - FILE_SUFFIX.len()];
+        if table.contains_key(&target) {
+            return Err(format!("{path} and {target_path} are both set, pick one"));
+        }
+
+        let contents = read_to_string(&file)
+            .map_err(|err| format!("{path}: couldn't read {file}: {err}"))?;
+        // Editors and `echo` like to leave a newline on the end, which is never part of a secret
+        let contents = contents.trim_end_matches(['\n', '\r']).to_owned();
+
+        table.remove(&key);
+        table.insert(target, Value::String(contents));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(raw: &str) -> Table {
+        raw.parse().unwrap()
+    }
+
+    fn temp_file(contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rust-auth-secret-{}", rand::random::<u64>()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn env_overrides() {
+        let mut config = table(
+            r#"
+            [server]
+            public-url = "http://localhost:3000"
+            [database]
+            max_connections = 5
+            "#,
+        );
+        let public_url = "https://auth.example.org";
+        apply_env(&mut config, "RUST_AUTH__SERVER__PUBLIC_URL", public_url).unwrap();
+        apply_env(&mut config, "RUST_AUTH__SERVER__DRAIN_TIMEOUT_SECONDS", "10").unwrap();
+        apply_env(&mut config, "RUST_AUTH__DATABASE__MAX_CONNECTIONS", "2").unwrap();
+        apply_env(&mut config, "RUST_AUTH__AUTH__BACKENDS", r#"["local", "ldap"]"#).unwrap();
+        apply_env(&mut config, "RUST_AUTH__MAIL__PORT", r#""587""#).unwrap();
+
+        assert_eq!(
+            config,
+            table(
+                r#"
+                [server]
+                public-url = "https://auth.example.org"
+                drain-timeout-seconds = 10
+                [database]
+                max_connections = 2
+                [auth]
+                backends = ["local", "ldap"]
+                [mail]
+                port = "587"
+                "#
+            )
+        );
+    }
+
+    #[test]
+    fn bad_env_overrides() {
+        let mut config = table("[server]\npublic-url = \"http://localhost:3000\"");
+        let err = apply_env(&mut config, "RUST_AUTH__SERVER__PUBLIC_URL__X", "x").unwrap_err();
+        assert!(err.contains("server.public-url, which isn't a section"), "{err}");
+        assert!(apply_env(&mut config, "RUST_AUTH____X", "x").is_err());
+        assert!(apply_env(&mut config, "RUST_AUTH__SERVER__", "x").is_err());
+    }
+
+    #[test]
+    fn env_overrides_beat_files_either_way() {
+        let mut config = table("[magic-link]\nsigning-key-file = \"/run/secrets/key\"");
+        apply_env(&mut config, "RUST_AUTH__MAGIC_LINK__SIGNING_KEY", "from the env").unwrap();
+        assert_eq!(config, table("[magic-link]\nsigning-key = \"from the env\""));
+
+        let mut config = table("[magic-link]\nsigning-key = \"from the file\"");
+        let key_file = "/run/secrets/key";
+        apply_env(&mut config, "RUST_AUTH__MAGIC_LINK__SIGNING_KEY_FILE", key_file).unwrap();
+        assert_eq!(config, table("[magic-link]\nsigning-key-file = \"/run/secrets/key\""));
+    }
+
+    #[test]
+    fn reads_secrets_from_files() {
+        let secret = temp_file("hunter2\n");
+        let mut config = table(&format!(
+            r#"
+            [mail.transport]
+            password-file = {secret:?}
+            [[webhooks]]
+            url = "https://example.org/hook"
+            secret-file = {secret:?}
+            "#
+        ));
+        resolve_files(&mut config, "").unwrap();
+        std::fs::remove_file(&secret).unwrap();
+
+        assert_eq!(config["mail"]["transport"]["password"].as_str(), Some("hunter2"));
+        assert_eq!(config["webhooks"][0]["secret"].as_str(), Some("hunter2"));
+        assert!(config["webhooks"][0].get("secret-file").is_none());
+    }
+
+    #[test]
+    fn bad_secret_files() {
+        let mut both = table("[mail]\npassword = \"a\"\npassword-file = \"/nope\"");
+        let err = resolve_files(&mut both, "").unwrap_err();
+        assert!(err.contains("mail.password-file and mail.password"), "{err}");
+
+        let mut missing = table("[mail]\npassword-file = \"/nope/not/here\"");
+        let err = resolve_files(&mut missing, "").unwrap_err();
+        assert!(err.starts_with("mail.password-file"), "{err}");
+    }
+
+    #[test]
+    fn config_path_args() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+
+        let mut given = args(&["--config", "/etc/rust-auth.toml", "list-users"]);
+        assert_eq!(take_path_arg(&mut given).unwrap(), Some(PathBuf::from("/etc/rust-auth.toml")));
+        assert_eq!(given, ["list-users"]);
+
+        let mut given = args(&["list-users", "--config=x.toml"]);
+        assert_eq!(take_path_arg(&mut given).unwrap(), Some(PathBuf::from("x.toml")));
+        assert_eq!(given, ["list-users"]);
+
+        assert_eq!(take_path_arg(&mut args(&["list-users"])).unwrap(), None);
+        assert!(take_path_arg(&mut args(&["--config"])).is_err());
+    }
+}
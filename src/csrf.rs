@@ -0,0 +1,115 @@
+use axum::http::{header, HeaderMap};
+
+use crate::state::Config;
+
+/// Checks a request to a server function came from one of our own pages, or from a browser-less
+/// client that can't be tricked into sending someone's cookies.
+///
+/// Browsers tell us where a request came from with `Sec-Fetch-Site` and `Origin`, and pages on
+/// other sites can't lie about either. The `SameSite=Strict` session cookie covers most of this
+/// already, this is for older browsers and sibling subdomains.
+pub fn check(config: &Config, headers: &HeaderMap) -> Result<(), String> {
+    let fetch_site = headers
+        .get("sec-fetch-site")
+        .and_then(|v| v.to_str().ok());
+
+    // "none" is someone typing the url in or using a bookmark
+    if let Some("same-origin" | "none") = fetch_site {
+        return Ok(());
+    }
+
+    let Some(origin) = headers.get(header::ORIGIN) else {
+        return match fetch_site {
+            Some(site) => Err(format!("{site} request without an Origin")),
+            // Not a browser, or a very old one
+            None => Ok(()),
+        };
+    };
+    let origin = origin
+        .to_str()
+        .map_err(|_| "Origin isn't valid".to_owned())?
+        .trim_end_matches('/');
+
+    if origin == config.public_url()
+        || config
+            .server
+            .trusted_origins
+            .iter()
+            .any(|trusted| trusted.trim_end_matches('/') == origin)
+    {
+        return Ok(());
+    }
+
+    // Whatever name we're being reached by, e.g. when there's no public-url set
+    let host = headers.get(header::HOST).and_then(|v| v.to_str().ok());
+    let origin_host = origin.split_once("://").map(|(_, host)| host);
+    if host.is_some() && origin_host == host {
+        return Ok(());
+    }
+
+    Err(format!("untrusted Origin {origin:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_config;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (header::HeaderName::from_static(name), value.parse().unwrap()))
+            .collect()
+    }
+
+    fn config() -> crate::state::Config {
+        test_config(
+            r#"
+            [server]
+            public-url = "https://auth.example.org/"
+            trusted-origins = ["https://app.example.org/"]
+            "#,
+        )
+    }
+
+    #[test]
+    fn browsers_saying_its_us() {
+        let config = config();
+        assert!(check(&config, &headers(&[("sec-fetch-site", "same-origin")])).is_ok());
+        assert!(check(&config, &headers(&[("sec-fetch-site", "none")])).is_ok());
+        // Says where it's from, but not where exactly
+        assert!(check(&config, &headers(&[("sec-fetch-site", "cross-site")])).is_err());
+        assert!(check(&config, &headers(&[("sec-fetch-site", "same-site")])).is_err());
+    }
+
+    #[test]
+    fn origins() {
+        let config = config();
+        let from = |origin| {
+            check(&config, &headers(&[("sec-fetch-site", "cross-site"), ("origin", origin)]))
+        };
+
+        assert!(from("https://auth.example.org").is_ok());
+        assert!(from("https://app.example.org").is_ok());
+        assert!(from("https://evil.example.org").is_err());
+        assert!(from("http://auth.example.org").is_err());
+        assert!(from("null").is_err());
+    }
+
+    #[test]
+    fn whatever_host_were_reached_by() {
+        let config = config();
+        let reached = |host, origin| {
+            check(&config, &headers(&[("host", host), ("origin", origin)]))
+        };
+
+        assert!(reached("127.0.0.1:3000", "http://127.0.0.1:3000").is_ok());
+        assert!(reached("127.0.0.1:3000", "http://127.0.0.1:3001").is_err());
+        assert!(check(&config, &headers(&[("origin", "http://127.0.0.1:3000")])).is_err());
+    }
+
+    #[test]
+    fn clients_that_arent_browsers() {
+        assert!(check(&config(), &HeaderMap::new()).is_ok());
+    }
+}
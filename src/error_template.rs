@@ -2,16 +2,52 @@ use http::status::StatusCode;
 use leptos::*;
 use thiserror::Error;
 
+use crate::auth_error::AuthError;
+
 #[derive(Clone, Debug, Error)]
 pub enum AppError {
     #[error("Not Found")]
     NotFound,
+    #[error("You need to log in to see this")]
+    Unauthorized,
+    #[error("You're not allowed to look at this")]
+    Forbidden,
+    #[error("Too many requests, slow down a bit")]
+    TooManyRequests,
+    #[error("Something went wrong on our end")]
+    Internal,
 }
 
 impl AppError {
     pub fn status_code(&self) -> StatusCode {
         match self {
             AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden => StatusCode::FORBIDDEN,
+            AppError::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// For when a whole page can't be shown because of a server function. Anything that's down to
+/// what was put in a form should be shown next to the form instead.
+impl From<&AuthError> for AppError {
+    fn from(err: &AuthError) -> Self {
+        match err.status_code() {
+            StatusCode::UNAUTHORIZED => AppError::Unauthorized,
+            StatusCode::FORBIDDEN => AppError::Forbidden,
+            StatusCode::TOO_MANY_REQUESTS => AppError::TooManyRequests,
+            _ => AppError::Internal,
+        }
+    }
+}
+
+impl From<&ServerFnError<AuthError>> for AppError {
+    fn from(err: &ServerFnError<AuthError>) -> Self {
+        match err {
+            ServerFnError::WrappedServerError(err) => err.into(),
+            _ => AppError::Internal,
         }
     }
 }
@@ -31,12 +67,19 @@ pub fn ErrorTemplate(
         },
     };
 
-    // Downcast lets us take a type that implements `std::error::Error`
+    // Downcast lets us take a type that implements `std::error::Error`. Anything we don't know
+    // about is still an error, just not one we can say much about.
     let errors: Vec<AppError> = errors
         .into_iter()
-        .filter_map(|(_k, v)| v.downcast_ref::<AppError>().cloned())
+        .map(|(_k, v)| {
+            v.downcast_ref::<AppError>()
+                .cloned()
+                .or_else(|| v.downcast_ref::<AuthError>().map(AppError::from))
+                .unwrap_or(AppError::Internal)
+        })
         .collect();
-    println!("Errors: {errors:#?}");
+    #[cfg(feature = "ssr")]
+    tracing::warn!(?errors, "Rendering errors");
 
     // Only the response code for the first error is actually sent from the server
     // this may be customized by the specific application
@@ -4,26 +4,53 @@ use axum::{
     response::IntoResponse,
     http::{Request, Response, StatusCode, Uri},
 };
+use axum::http::header::{HeaderValue, CACHE_CONTROL};
 use axum::response::Response as AxumResponse;
+use std::path::Path;
 use tower::ServiceExt;
 use tower_http::services::ServeDir;
-use crate::{app::App, state::AppState};
+use crate::{app::App, avatar, state::AppState};
 
 pub async fn file_and_error_handler(uri: Uri, State(state): State<AppState>, req: Request<Body>) -> AxumResponse {
+    // Avatars live outside the site root. Every upload gets a new name, so they can be cached for
+    // as long as browsers are willing to.
+    if let Some(name) = uri.path().strip_prefix(avatar::URL_PREFIX) {
+        if let Ok(avatar_uri) = format!("/{name}").parse::<Uri>() {
+            let mut res = get_static_file(avatar_uri, &state.config.avatars.dir).await.unwrap();
+            if res.status() == StatusCode::OK {
+                res.headers_mut().insert(
+                    CACHE_CONTROL,
+                    HeaderValue::from_static("public, max-age=31536000, immutable"),
+                );
+                return res.into_response();
+            }
+        }
+    }
+
     let root = state.config.leptos.site_root.clone();
     let res = get_static_file(uri.clone(), &root).await.unwrap();
 
     if res.status() == StatusCode::OK {
         res.into_response()
     } else {
-        let handler = leptos_axum::render_app_to_stream(state.config.leptos, App);
+        // Same as leptos_routes_handler, the scripts need the nonce
+        let nonce = req.extensions().get::<leptos::nonce::Nonce>().cloned();
+        let handler = leptos_axum::render_app_to_stream_with_context(
+            state.config.leptos,
+            move || {
+                if let Some(nonce) = nonce.clone() {
+                    leptos::provide_context(nonce);
+                }
+            },
+            App,
+        );
         handler(req).await.into_response()
     }
 }
 
 async fn get_static_file(
     uri: Uri,
-    root: &str,
+    root: impl AsRef<Path>,
 ) -> Result<Response<Body>, (StatusCode, String)> {
     let req = Request::builder()
         .uri(uri.clone())
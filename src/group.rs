@@ -0,0 +1,276 @@
+use sqlx::{FromRow, SqlitePool};
+use std::collections::HashSet;
+use thiserror::Error;
+
+use crate::audit;
+use crate::permission::Permission;
+
+/// Finds every group a set of groups is in, going up through parents. `UNION` rather than
+/// `UNION ALL` so it stops even if a loop somehow got into the database.
+const ANCESTORS: &str = "
+    WITH RECURSIVE ancestor (id) AS (
+        {start}
+        UNION
+        SELECT user_group.parent_id FROM user_group JOIN ancestor ON user_group.id = ancestor.id
+        WHERE user_group.parent_id IS NOT NULL
+    )";
+
+fn ancestors(start: &str) -> String {
+    ANCESTORS.replace("{start}", start)
+}
+
+#[derive(Error, Debug)]
+pub enum GroupError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("There's already a group called {0:?}")]
+    NameTaken(String),
+
+    #[error("No group called {0:?}")]
+    NoSuchGroup(String),
+
+    #[error("{0:?} is already inside {1:?}, so it can't be its parent")]
+    Loop(String, String),
+}
+
+#[derive(FromRow, Debug, Clone)]
+pub struct Group {
+    pub id: i64,
+    pub name: String,
+    pub parent_id: Option<i64>,
+}
+
+/// Names are case insensitive like usernames, and can't be blank or have anything odd in them.
+pub fn normalise_name(name: &str) -> Result<String, String> {
+    let name = name.trim().to_lowercase();
+    if name.is_empty() || name.len() > 64 {
+        return Err("Group names need to be between 1 and 64 characters".to_owned());
+    }
+    if !name.chars().all(|c| c.is_alphanumeric() || "-_.".contains(c)) {
+        return Err("Group names can only have letters, numbers, dashes, underscores and dots".to_owned());
+    }
+    Ok(name)
+}
+
+pub async fn find(pool: &SqlitePool, name: &str) -> Result<Group, GroupError> {
+    sqlx::query_as("SELECT id, name, parent_id FROM user_group WHERE name = ?")
+        .bind(name)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| GroupError::NoSuchGroup(name.to_owned()))
+}
+
+pub async fn find_by_id(pool: &SqlitePool, id: i64) -> Result<Group, GroupError> {
+    sqlx::query_as("SELECT id, name, parent_id FROM user_group WHERE id = ?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| GroupError::NoSuchGroup(format!("#{id}")))
+}
+
+/// Every group, by name
+pub async fn list(pool: &SqlitePool) -> Result<Vec<Group>, sqlx::Error> {
+    sqlx::query_as("SELECT id, name, parent_id FROM user_group ORDER BY name")
+        .fetch_all(pool)
+        .await
+}
+
+/// `name` should already have been through [`normalise_name`].
+pub async fn create(
+    pool: &SqlitePool,
+    name: &str,
+    parent: Option<i64>,
+    created_by: Option<i64>,
+) -> Result<i64, GroupError> {
+    let res = sqlx::query("INSERT INTO user_group (name, parent_id) VALUES (?, ?)")
+        .bind(name)
+        .bind(parent)
+        .execute(pool)
+        .await;
+
+    let id = match res {
+        Ok(res) => res.last_insert_rowid(),
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+            return Err(GroupError::NameTaken(name.to_owned()))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    audit::record(pool, created_by, audit::GROUP_CREATED, name).await;
+    Ok(id)
+}
+
+/// Members and permissions go with it. Groups inside it are left without a parent.
+pub async fn delete(pool: &SqlitePool, group: &Group, deleted_by: Option<i64>) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    for query in [
+        "DELETE FROM group_member WHERE group_id = ?",
+        "DELETE FROM group_permission WHERE group_id = ?",
+        "UPDATE user_group SET parent_id = NULL WHERE parent_id = ?",
+        "DELETE FROM user_group WHERE id = ?",
+    ] {
+        sqlx::query(query).bind(group.id).execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+
+    audit::record(pool, deleted_by, audit::GROUP_DELETED, &group.name).await;
+    Ok(())
+}
+
+/// Puts `group` inside `parent`, or takes it out of whatever it's in with `None`.
+pub async fn set_parent(pool: &SqlitePool, group: &Group, parent: Option<&Group>) -> Result<(), GroupError> {
+    if let Some(parent) = parent {
+        // Putting a group inside one of its own descendants would go round in circles
+        let query = format!(
+            "{} SELECT EXISTS (SELECT 1 FROM ancestor WHERE id = ?)",
+            ancestors("SELECT ?")
+        );
+        let inside: bool = sqlx::query_scalar(&query)
+            .bind(parent.id)
+            .bind(group.id)
+            .fetch_one(pool)
+            .await?;
+        if inside {
+            return Err(GroupError::Loop(parent.name.clone(), group.name.clone()));
+        }
+    }
+
+    sqlx::query("UPDATE user_group SET parent_id = ? WHERE id = ?")
+        .bind(parent.map(|p| p.id))
+        .bind(group.id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Usernames of everyone directly in the group
+pub async fn members(pool: &SqlitePool, group_id: i64) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT user.username FROM group_member JOIN user ON user.id = group_member.user_id
+         WHERE group_member.group_id = ? ORDER BY user.username",
+    )
+    .bind(group_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Gives back whether they weren't already in it
+pub async fn add_member(
+    pool: &SqlitePool,
+    group: &Group,
+    user_id: i64,
+    added_by: Option<i64>,
+) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query("INSERT OR IGNORE INTO group_member (group_id, user_id) VALUES (?, ?)")
+        .bind(group.id)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+    let added = res.rows_affected() == 1;
+    if added {
+        let detail = match added_by {
+            Some(by) => format!("{} by user {by}", group.name),
+            None => group.name.clone(),
+        };
+        audit::record(pool, Some(user_id), audit::GROUP_MEMBER_ADDED, &detail).await;
+    }
+    Ok(added)
+}
+
+/// Gives back whether they were in it
+pub async fn remove_member(
+    pool: &SqlitePool,
+    group: &Group,
+    user_id: i64,
+    removed_by: Option<i64>,
+) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query("DELETE FROM group_member WHERE group_id = ? AND user_id = ?")
+        .bind(group.id)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+    let removed = res.rows_affected() == 1;
+    if removed {
+        let detail = match removed_by {
+            Some(by) => format!("{} by user {by}", group.name),
+            None => group.name.clone(),
+        };
+        audit::record(pool, Some(user_id), audit::GROUP_MEMBER_REMOVED, &detail).await;
+    }
+    Ok(removed)
+}
+
+/// Only what the group's been given itself, not what it gets from its parents
+pub async fn permissions(pool: &SqlitePool, group_id: i64) -> Result<Vec<Permission>, sqlx::Error> {
+    sqlx::query_scalar("SELECT permission FROM group_permission WHERE group_id = ? ORDER BY permission")
+        .bind(group_id)
+        .fetch_all(pool)
+        .await
+}
+
+/// What anyone in the group gets, counting its parents
+pub async fn effective_permissions(
+    pool: &SqlitePool,
+    group_id: i64,
+) -> Result<HashSet<Permission>, sqlx::Error> {
+    let query = format!(
+        "{} SELECT DISTINCT permission FROM group_permission WHERE group_id IN (SELECT id FROM ancestor)",
+        ancestors("SELECT ?")
+    );
+    let permissions: Vec<Permission> = sqlx::query_scalar(&query).bind(group_id).fetch_all(pool).await?;
+    Ok(permissions.into_iter().collect())
+}
+
+pub async fn grant(
+    pool: &SqlitePool,
+    group: &Group,
+    permission: Permission,
+    granted_by: Option<i64>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT OR IGNORE INTO group_permission (group_id, permission) VALUES (?, ?)")
+        .bind(group.id)
+        .bind(permission)
+        .execute(pool)
+        .await?;
+
+    let detail = format!("{}: {}", group.name, permission.as_str());
+    audit::record(pool, granted_by, audit::GROUP_PERMISSION_GRANTED, &detail).await;
+    Ok(())
+}
+
+pub async fn revoke(
+    pool: &SqlitePool,
+    group: &Group,
+    permission: Permission,
+    revoked_by: Option<i64>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM group_permission WHERE group_id = ? AND permission = ?")
+        .bind(group.id)
+        .bind(permission)
+        .execute(pool)
+        .await?;
+
+    let detail = format!("{}: {}", group.name, permission.as_str());
+    audit::record(pool, revoked_by, audit::GROUP_PERMISSION_REVOKED, &detail).await;
+    Ok(())
+}
+
+/// Names of every group someone's in, directly or through a group inside it
+pub async fn of_user(pool: &SqlitePool, user_id: i64) -> Result<Vec<String>, sqlx::Error> {
+    let query = format!(
+        "{} SELECT name FROM user_group WHERE id IN (SELECT id FROM ancestor) ORDER BY name",
+        ancestors("SELECT group_id FROM group_member WHERE user_id = ?")
+    );
+    sqlx::query_scalar(&query).bind(user_id).fetch_all(pool).await
+}
+
+/// What someone gets from all of [`of_user`]
+pub async fn user_permissions(pool: &SqlitePool, user_id: i64) -> Result<Vec<Permission>, sqlx::Error> {
+    let query = format!(
+        "{} SELECT DISTINCT permission FROM group_permission WHERE group_id IN (SELECT id FROM ancestor)",
+        ancestors("SELECT group_id FROM group_member WHERE user_id = ?")
+    );
+    sqlx::query_scalar(&query).bind(user_id).fetch_all(pool).await
+}
@@ -0,0 +1,118 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::collections::HashSet;
+use std::future::Future;
+use std::time::Duration;
+
+/// How long any one check gets before it counts as failed. Orchestrators give up on probes
+/// pretty quickly, so better we say what went wrong than get cut off.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    /// What went wrong, if it did
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Health {
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub checks: Vec<Check>,
+}
+
+/// We're up. Doesn't touch anything, so a slow database doesn't get us restarted.
+pub async fn healthz() -> Json<Health> {
+    Json(Health {
+        status: "ok",
+        checks: vec![],
+    })
+}
+
+/// Whether we can actually serve anyone: the database answers, it's got all our migrations, and
+/// sessions can be written. Responds 503 if any of that isn't true.
+pub async fn readyz(State(pool): State<SqlitePool>) -> (StatusCode, Json<Health>) {
+    let checks = vec![
+        check("database", database(&pool)).await,
+        check("migrations", migrations(&pool)).await,
+        check("session_store", session_store(&pool)).await,
+    ];
+
+    if checks.iter().all(|check| check.ok) {
+        (StatusCode::OK, Json(Health { status: "ok", checks }))
+    } else {
+        tracing::warn!(?checks, "Not ready");
+        let health = Health {
+            status: "unavailable",
+            checks,
+        };
+        (StatusCode::SERVICE_UNAVAILABLE, Json(health))
+    }
+}
+
+async fn check(name: &'static str, fut: impl Future<Output = Result<(), String>>) -> Check {
+    let res = match tokio::time::timeout(CHECK_TIMEOUT, fut).await {
+        Ok(res) => res,
+        Err(_) => Err(format!("timed out after {}s", CHECK_TIMEOUT.as_secs())),
+    };
+
+    Check {
+        name,
+        ok: res.is_ok(),
+        error: res.err(),
+    }
+}
+
+async fn database(pool: &SqlitePool) -> Result<(), String> {
+    sqlx::query("SELECT 1")
+        .execute(pool)
+        .await
+        .map_err(|err| err.to_string())?;
+    Ok(())
+}
+
+async fn migrations(pool: &SqlitePool) -> Result<(), String> {
+    let applied: HashSet<i64> =
+        sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success = 1")
+            .fetch_all(pool)
+            .await
+            .map_err(|err| err.to_string())?
+            .into_iter()
+            .collect();
+
+    let missing: Vec<String> = sqlx::migrate!()
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version))
+        .map(|migration| migration.version.to_string())
+        .collect();
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("not applied: {}", missing.join(", ")))
+    }
+}
+
+/// Writes a session and deletes it again. It has to actually be committed, reading works fine
+/// from a file that's gone read-only and writes that get rolled back never make it to disk.
+async fn session_store(pool: &SqlitePool) -> Result<(), String> {
+    // Already expired, so nothing could ever use it even if the delete didn't happen
+    sqlx::query(
+        "INSERT OR REPLACE INTO tower_sessions (id, data, expiry_date) VALUES ('readyz', x'', 0)",
+    )
+    .execute(pool)
+    .await
+    .map_err(|err| err.to_string())?;
+
+    sqlx::query("DELETE FROM tower_sessions WHERE id = 'readyz'")
+        .execute(pool)
+        .await
+        .map_err(|err| err.to_string())?;
+    Ok(())
+}
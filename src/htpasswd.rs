@@ -0,0 +1,241 @@
+use axum::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use md5::{Digest, Md5};
+use serde::Deserialize;
+use sha1::Sha1;
+use std::fmt::{self, Debug};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::auth::{find_or_provision, Authenticator, BackendError, Credentials, User};
+use crate::metrics;
+use crate::store::UserStore;
+
+/// The alphabet crypt(3) uses for its "base64".
+const CRYPT_ALPHABET: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+const APR1_MAGIC: &str = "$apr1$";
+const SHA_PREFIX: &str = "{SHA}";
+
+/// The kinds of hash Apache puts in htpasswd files that we know how to check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashKind {
+    Bcrypt,
+    Apr1,
+    Sha1,
+}
+
+impl HashKind {
+    /// `None` for anything we can't verify (e.g. old DES crypt or plain text).
+    pub fn of(hash: &str) -> Option<Self> {
+        if ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|p| hash.starts_with(p)) {
+            Some(HashKind::Bcrypt)
+        } else if hash.starts_with(APR1_MAGIC) {
+            Some(HashKind::Apr1)
+        } else if hash.starts_with(SHA_PREFIX) {
+            Some(HashKind::Sha1)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub username: String,
+    pub hash: String,
+}
+
+/// Reads `user:hash` lines, skipping blanks and `#` comments. Bad lines come back as errors
+/// (with their line number) so the caller can decide whether to care.
+pub fn parse(contents: &str) -> Vec<Result<Entry, String>> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(i, line)| match line.trim().split_once(':') {
+            Some((username, hash)) if !username.is_empty() && !hash.is_empty() => Ok(Entry {
+                username: username.to_owned(),
+                hash: hash.to_owned(),
+            }),
+            _ => Err(format!("line {}: expected username:hash", i + 1)),
+        })
+        .collect()
+}
+
+/// Checks a password against any hash [`HashKind::of`] recognises. Everything else is a no.
+pub fn verify(password: &str, hash: &str) -> bool {
+    match HashKind::of(hash) {
+        Some(HashKind::Bcrypt) => bcrypt::verify(password, hash).unwrap_or(false),
+        Some(HashKind::Apr1) => {
+            let salt = hash[APR1_MAGIC.len()..].split('$').next().unwrap_or_default();
+            constant_time_eq(apr1(password, salt).as_bytes(), hash.as_bytes())
+        }
+        Some(HashKind::Sha1) => {
+            let digest = STANDARD.encode(Sha1::digest(password.as_bytes()));
+            constant_time_eq(digest.as_bytes(), &hash.as_bytes()[SHA_PREFIX.len()..])
+        }
+        None => false,
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Apache's variant of md5-crypt. It's the FreeBSD algorithm with a different magic string,
+/// thousand rounds and all.
+fn apr1(password: &str, salt: &str) -> String {
+    let pw = password.as_bytes();
+    let salt = &salt.as_bytes()[..salt.len().min(8)];
+
+    let alternate = Md5::new()
+        .chain_update(pw)
+        .chain_update(salt)
+        .chain_update(pw)
+        .finalize();
+
+    let mut ctx = Md5::new()
+        .chain_update(pw)
+        .chain_update(APR1_MAGIC)
+        .chain_update(salt);
+    for chunk in pw.chunks(16) {
+        ctx.update(&alternate[..chunk.len()]);
+    }
+    let mut i = pw.len();
+    while i > 0 {
+        if i & 1 == 1 {
+            ctx.update([0]);
+        } else {
+            ctx.update(&pw[..1]);
+        }
+        i >>= 1;
+    }
+    let mut digest = ctx.finalize();
+
+    // Supposedly this slows down brute forcing. It did in 1994 anyway.
+    for round in 0..1000 {
+        let mut ctx = Md5::new();
+        if round & 1 == 1 {
+            ctx.update(pw);
+        } else {
+            ctx.update(digest);
+        }
+        if round % 3 != 0 {
+            ctx.update(salt);
+        }
+        if round % 7 != 0 {
+            ctx.update(pw);
+        }
+        if round & 1 == 1 {
+            ctx.update(digest);
+        } else {
+            ctx.update(pw);
+        }
+        digest = ctx.finalize();
+    }
+
+    let mut out = format!("{APR1_MAGIC}{}$", String::from_utf8_lossy(salt));
+    let mut push = |value: u32, chars: usize| {
+        for i in 0..chars {
+            out.push(CRYPT_ALPHABET[((value >> (6 * i)) & 0x3f) as usize] as char);
+        }
+    };
+    for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        push(
+            (digest[a] as u32) << 16 | (digest[b] as u32) << 8 | digest[c] as u32,
+            4,
+        );
+    }
+    push(digest[11] as u32, 2);
+
+    out
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct HtpasswdConfig {
+    pub path: PathBuf,
+}
+
+/// The last parsed copy of the file, and when it was written.
+#[derive(Default)]
+struct Cache {
+    modified: Option<SystemTime>,
+    entries: Arc<Vec<Entry>>,
+}
+
+// Keep the hashes out of the logs
+impl Debug for Cache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cache")
+            .field("modified", &self.modified)
+            .field("entries", &self.entries.len())
+            .finish()
+    }
+}
+
+/// Checks credentials against an Apache style htpasswd file. The parsed file is kept around until
+/// its modification time changes, so it can still be edited without a restart.
+#[derive(Debug, Clone)]
+pub struct HtpasswdAuthenticator {
+    pub config: HtpasswdConfig,
+    pub store: Arc<dyn UserStore>,
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl HtpasswdAuthenticator {
+    pub fn new(config: HtpasswdConfig, store: Arc<dyn UserStore>) -> Self {
+        Self { config, store, cache: Default::default() }
+    }
+
+    /// The file's entries, only reading it again if it's changed since last time.
+    async fn entries(&self) -> Result<Arc<Vec<Entry>>, BackendError> {
+        let modified = tokio::fs::metadata(&self.config.path).await?.modified()?;
+        {
+            let cache = self.cache.lock().expect("htpasswd cache lock shouldn't be poisoned");
+            if cache.modified == Some(modified) {
+                return Ok(cache.entries.clone());
+            }
+        }
+
+        let contents = tokio::fs::read_to_string(&self.config.path).await?;
+        let entries = Arc::new(parse(&contents).into_iter().flatten().collect::<Vec<_>>());
+        let mut cache = self.cache.lock().expect("htpasswd cache lock shouldn't be poisoned");
+        *cache = Cache { modified: Some(modified), entries: entries.clone() };
+        Ok(entries)
+    }
+}
+
+#[async_trait]
+impl Authenticator for HtpasswdAuthenticator {
+    fn name(&self) -> &'static str {
+        "htpasswd"
+    }
+
+    async fn authenticate(&self, creds: &Credentials) -> Result<Option<User>, BackendError> {
+        let entries = self.entries().await?;
+        let username = creds.username.trim();
+
+        // htpasswd usernames are case sensitive, but ours all end up lowercase
+        let Some(entry) = entries.iter().find(|e| e.username.to_lowercase() == username.to_lowercase())
+        else {
+            return Ok(None);
+        };
+
+        if !metrics::time_hash("verify", || verify(&creds.password, &entry.hash)) {
+            return Ok(None);
+        }
+
+        Ok(find_or_provision(&*self.store, &entry.username.to_lowercase(), self.name()).await?)
+    }
+
+    async fn verify(&self, user: &User, password: &str) -> Result<bool, BackendError> {
+        let entries = self.entries().await?;
+        Ok(entries
+            .iter()
+            .find(|e| e.username.to_lowercase() == user.username)
+            .is_some_and(|entry| verify(password, &entry.hash)))
+    }
+}
@@ -0,0 +1,129 @@
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use axum_login::tower_sessions::{session, Session};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use thiserror::Error;
+
+use crate::audit;
+use crate::auth::{AuthBackend, AuthSession, User};
+use crate::otp::now;
+use crate::state::AppState;
+use crate::store::StoreError;
+
+const IMPERSONATION_KEY: &str = "impersonation";
+
+#[derive(Error, Debug)]
+pub enum ImpersonateError {
+    #[error("Session error: {0}")]
+    Session(#[from] session::Error),
+
+    #[error("Couldn't switch users: {0}")]
+    Login(#[from] axum_login::Error<AuthBackend>),
+
+    #[error("Store error: {0}")]
+    Store(#[from] StoreError),
+}
+
+/// Someone looking at the site as somebody else. Kept in the session next to the user it's
+/// logged in as, which is `user_id` until it's over.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Impersonation {
+    /// Who to go back to
+    pub admin_id: i64,
+    pub user_id: i64,
+    pub expires_at: i64,
+}
+
+/// Whoever's being impersonated in this session, if anyone.
+pub async fn current(session: &Session) -> Result<Option<Impersonation>, session::Error> {
+    session.get(IMPERSONATION_KEY).await
+}
+
+/// Logs this session in as `user`, remembering to come back to `admin` after `minutes`. Whether
+/// they're allowed to is up to the caller.
+pub async fn start(
+    pool: &SqlitePool,
+    auth_session: &mut AuthSession,
+    session: &Session,
+    admin: &User,
+    user: &User,
+    minutes: i64,
+) -> Result<(), ImpersonateError> {
+    auth_session.login(user).await?;
+    let impersonation = Impersonation {
+        admin_id: admin.id,
+        user_id: user.id,
+        expires_at: now() + minutes * 60,
+    };
+    session.insert(IMPERSONATION_KEY, impersonation).await?;
+
+    let detail = format!("as {} for {minutes} minutes", user.username);
+    audit::record(pool, Some(admin.id), audit::IMPERSONATION_STARTED, &detail).await;
+    tracing::info!(admin = admin.username, user.username, minutes, "Started impersonating");
+    Ok(())
+}
+
+/// Goes back to whoever started the impersonation. `why` is for the audit log. If they can't be
+/// logged back in, say because they've since been disabled, the session's logged out instead.
+pub async fn stop(
+    pool: &SqlitePool,
+    auth_session: &mut AuthSession,
+    session: &Session,
+    why: &str,
+) -> Result<Option<User>, ImpersonateError> {
+    let Some(impersonation) = current(session).await? else {
+        return Ok(None);
+    };
+
+    let admin = auth_session
+        .backend
+        .store
+        .find_by_id(impersonation.admin_id)
+        .await?
+        .filter(|admin| admin.check_status().is_ok());
+    match &admin {
+        Some(admin) => auth_session.login(admin).await?,
+        None => {
+            auth_session.logout().await?;
+        }
+    }
+    session.remove::<Impersonation>(IMPERSONATION_KEY).await?;
+
+    let detail = format!("as user {}, {why}", impersonation.user_id);
+    audit::record(pool, Some(impersonation.admin_id), audit::IMPERSONATION_ENDED, &detail).await;
+    tracing::info!(admin_id = impersonation.admin_id, impersonation.user_id, why, "Stopped impersonating");
+    Ok(admin)
+}
+
+/// Ends impersonations that have run out of time, before anything else sees the request.
+pub async fn layer(State(state): State<AppState>, mut request: Request<Body>, next: Next) -> Response {
+    let (Some(mut auth_session), Some(session)) = (
+        request.extensions().get::<AuthSession>().cloned(),
+        request.extensions().get::<Session>().cloned(),
+    ) else {
+        return next.run(request).await;
+    };
+
+    let expired = match current(&session).await {
+        Ok(impersonation) => impersonation.is_some_and(|i| i.expires_at <= now()),
+        Err(err) => {
+            tracing::error!(%err, "Couldn't check for impersonation");
+            false
+        }
+    };
+    if expired {
+        match stop(&state.pool, &mut auth_session, &session, "expired").await {
+            // Handlers get their `AuthSession` from here, so they need to see who it is now
+            Ok(_) => {
+                request.extensions_mut().insert(auth_session);
+            }
+            Err(err) => tracing::error!(%err, "Couldn't end an expired impersonation"),
+        }
+    }
+
+    next.run(request).await
+}
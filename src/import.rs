@@ -0,0 +1,163 @@
+#[cfg(feature = "ssr")]
+use bcrypt::hash;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "ssr")]
+use crate::auth::{Role, BCRYPT_COST};
+#[cfg(feature = "ssr")]
+use crate::htpasswd::{self, HashKind};
+#[cfg(feature = "ssr")]
+use crate::metrics;
+#[cfg(feature = "ssr")]
+use crate::store::{StoreError, UserStore};
+
+/// What happened to each user in an import.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ImportReport {
+    pub imported: Vec<String>,
+    /// Usernames that were already taken, so were left alone
+    pub conflicts: Vec<String>,
+    /// Rows we couldn't make sense of, and why
+    pub skipped: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    Htpasswd,
+    Csv,
+}
+
+impl std::str::FromStr for ImportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "htpasswd" => Ok(ImportFormat::Htpasswd),
+            "csv" => Ok(ImportFormat::Csv),
+            _ => Err(format!("Unknown format {s:?}, expected htpasswd or csv")),
+        }
+    }
+}
+
+/// One row of a CSV export. Needs a `username` column and one of `password_hash` or `password`
+/// (plain text, which gets hashed on the way in). `role` is optional.
+#[cfg(feature = "ssr")]
+#[derive(Deserialize, Debug)]
+struct CsvRow {
+    username: String,
+    password_hash: Option<String>,
+    password: Option<String>,
+    role: Option<Role>,
+}
+
+/// A user that's ready to go into the store.
+#[cfg(feature = "ssr")]
+struct NewUser {
+    username: String,
+    pw_hash: String,
+    role: Option<Role>,
+}
+
+/// Adds everyone in `contents` to the store. Existing users are never touched, they're reported
+/// as conflicts instead. Hashes are kept as they are as long as we know how to check them, so
+/// nobody has to pick a new password.
+///
+/// Rows asking for a role above `max_role` are skipped, so importing can't hand out more than
+/// whoever's doing it has. `None` is for the command line, which can do anything anyway.
+#[cfg(feature = "ssr")]
+pub async fn import_users(
+    store: &dyn UserStore,
+    format: ImportFormat,
+    contents: &str,
+    max_role: Option<Role>,
+) -> Result<ImportReport, StoreError> {
+    let mut report = ImportReport::default();
+
+    let rows: Vec<Result<NewUser, String>> = match format {
+        ImportFormat::Htpasswd => htpasswd::parse(contents)
+            .into_iter()
+            .map(|entry| {
+                let entry = entry?;
+                Ok(NewUser {
+                    username: entry.username,
+                    pw_hash: entry.hash,
+                    role: None,
+                })
+            })
+            .collect(),
+        ImportFormat::Csv => csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(contents.as_bytes())
+            .deserialize::<CsvRow>()
+            .enumerate()
+            .map(|(i, row)| csv_user(row).map_err(|err| format!("row {}: {err}", i + 1)))
+            .collect(),
+    };
+
+    for row in rows {
+        let user = match row {
+            Ok(user) => user,
+            Err(err) => {
+                report.skipped.push(err);
+                continue;
+            }
+        };
+
+        // Same as signing up
+        let username = user.username.trim().to_lowercase();
+        if username.is_empty() {
+            report.skipped.push("A user without a username".to_owned());
+            continue;
+        }
+        if HashKind::of(&user.pw_hash).is_none() {
+            report
+                .skipped
+                .push(format!("{username}: can't verify that kind of password hash"));
+            continue;
+        }
+        if let (Some(role), Some(max_role)) = (user.role, max_role) {
+            if role > max_role {
+                report
+                    .skipped
+                    .push(format!("{username}: can't give out a role above your own"));
+                continue;
+            }
+        }
+
+        let created = match store.create(&username, &user.pw_hash).await {
+            Ok(created) => created,
+            Err(StoreError::UsernameTaken) => {
+                report.conflicts.push(username);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+
+        if let Some(role) = user.role {
+            store.set_role(created.id, role).await?;
+        }
+        report.imported.push(username);
+    }
+
+    Ok(report)
+}
+
+#[cfg(feature = "ssr")]
+fn csv_user(row: Result<CsvRow, csv::Error>) -> Result<NewUser, String> {
+    let row = row.map_err(|err| err.to_string())?;
+
+    let pw_hash = match (row.password_hash, row.password) {
+        (Some(pw_hash), _) if !pw_hash.is_empty() => pw_hash,
+        (_, Some(password)) if !password.is_empty() => {
+            metrics::time_hash("hash", || hash(&password, BCRYPT_COST))
+                .map_err(|err| err.to_string())?
+        }
+        _ => return Err("needs a password_hash or password".to_owned()),
+    };
+
+    Ok(NewUser {
+        username: row.username,
+        pw_hash,
+        role: row.role,
+    })
+}
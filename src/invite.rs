@@ -0,0 +1,246 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, SqlitePool};
+
+use crate::audit;
+use crate::auth::Role;
+use crate::org::OrgRole;
+
+const CODE_LEN: usize = 16;
+
+/// Codes are random enough that a plain hash is fine, same as recovery codes.
+fn hash(code: &str) -> String {
+    Sha256::digest(code.trim().as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// An invite as it sits in the database. The code itself is only ever seen once.
+#[derive(FromRow, Debug, Clone)]
+pub struct Invite {
+    pub id: i64,
+    pub created_by: Option<i64>,
+    /// What everyone who signs up with it becomes
+    pub role: Role,
+    pub max_uses: Option<i64>,
+    pub uses: i64,
+    pub expires_at: Option<i64>,
+    pub created_at: i64,
+    /// The organization they're put in, and as what
+    pub org_id: Option<i64>,
+    pub org_role: Option<OrgRole>,
+}
+
+/// Whether someone who's `inviter` can hand out invites for `role`. Otherwise anyone who can
+/// invite could make themselves an admin.
+pub fn check_role(inviter: Role, role: Role) -> Result<(), String> {
+    if role > inviter {
+        return Err("You can't invite people as something you're not".to_owned());
+    }
+    Ok(())
+}
+
+/// Reads a `max_uses` or `ttl_hours` as typed in. Blank means no limit.
+pub fn parse_limit(value: &str) -> Result<Option<i64>, String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    match value.parse() {
+        Ok(n) if n > 0 => Ok(Some(n)),
+        _ => Err(format!("Expected a whole number above 0, got {value:?}")),
+    }
+}
+
+/// Makes a new invite and gives back its code. `None`s mean no limit, or no organization.
+pub async fn create(
+    pool: &SqlitePool,
+    created_by: Option<i64>,
+    role: Role,
+    org: Option<(i64, OrgRole)>,
+    max_uses: Option<i64>,
+    ttl_hours: Option<i64>,
+) -> Result<String, sqlx::Error> {
+    let mut code = [0; CODE_LEN];
+    rand::thread_rng().fill_bytes(&mut code);
+    let code = URL_SAFE_NO_PAD.encode(code);
+
+    let res = sqlx::query(
+        "INSERT INTO invite (code_hash, created_by, role, org_id, org_role, max_uses, expires_at)
+         VALUES (?, ?, ?, ?, ?, ?, unixepoch() + ? * 3600)",
+    )
+    .bind(hash(&code))
+    .bind(created_by)
+    .bind(role)
+    .bind(org.map(|(id, _)| id))
+    .bind(org.map(|(_, role)| role))
+    .bind(max_uses)
+    .bind(ttl_hours)
+    .execute(pool)
+    .await?;
+
+    audit::record(
+        pool,
+        created_by,
+        audit::INVITE_CREATED,
+        &match org {
+            Some((org_id, org_role)) => format!(
+                "invite {} for {}, {} of org {org_id}",
+                res.last_insert_rowid(),
+                role.as_str(),
+                org_role.as_str()
+            ),
+            None => format!("invite {} for {}", res.last_insert_rowid(), role.as_str()),
+        },
+    )
+    .await;
+    Ok(code)
+}
+
+/// Every invite, newest first, including ones that are used up or expired. Only the ones into
+/// `org` if it's given.
+pub async fn list(pool: &SqlitePool, org: Option<i64>) -> Result<Vec<Invite>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT id, created_by, role, max_uses, uses, expires_at, created_at, org_id, org_role
+         FROM invite WHERE ?1 IS NULL OR org_id = ?1
+         ORDER BY id DESC",
+    )
+    .bind(org)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn find_by_id(pool: &SqlitePool, id: i64) -> Result<Option<Invite>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT id, created_by, role, max_uses, uses, expires_at, created_at, org_id, org_role
+         FROM invite WHERE id = ?",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Stops an invite from working. Anyone who already used it keeps their account.
+pub async fn revoke(pool: &SqlitePool, id: i64, revoked_by: Option<i64>) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query("DELETE FROM invite WHERE id = ?")
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    let revoked = res.rows_affected() == 1;
+    if revoked {
+        audit::record(pool, revoked_by, audit::INVITE_REVOKED, &format!("invite {id}")).await;
+    }
+    Ok(revoked)
+}
+
+/// Uses up one go of an invite. `None` if there's no such code, or it's expired or used up.
+pub async fn redeem(pool: &SqlitePool, code: &str) -> Result<Option<Invite>, sqlx::Error> {
+    let invite: Option<Invite> = sqlx::query_as(
+        "SELECT id, created_by, role, max_uses, uses, expires_at, created_at, org_id, org_role
+         FROM invite WHERE code_hash = ?",
+    )
+    .bind(hash(code))
+    .fetch_optional(pool)
+    .await?;
+    let Some(invite) = invite else {
+        return Ok(None);
+    };
+
+    // The limits get checked again here so two people can't squeeze into the last use at once
+    let res = sqlx::query(
+        "UPDATE invite SET uses = uses + 1
+         WHERE id = ? AND (max_uses IS NULL OR uses < max_uses)
+           AND (expires_at IS NULL OR expires_at > unixepoch())",
+    )
+    .bind(invite.id)
+    .execute(pool)
+    .await?;
+
+    Ok((res.rows_affected() == 1).then_some(invite))
+}
+
+/// Hands back a use from [`redeem`] when the sign up fell through afterwards.
+pub async fn give_back(pool: &SqlitePool, id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE invite SET uses = uses - 1 WHERE id = ? AND uses > 0")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Where to send someone so the code's already filled in.
+pub fn link(public_url: &str, code: &str) -> String {
+    format!("{public_url}/signup?invite={code}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_pool;
+
+    #[test]
+    fn only_as_far_as_your_own_role() {
+        assert!(check_role(Role::Admin, Role::Admin).is_ok());
+        assert!(check_role(Role::Admin, Role::User).is_ok());
+        assert!(check_role(Role::User, Role::User).is_ok());
+        assert!(check_role(Role::User, Role::Admin).is_err());
+    }
+
+    #[test]
+    fn limits() {
+        assert_eq!(parse_limit(" 5 "), Ok(Some(5)));
+        assert_eq!(parse_limit(""), Ok(None));
+        assert!(parse_limit("0").is_err());
+        assert!(parse_limit("-1").is_err());
+        assert!(parse_limit("lots").is_err());
+    }
+
+    #[tokio::test]
+    async fn used_up() {
+        let pool = test_pool().await;
+        let code = create(&pool, None, Role::User, None, Some(2), None).await.unwrap();
+
+        let first = redeem(&pool, &code).await.unwrap().unwrap();
+        assert_eq!(first.role, Role::User);
+        redeem(&pool, &code).await.unwrap().unwrap();
+        assert!(redeem(&pool, &code).await.unwrap().is_none());
+
+        // Handing one back lets exactly one more person in
+        give_back(&pool, first.id).await.unwrap();
+        redeem(&pool, &code).await.unwrap().unwrap();
+        assert!(redeem(&pool, &code).await.unwrap().is_none());
+        assert_eq!(find_by_id(&pool, first.id).await.unwrap().unwrap().uses, 2);
+    }
+
+    #[tokio::test]
+    async fn give_back_never_goes_below_nothing() {
+        let pool = test_pool().await;
+        let code = create(&pool, None, Role::Admin, None, Some(1), None).await.unwrap();
+        let invite = redeem(&pool, &code).await.unwrap().unwrap();
+
+        give_back(&pool, invite.id).await.unwrap();
+        give_back(&pool, invite.id).await.unwrap();
+        assert_eq!(find_by_id(&pool, invite.id).await.unwrap().unwrap().uses, 0);
+    }
+
+    #[tokio::test]
+    async fn expired_revoked_and_made_up() {
+        let pool = test_pool().await;
+        let expired = create(&pool, None, Role::User, None, None, Some(1)).await.unwrap();
+        sqlx::query("UPDATE invite SET expires_at = unixepoch() - 1")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(redeem(&pool, &expired).await.unwrap().is_none());
+
+        let revoked = create(&pool, None, Role::User, None, None, None).await.unwrap();
+        let id = list(&pool, None).await.unwrap()[0].id;
+        assert!(revoke(&pool, id, None).await.unwrap());
+        assert!(redeem(&pool, &revoked).await.unwrap().is_none());
+
+        assert!(redeem(&pool, "made up").await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,280 @@
+use axum::async_trait;
+use ldap3::{dn_escape, ldap_escape, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::auth::{find_or_provision, Authenticator, BackendError, Credentials, Role, User};
+use crate::store::{StoreError, UserStore};
+
+/// The result code servers send back for a bad DN/password combo.
+const INVALID_CREDENTIALS: u32 = 49;
+
+fn default_group_attribute() -> String {
+    "memberOf".to_owned()
+}
+
+fn default_timeout_seconds() -> u64 {
+    5
+}
+
+/// How to find the entry to bind as when the DN can't just be built from the username.
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct LdapSearchConfig {
+    pub base_dn: String,
+
+    /// `{username}` gets replaced with the (escaped) username, e.g. `(uid={username})`.
+    pub filter: String,
+
+    /// Who to bind as while searching. Anonymous if left out.
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+}
+
+impl std::fmt::Debug for LdapSearchConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LdapSearchConfig")
+            .field("base_dn", &self.base_dn)
+            .field("filter", &self.filter)
+            .field("bind_dn", &self.bind_dn)
+            .field("bind_password", &self.bind_password.as_ref().map(|_| "Wouldn't you like to know"))
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct LdapConfig {
+    /// e.g. `ldap://localhost:389` or `ldaps://ldap.example.org`
+    pub url: String,
+
+    /// Bind straight as the user, e.g. `uid={username},ou=people,dc=example,dc=org`.
+    pub bind_dn_template: Option<String>,
+
+    /// Or search for them first. Only used if there's no `bind-dn-template`.
+    pub search: Option<LdapSearchConfig>,
+
+    /// Upgrade plain `ldap://` connections with StartTLS.
+    #[serde(default)]
+    pub starttls: bool,
+
+    /// Don't check the server's certificate. Only for testing!!
+    #[serde(default)]
+    pub no_tls_verify: bool,
+
+    /// The attribute on the user's entry listing the groups they're in.
+    #[serde(default = "default_group_attribute")]
+    pub group_attribute: String,
+
+    /// Group DN -> role. If someone's in several, they get the most powerful one.
+    #[serde(default)]
+    pub group_roles: HashMap<String, Role>,
+
+    /// How long to wait for the server to answer, when connecting and for each thing we ask it,
+    /// so one that's hung doesn't hang every login with it.
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+}
+
+impl LdapConfig {
+    /// Catches config that would parse fine but can't possibly work.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.bind_dn_template.is_none() && self.search.is_none() {
+            return Err("[ldap] needs either bind-dn-template or a [ldap.search] section".to_owned());
+        }
+        if self.timeout_seconds == 0 {
+            return Err("ldap.timeout-seconds needs to be at least 1".to_owned());
+        }
+        if let Some(search) = &self.search {
+            // Otherwise we'd quietly search anonymously
+            if search.bind_dn.is_some() != search.bind_password.is_some() {
+                return Err("[ldap.search] needs both bind-dn and bind-password, or neither".to_owned());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks credentials by binding to a directory as the user. Anyone who gets in is given a local
+/// `User` row (without a password) so sessions and everything else work as usual.
+#[derive(Debug, Clone)]
+pub struct LdapAuthenticator {
+    pub config: LdapConfig,
+    pub store: Arc<dyn UserStore>,
+}
+
+impl LdapAuthenticator {
+    pub fn new(config: LdapConfig, store: Arc<dyn UserStore>) -> Self {
+        Self { config, store }
+    }
+
+    /// Returns the groups the user is in, or `None` if the directory didn't like their password.
+    async fn bind_as_user(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<Vec<String>>, BackendError> {
+        let timeout = Duration::from_secs(self.config.timeout_seconds);
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(timeout)
+            .set_starttls(self.config.starttls)
+            .set_no_tls_verify(self.config.no_tls_verify);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.config.url).await?;
+        ldap3::drive!(conn);
+
+        let user_dn = if let Some(template) = &self.config.bind_dn_template {
+            template.replace("{username}", &dn_escape(username))
+        } else if let Some(search) = &self.config.search {
+            if let (Some(dn), Some(pw)) = (&search.bind_dn, &search.bind_password) {
+                ldap.with_timeout(timeout).simple_bind(dn, pw).await?.success()?;
+            }
+
+            let filter = search.filter.replace("{username}", &ldap_escape(username));
+            let (entries, _) = ldap
+                .with_timeout(timeout)
+                .search(&search.base_dn, Scope::Subtree, &filter, vec!["1.1"])
+                .await?
+                .success()?;
+
+            // Zero means they don't exist, more than one means the filter is too loose. Either
+            // way we can't tell who they are.
+            let mut entries = entries.into_iter();
+            let (Some(entry), None) = (entries.next(), entries.next()) else {
+                let _ = ldap.unbind().await;
+                return Ok(None);
+            };
+            SearchEntry::construct(entry).dn
+        } else {
+            return Err(BackendError::Config("[ldap] needs either bind-dn-template or [ldap.search]"));
+        };
+
+        let res = ldap.with_timeout(timeout).simple_bind(&user_dn, password).await?;
+        if res.rc == INVALID_CREDENTIALS {
+            let _ = ldap.unbind().await;
+            return Ok(None);
+        }
+        res.success()?;
+
+        // Bound as them, so read our own entry for the groups
+        let (entries, _) = ldap
+            .with_timeout(timeout)
+            .search(&user_dn, Scope::Base, "(objectClass=*)", vec![self.config.group_attribute.as_str()])
+            .await?
+            .success()?;
+
+        let groups = entries
+            .into_iter()
+            .map(SearchEntry::construct)
+            .flat_map(|e| e.attrs.into_values().flatten())
+            .collect();
+
+        let _ = ldap.unbind().await;
+        Ok(Some(groups))
+    }
+
+    /// The best role their groups give them, if any of them are mapped.
+    fn role_for(&self, groups: &[String]) -> Option<Role> {
+        groups
+            .iter()
+            .filter_map(|group| {
+                // DNs aren't case sensitive
+                self.config
+                    .group_roles
+                    .iter()
+                    .find(|(dn, _)| dn.eq_ignore_ascii_case(group))
+                    .map(|(_, role)| *role)
+            })
+            .max()
+    }
+
+    /// Makes sure there's a local user matching the directory one. Local accounts that happen to
+    /// share the name are left alone, roles included.
+    async fn provision(&self, username: &str, groups: &[String]) -> Result<Option<User>, StoreError> {
+        let Some(mut user) = find_or_provision(&*self.store, username, self.name()).await? else {
+            return Ok(None);
+        };
+
+        // Only touch roles if there's a mapping, otherwise roles are managed locally
+        if !self.config.group_roles.is_empty() {
+            let role = self.role_for(groups).unwrap_or_default();
+            if role != user.role {
+                self.store.set_role(user.id, role).await?;
+                user.role = role;
+            }
+        }
+
+        Ok(Some(user))
+    }
+}
+
+#[async_trait]
+impl Authenticator for LdapAuthenticator {
+    fn name(&self) -> &'static str {
+        "ldap"
+    }
+
+    async fn authenticate(&self, creds: &Credentials) -> Result<Option<User>, BackendError> {
+        // An empty password is an "unauthenticated bind", which most servers happily accept.
+        if creds.password.is_empty() {
+            return Ok(None);
+        }
+
+        let username = creds.username.trim().to_lowercase();
+        let Some(groups) = self.bind_as_user(&username, &creds.password).await? else {
+            return Ok(None);
+        };
+
+        Ok(self.provision(&username, &groups).await?)
+    }
+
+    async fn verify(&self, user: &User, password: &str) -> Result<bool, BackendError> {
+        if password.is_empty() {
+            return Ok(false);
+        }
+        Ok(self.bind_as_user(&user.username, password).await?.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(search: Option<LdapSearchConfig>) -> LdapConfig {
+        LdapConfig {
+            url: "ldap://localhost:389".to_owned(),
+            bind_dn_template: None,
+            search,
+            starttls: false,
+            no_tls_verify: false,
+            group_attribute: default_group_attribute(),
+            group_roles: HashMap::new(),
+            timeout_seconds: default_timeout_seconds(),
+        }
+    }
+
+    fn search(bind_dn: Option<&str>, bind_password: Option<&str>) -> LdapSearchConfig {
+        LdapSearchConfig {
+            base_dn: "dc=example,dc=org".to_owned(),
+            filter: "(uid={username})".to_owned(),
+            bind_dn: bind_dn.map(str::to_owned),
+            bind_password: bind_password.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn needs_a_way_to_find_users() {
+        assert!(config(None).validate().is_err());
+        assert!(config(Some(search(None, None))).validate().is_ok());
+    }
+
+    #[test]
+    fn needs_both_halves_of_the_service_account() {
+        let dn = Some("cn=admin,dc=example,dc=org");
+        assert!(config(Some(search(dn, Some("admin")))).validate().is_ok());
+        assert!(config(Some(search(dn, None))).validate().is_err());
+        assert!(config(Some(search(None, Some("admin")))).validate().is_err());
+    }
+}
@@ -1,11 +1,71 @@
+pub mod account;
+pub mod admin;
 pub mod app;
+pub mod auth_error;
 pub mod error_template;
+pub mod org;
+pub mod org_page;
+pub mod permission;
+pub mod profile;
 #[cfg(feature = "ssr")]
 pub mod fileserv;
 #[cfg(feature = "ssr")]
 pub mod state;
 #[cfg(feature = "ssr")]
 pub mod auth;
+#[cfg(feature = "ssr")]
+pub mod store;
+#[cfg(feature = "ssr")]
+pub mod ldap;
+#[cfg(feature = "ssr")]
+pub mod htpasswd;
+pub mod import;
+#[cfg(feature = "ssr")]
+pub mod cli;
+#[cfg(feature = "ssr")]
+pub mod mail;
+#[cfg(feature = "ssr")]
+pub mod magic_link;
+#[cfg(feature = "ssr")]
+pub mod rate_limit;
+#[cfg(feature = "ssr")]
+pub mod audit;
+#[cfg(feature = "ssr")]
+pub mod recovery;
+#[cfg(feature = "ssr")]
+pub mod message;
+#[cfg(feature = "ssr")]
+pub mod otp;
+#[cfg(feature = "ssr")]
+pub mod invite;
+#[cfg(feature = "ssr")]
+pub mod group;
+#[cfg(feature = "ssr")]
+pub mod impersonate;
+#[cfg(feature = "ssr")]
+pub mod webhook;
+#[cfg(feature = "ssr")]
+pub mod csrf;
+#[cfg(feature = "ssr")]
+pub mod security_headers;
+#[cfg(feature = "ssr")]
+pub mod tls;
+#[cfg(feature = "ssr")]
+pub mod client_info;
+#[cfg(feature = "ssr")]
+pub mod logging;
+#[cfg(feature = "ssr")]
+pub mod metrics;
+#[cfg(feature = "ssr")]
+pub mod health;
+#[cfg(feature = "ssr")]
+pub mod shutdown;
+#[cfg(feature = "ssr")]
+pub mod config;
+#[cfg(feature = "ssr")]
+pub mod reload;
+#[cfg(feature = "ssr")]
+pub mod avatar;
 
 #[cfg(feature = "hydrate")]
 #[wasm_bindgen::prelude::wasm_bindgen]
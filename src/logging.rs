@@ -0,0 +1,165 @@
+use axum::body::Body;
+use axum::extract::Request;
+use axum::http::{HeaderValue, Uri};
+use axum::middleware::Next;
+use axum::response::Response;
+use rand::Rng;
+use serde::Deserialize;
+use std::sync::OnceLock;
+use std::time::Instant;
+use tracing::Instrument;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
+
+use crate::client_info::ClientInfo;
+
+/// Query parameters whose values never make it into the logs
+const SECRET_PARAMS: &[&str] = &["token", "invite", "code", "password"];
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// Multi-line and colourful, for reading in a terminal
+    #[default]
+    Pretty,
+    /// One line per event
+    Compact,
+    /// One JSON object per line, for feeding to something else
+    Json,
+}
+
+fn default_level() -> String {
+    "info".to_owned()
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct LoggingConfig {
+    /// Either a level, or per-module directives like `info,sqlx=warn`. `RUST_LOG` wins if it's
+    /// set.
+    #[serde(default = "default_level")]
+    pub level: String,
+
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: default_level(),
+            format: LogFormat::default(),
+        }
+    }
+}
+
+/// Lets [`set_level`] change the level after [`init`]
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Sets up the global subscriber. Logs go to stderr, so command line output stays clean.
+pub fn init(config: &LoggingConfig) -> Result<(), String> {
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => parse_level(&config.level)?,
+    };
+    let (filter, handle) = reload::Layer::new(filter);
+
+    let fmt = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    let fmt = match config.format {
+        LogFormat::Pretty => fmt.pretty().boxed(),
+        LogFormat::Compact => fmt.compact().boxed(),
+        LogFormat::Json => fmt.json().boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt)
+        .try_init()
+        .map_err(|err| format!("Couldn't set up logging: {err}"))?;
+
+    let _ = FILTER.set(handle);
+    Ok(())
+}
+
+fn parse_level(level: &str) -> Result<EnvFilter, String> {
+    EnvFilter::try_new(level).map_err(|err| format!("logging.level {level:?} isn't valid: {err}"))
+}
+
+/// Whether [`set_level`] would take it.
+pub fn check_level(level: &str) -> Result<(), String> {
+    parse_level(level).map(|_| ())
+}
+
+/// Swaps in a new level or set of directives. `RUST_LOG` still wins if it's set, but the level
+/// gets checked either way.
+pub fn set_level(level: &str) -> Result<(), String> {
+    let filter = parse_level(level)?;
+
+    let Some(handle) = FILTER.get() else {
+        return Ok(());
+    };
+    if std::env::var_os(EnvFilter::DEFAULT_ENV).is_some() {
+        tracing::info!("Not changing the log level, {} is set", EnvFilter::DEFAULT_ENV);
+        return Ok(());
+    }
+
+    handle
+        .reload(filter)
+        .map_err(|err| format!("Couldn't change the log level: {err}"))
+}
+
+/// The path and query, with anything that could let someone in swapped out.
+pub fn redact(uri: &Uri) -> String {
+    let Some(query) = uri.query() else {
+        return uri.path().to_owned();
+    };
+
+    let query: Vec<String> = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((key, _)) if SECRET_PARAMS.contains(&key) => format!("{key}=[redacted]"),
+            _ => pair.to_owned(),
+        })
+        .collect();
+    format!("{}?{}", uri.path(), query.join("&"))
+}
+
+/// Tells one request's logs apart from another's. Also sent back as `x-request-id`.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+/// Wraps each request in a span with an id, and logs how it went. Headers are left out, so
+/// cookies and session ids never get logged.
+pub async fn layer(mut request: Request<Body>, next: Next) -> Response {
+    let id = format!("{:016x}", rand::thread_rng().gen::<u64>());
+    let client = request
+        .extensions()
+        .get::<ClientInfo>()
+        .map(|client| client.ip.to_string())
+        .unwrap_or_default();
+    let span = tracing::info_span!(
+        "request",
+        id,
+        method = %request.method(),
+        uri = redact(request.uri()),
+        client,
+    );
+    request.extensions_mut().insert(RequestId(id.clone()));
+
+    let started = Instant::now();
+    let mut response = next.run(request).instrument(span.clone()).await;
+
+    span.in_scope(|| {
+        tracing::info!(
+            status = response.status().as_u16(),
+            latency_ms = started.elapsed().as_millis() as u64,
+            "finished"
+        )
+    });
+    if let Ok(id) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert("x-request-id", id);
+    }
+
+    response
+}
@@ -0,0 +1,351 @@
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::response::Redirect;
+use axum::Form;
+use axum_login::tower_sessions::Session;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::Sha256;
+use sqlx::SqlitePool;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::auth::{AuthSession, Blocked};
+use crate::csrf;
+use crate::metrics;
+use crate::otp::begin_second_factor;
+use crate::rate_limit::RateLimit;
+use crate::state::{AppState, Config};
+use crate::webhook;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_LEN: usize = 16;
+
+fn default_ttl_minutes() -> i64 {
+    15
+}
+
+fn default_max_per_address() -> usize {
+    3
+}
+
+fn default_max_per_ip() -> usize {
+    10
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct MagicLinkConfig {
+    /// Any long random string. Changing it breaks every link that's been sent out.
+    pub signing_key: String,
+
+    /// How long a link works for
+    #[serde(default = "default_ttl_minutes")]
+    pub ttl_minutes: i64,
+
+    /// How many links can be asked for in an hour for one address, and from one IP
+    #[serde(default = "default_max_per_address")]
+    pub max_per_address: usize,
+    #[serde(default = "default_max_per_ip")]
+    pub max_per_ip: usize,
+}
+
+impl std::fmt::Debug for MagicLinkConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MagicLinkConfig")
+            .field("signing_key", &"Wouldn't you like to know")
+            .field("ttl_minutes", &self.ttl_minutes)
+            .field("max_per_address", &self.max_per_address)
+            .field("max_per_ip", &self.max_per_ip)
+            .finish()
+    }
+}
+
+/// Hands out and redeems "email me a sign-in link" tokens.
+///
+/// A token is a random nonce and the user's id, signed so we can tell it's one of ours. Each one
+/// also gets a row in `magic_link`, which is how they expire and only work once.
+#[derive(Debug, Clone)]
+pub struct MagicLinks {
+    pub config: MagicLinkConfig,
+    pub pool: SqlitePool,
+    by_address: Arc<RateLimit>,
+    by_ip: Arc<RateLimit>,
+}
+
+impl MagicLinks {
+    pub fn new(config: MagicLinkConfig, pool: SqlitePool) -> Result<Self, String> {
+        if config.signing_key.len() < 32 {
+            return Err("magic-link.signing-key should be at least 32 characters long".to_owned());
+        }
+
+        Ok(Self {
+            config,
+            pool,
+            by_address: Default::default(),
+            by_ip: Default::default(),
+        })
+    }
+
+    /// Whether someone at `ip` can ask for another link to `email` yet, going by the limits in
+    /// `config` (the live one, since they can be reloaded). Counted whether or not the address
+    /// has an account, so it doesn't give anything away. `Err` is how many seconds they've got to
+    /// wait.
+    pub fn allow_request(&self, config: &Config, email: &str, ip: IpAddr) -> Result<(), i64> {
+        let limits = config.magic_link.as_ref().unwrap_or(&self.config);
+        self.by_ip.hit(&ip.to_string(), limits.max_per_ip)?;
+        self.by_address.hit(email, limits.max_per_address)
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(self.config.signing_key.as_bytes())
+            .expect("HMAC should take a key of any size")
+    }
+
+    /// Makes a token that logs `user_id` in.
+    pub async fn issue(&self, user_id: i64) -> Result<String, sqlx::Error> {
+        let mut payload = [0; NONCE_LEN + 8];
+        rand::thread_rng().fill_bytes(&mut payload[..NONCE_LEN]);
+        payload[NONCE_LEN..].copy_from_slice(&user_id.to_be_bytes());
+
+        // Might as well clean up while we're here
+        sqlx::query("DELETE FROM magic_link WHERE expires_at < unixepoch()")
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query(
+            "INSERT INTO magic_link (nonce, user_id, expires_at) VALUES (?, ?, unixepoch() + ?)",
+        )
+        .bind(URL_SAFE_NO_PAD.encode(&payload[..NONCE_LEN]))
+        .bind(user_id)
+        .bind(self.config.ttl_minutes * 60)
+        .execute(&self.pool)
+        .await?;
+
+        let signature = self.mac().chain_update(payload).finalize().into_bytes();
+        Ok(format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(payload),
+            URL_SAFE_NO_PAD.encode(signature)
+        ))
+    }
+
+    /// Uses up a token, giving back who it was for. `None` if it's forged, expired or been used
+    /// already.
+    pub async fn redeem(&self, token: &str) -> Result<Option<i64>, sqlx::Error> {
+        let Some((payload, signature)) = token.split_once('.') else {
+            return Ok(None);
+        };
+        let (Ok(payload), Ok(signature)) = (
+            URL_SAFE_NO_PAD.decode(payload),
+            URL_SAFE_NO_PAD.decode(signature),
+        ) else {
+            return Ok(None);
+        };
+
+        if payload.len() != NONCE_LEN + 8
+            || self
+                .mac()
+                .chain_update(&payload)
+                .verify_slice(&signature)
+                .is_err()
+        {
+            return Ok(None);
+        }
+
+        let user_id = i64::from_be_bytes(
+            payload[NONCE_LEN..]
+                .try_into()
+                .expect("payload length was checked"),
+        );
+
+        // Doing the check and the marking in one go means it can't be redeemed twice at once
+        let res = sqlx::query(
+            "UPDATE magic_link SET used_at = unixepoch()
+             WHERE nonce = ? AND user_id = ? AND used_at IS NULL AND expires_at > unixepoch()",
+        )
+        .bind(URL_SAFE_NO_PAD.encode(&payload[..NONCE_LEN]))
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok((res.rows_affected() == 1).then_some(user_id))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RedeemParams {
+    token: String,
+}
+
+/// What the confirm page at `/login/magic` (the link in the email) posts. Logs them in and sends
+/// them home, or back to the log in page if the link's no good.
+///
+/// Opening the link doesn't use it up, since mail scanners and link previews open them too.
+pub async fn redeem_handler(
+    State(state): State<AppState>,
+    mut auth_session: AuthSession,
+    session: Session,
+    headers: HeaderMap,
+    Form(params): Form<RedeemParams>,
+) -> Redirect {
+    const INVALID: &str = "/login?magic=invalid";
+
+    // Otherwise another site could log people in as someone else
+    if let Err(reason) = csrf::check(&state.live.get(), &headers) {
+        tracing::warn!(reason, "Refused cross-site sign in link");
+        return Redirect::to(INVALID);
+    }
+
+    let Some(links) = &state.magic_links else {
+        return Redirect::to(INVALID);
+    };
+
+    let user_id = match links.redeem(&params.token).await {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => return Redirect::to(INVALID),
+        Err(err) => {
+            tracing::error!(%err, "Couldn't redeem sign in link");
+            return Redirect::to(INVALID);
+        }
+    };
+
+    let user = match state.auth.store.find_by_id(user_id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Redirect::to(INVALID),
+        Err(err) => {
+            tracing::error!(%err, user_id, "Couldn't find who a sign in link is for");
+            return Redirect::to(INVALID);
+        }
+    };
+
+    // The link was fine, so it's worth telling them why it didn't get them in
+    if let Err(blocked) = user.check_status() {
+        tracing::info!(user.username, %blocked, "Sign in link used by someone who can't log in");
+        return Redirect::to(match blocked {
+            Blocked::Disabled { .. } => "/login?magic=disabled",
+            Blocked::Locked { .. } => "/login?magic=locked",
+            Blocked::PendingVerification => "/login?magic=pending-verification",
+        });
+    }
+
+    match begin_second_factor(state.otp.as_ref(), &session, &user).await {
+        Ok(true) => return Redirect::to("/login/verify"),
+        Ok(false) => {}
+        Err(err) => {
+            tracing::error!(%err, user.username, "Couldn't start second factor");
+            return Redirect::to(INVALID);
+        }
+    }
+
+    if let Err(err) = auth_session.login(&user).await {
+        tracing::error!(%err, user.username, "Couldn't log in with sign in link");
+        return Redirect::to(INVALID);
+    }
+    metrics::login(metrics::LOGIN_SUCCESS);
+    webhook::logged_in(&state, &user, "magic-link").await;
+
+    Redirect::to("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::NO_PASSWORD;
+    use crate::state::{test_config, test_pool};
+    use crate::store::{SqlxUserStore, UserStore};
+    use std::net::Ipv4Addr;
+
+    fn config(signing_key: &str) -> MagicLinkConfig {
+        MagicLinkConfig {
+            signing_key: signing_key.to_owned(),
+            ttl_minutes: default_ttl_minutes(),
+            max_per_address: default_max_per_address(),
+            max_per_ip: default_max_per_ip(),
+        }
+    }
+
+    async fn links() -> (MagicLinks, i64) {
+        let pool = test_pool().await;
+        let alice = SqlxUserStore::new(pool.clone())
+            .create("alice", NO_PASSWORD)
+            .await
+            .unwrap()
+            .id;
+        let links = MagicLinks::new(config("not a very secret key, but long enough"), pool);
+        (links.unwrap(), alice)
+    }
+
+    #[tokio::test]
+    async fn links_work_once() {
+        let (links, alice) = links().await;
+        let token = links.issue(alice).await.unwrap();
+
+        assert_eq!(links.redeem(&token).await.unwrap(), Some(alice));
+        assert_eq!(links.redeem(&token).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn expired_links_dont() {
+        let (links, alice) = links().await;
+        let token = links.issue(alice).await.unwrap();
+        sqlx::query("UPDATE magic_link SET expires_at = unixepoch() - 1")
+            .execute(&links.pool)
+            .await
+            .unwrap();
+
+        assert_eq!(links.redeem(&token).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn forged_links_dont() {
+        let (links, alice) = links().await;
+        let token = links.issue(alice).await.unwrap();
+        let (payload, signature) = token.split_once('.').unwrap();
+
+        // Someone else's id in it
+        let mut forged = URL_SAFE_NO_PAD.decode(payload).unwrap();
+        *forged.last_mut().unwrap() ^= 1;
+        let forged = format!("{}.{signature}", URL_SAFE_NO_PAD.encode(forged));
+        assert_eq!(links.redeem(&forged).await.unwrap(), None);
+
+        // Signed with some other key
+        let other = config("a different key that's also long enough");
+        let other = MagicLinks::new(other, links.pool.clone()).unwrap();
+        assert_eq!(other.redeem(&token).await.unwrap(), None);
+
+        for junk in ["", ".", "nope", &format!("{payload}."), &format!("{payload}.!!")] {
+            assert_eq!(links.redeem(junk).await.unwrap(), None);
+        }
+        // None of that used it up
+        assert_eq!(links.redeem(&token).await.unwrap(), Some(alice));
+    }
+
+    #[tokio::test]
+    async fn keys_need_to_be_long() {
+        assert!(MagicLinks::new(config("hunter2"), test_pool().await).is_err());
+    }
+
+    #[tokio::test]
+    async fn asking_for_too_many() {
+        let (links, _) = links().await;
+        let live = test_config(
+            r#"
+            [magic-link]
+            signing-key = "not a very secret key, but long enough"
+            max-per-address = 1
+            max-per-ip = 2
+            "#,
+        );
+        let home = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let away = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+        links.allow_request(&live, "a@example.org", home).unwrap();
+        assert!(links.allow_request(&live, "a@example.org", away).is_err());
+        links.allow_request(&live, "b@example.org", home).unwrap();
+        assert!(links.allow_request(&live, "c@example.org", home).is_err());
+    }
+}
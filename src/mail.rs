@@ -0,0 +1,159 @@
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials as SmtpCredentials;
+use lettre::{
+    AsyncFileTransport, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use serde::Deserialize;
+use std::path::PathBuf;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum MailError {
+    #[error("Bad email address: {0}")]
+    Address(#[from] lettre::address::AddressError),
+
+    #[error("Couldn't build email: {0}")]
+    Message(#[from] lettre::error::Error),
+
+    #[error("SMTP error: {0}")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+
+    #[error("Couldn't write email: {0}")]
+    File(#[from] lettre::transport::file::Error),
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum SmtpTls {
+    /// Straight to TLS, usually port 465
+    Tls,
+    /// Upgrade a plain connection, usually port 587
+    #[default]
+    Starttls,
+    /// Only for a server on localhost!!
+    None,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(tag = "transport", rename_all = "kebab-case")]
+pub enum TransportConfig {
+    #[serde(rename_all = "kebab-case")]
+    Smtp {
+        host: String,
+        port: Option<u16>,
+        username: Option<String>,
+        password: Option<String>,
+        #[serde(default)]
+        tls: SmtpTls,
+    },
+    /// Writes every email to a `.eml` file in `dir` instead of sending it. For local testing.
+    File { dir: PathBuf },
+}
+
+impl std::fmt::Debug for TransportConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransportConfig::Smtp { host, port, username, password, tls } => f
+                .debug_struct("Smtp")
+                .field("host", host)
+                .field("port", port)
+                .field("username", username)
+                .field("password", &password.as_ref().map(|_| "Wouldn't you like to know"))
+                .field("tls", tls)
+                .finish(),
+            TransportConfig::File { dir } => f.debug_struct("File").field("dir", dir).finish(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct MailConfig {
+    /// e.g. `Rust Auth <noreply@example.org>`
+    pub from: String,
+
+    #[serde(flatten)]
+    pub transport: TransportConfig,
+}
+
+#[derive(Clone)]
+enum Transport {
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    File(AsyncFileTransport<Tokio1Executor>),
+}
+
+/// Sends plain text emails with whichever transport is configured.
+#[derive(Clone)]
+pub struct Mailer {
+    from: Mailbox,
+    transport: Transport,
+}
+
+impl std::fmt::Debug for Mailer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The SMTP transport has the password in it
+        f.debug_struct("Mailer").field("from", &self.from).finish()
+    }
+}
+
+impl Mailer {
+    pub fn new(config: &MailConfig) -> Result<Self, String> {
+        let from = config
+            .from
+            .parse()
+            .map_err(|err| format!("mail.from isn't a valid address: {err}"))?;
+
+        let transport = match &config.transport {
+            TransportConfig::Smtp {
+                host,
+                port,
+                username,
+                password,
+                tls,
+            } => {
+                let builder = match tls {
+                    SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host),
+                    SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host),
+                    SmtpTls::None => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)),
+                };
+                let mut builder =
+                    builder.map_err(|err| format!("Couldn't set up SMTP for {host}: {err}"))?;
+
+                if let Some(port) = port {
+                    builder = builder.port(*port);
+                }
+                if let (Some(username), Some(password)) = (username, password) {
+                    builder = builder.credentials(SmtpCredentials::new(username.clone(), password.clone()));
+                }
+
+                Transport::Smtp(builder.build())
+            }
+            TransportConfig::File { dir } => {
+                std::fs::create_dir_all(dir)
+                    .map_err(|err| format!("Couldn't create mail directory {dir:?}: {err}"))?;
+                Transport::File(AsyncFileTransport::new(dir))
+            }
+        };
+
+        Ok(Self { from, transport })
+    }
+
+    pub async fn send(&self, to: &str, subject: &str, body: String) -> Result<(), MailError> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to.parse()?)
+            .subject(subject)
+            .body(body)?;
+
+        match &self.transport {
+            Transport::Smtp(smtp) => {
+                smtp.send(message).await?;
+            }
+            Transport::File(file) => {
+                file.send(message).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
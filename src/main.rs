@@ -1,15 +1,33 @@
 use axum::body::Body as AxumBody;
 use axum::extract::{Path as AxumPath, State};
-use axum::http::Request;
+use axum::http::{Request, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::Router;
+use axum_login::tower_sessions::Session;
+use leptos::nonce::Nonce;
 use leptos::*;
 use leptos_axum::{generate_route_list, handle_server_fns_with_context, LeptosRoutes};
 use rust_auth::app::*;
 use rust_auth::auth::AuthSession;
+use rust_auth::auth_error;
+use rust_auth::avatar;
+use rust_auth::cli;
+use rust_auth::client_info::{self, ClientInfo};
+use rust_auth::config;
+use rust_auth::csrf;
+use rust_auth::magic_link;
+use rust_auth::metrics;
+use rust_auth::reload;
 use rust_auth::fileserv::file_and_error_handler;
+use rust_auth::health;
+use rust_auth::impersonate;
+use rust_auth::security_headers;
+use rust_auth::shutdown::Shutdown;
 use rust_auth::state::*;
-use std::path::Path;
+use rust_auth::tls;
+use rust_auth::webhook;
+use std::net::SocketAddr;
+use std::time::Duration;
 
 #[cfg(feature = "ssr")]
 #[tokio::main]
@@ -18,17 +36,29 @@ async fn main() {
     // let leptos_options = conf.leptos_options;
     // let addr = leptos_options.site_addr;
 
-    use axum::routing::get;
+    use axum::middleware::from_fn_with_state;
+    use axum::routing::{get, post};
     use axum_login::{
-        tower_sessions::{SessionManagerLayer, SessionStore},
+        tower_sessions::SessionManagerLayer,
         AuthManagerLayerBuilder,
     };
     use leptos::server_fn::axum::server_fn_paths;
     use std::process::exit;
     use tower_sessions_sqlx_store::SqliteStore;
 
+    // Anything on the command line (other than where the config is) is a one off command
+    // instead of running the server
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let config_path = match config::take_path_arg(&mut args) {
+        Ok(path) => path,
+        Err(err) => {
+            eprintln!("{err}");
+            exit(1);
+        }
+    };
+
     // Try and crate a state we can live with
-    let state = match AppState::new(Path::new("config.toml")) {
+    let state = match config::load(config_path.as_deref()).and_then(AppState::new) {
         Ok(v) => v,
         Err(err) => {
             eprintln!("There was a problem during start up!");
@@ -37,11 +67,26 @@ async fn main() {
         }
     };
 
+    if let Err(err) = rust_auth::logging::init(&state.config.logging) {
+        eprintln!("{err}");
+        exit(1);
+    }
+
     sqlx::migrate!()
         .run(&state.pool)
         .await
         .expect("Migrations to run correctly");
 
+    if !args.is_empty() {
+        let res = cli::run(&state, &args).await;
+        state.pool.close().await;
+        if let Err(err) = res {
+            eprintln!("{}", err);
+            exit(1);
+        }
+        return;
+    }
+
     // The session needs a place to store the user cookies and such
     // Pool is behind an Arc so ok to clone
     // let session_store = SqliteStore::new(state.pool.clone());
@@ -53,53 +98,169 @@ async fn main() {
 
     // Requests get passed through this layer, pressumably to ensure they've got the cookies and
     // stuff.
-    let session_layer = SessionManagerLayer::new(session_store);
+    // Browsers only send Secure cookies over https, so only ask for it when that's what we've got:
+    // either we're serving it, or a proxy in front of us is (which is what an https public-url
+    // means, since we're not)
+    let secure = state.config.tls.is_some() || state.config.public_url().starts_with("https://");
+    let session_layer = SessionManagerLayer::new(session_store).with_secure(secure);
     let auth_layer = AuthManagerLayerBuilder::new(state.auth.clone(), session_layer).build();
 
     let addr = state.config.leptos.site_addr;
+    let tls = state.config.tls.clone();
+    let public_url = state.config.public_url();
     let routes = generate_route_list(App);
 
-    println!("{:?}", server_fn_paths().collect::<Vec<_>>());
+    // Not the whole config, there's secrets in there
+    tracing::info!(
+        backends = ?state.config.auth.backends,
+        registration = ?state.config.auth.registration,
+        %public_url,
+        "Starting up"
+    );
+
+    tracing::debug!(paths = ?server_fn_paths().collect::<Vec<_>>(), "Server functions");
 
     // build our application with a route
-    let app = Router::<AppState>::new()
+    let mut app = Router::<AppState>::new()
         // Pass server functions through this handler so we can have context
         .route(
             "/api/*function",
             get(server_fn_handler).post(server_fn_handler),
         )
+        .route("/login/magic", post(magic_link::redeem_handler))
+        .route(
+            "/account/avatar",
+            post(avatar::upload_handler).layer(state.config.avatars.body_limit()),
+        );
+
+    // Everything shuts down together, by the same deadline
+    let shutdown = Shutdown::on_signal(Duration::from_secs(
+        state.config.server.drain_timeout_seconds,
+    ));
+
+    // Either right here next to everything else, or on their own listener
+    if let Some(config) = state.config.metrics.clone() {
+        match config.on_app_listener {
+            true => app = app.route(&config.path, get(metrics::handler)),
+            false => {
+                let (metrics_state, shutdown) = (state.clone(), shutdown.clone());
+                state.tasks.spawn(async move {
+                    let res = metrics::serve(config.listen, config.path, metrics_state, shutdown);
+                    if let Err(err) = res.await {
+                        tracing::error!(%err, "Metrics listener stopped");
+                    }
+                });
+            }
+        }
+    }
+
+    // Kept for once the server's stopped, the router takes the other one
+    let shutdown_state = state.clone();
+
+    let app = app
         .leptos_routes_with_handler(routes, get(leptos_routes_handler))
         // .leptos_routes(&state, routes, App)
         .fallback(file_and_error_handler)
+        .layer(from_fn_with_state(state.clone(), impersonate::layer))
         .layer(auth_layer)
-        .with_state(dbg!(state));
+        .layer(axum::middleware::from_fn(metrics::layer))
+        .layer(axum::middleware::from_fn(rust_auth::logging::layer))
+        .layer(from_fn_with_state(state.clone(), client_info::layer))
+        .layer(from_fn_with_state(state.clone(), security_headers::layer))
+        // After the layers so probes don't fill the logs or touch sessions
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .with_state(state);
 
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    logging::log!("listening on http://{}", &addr);
-    axum::serve(listener, app.into_make_service())
-        .await
-        .unwrap();
+    // Picks up changes to the bits of the config that don't need a restart
+    reload::watch(shutdown_state.clone(), config_path);
+    webhook::spawn_worker(shutdown_state.clone(), shutdown.stop.clone());
+
+    let res = match tls {
+        None => {
+            let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+            tracing::info!("listening on http://{}", &addr);
+            let server = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(shutdown.stop.clone().cancelled_owned());
+            shutdown.drain(server).await
+        }
+        Some(tls) => {
+            let rustls = match tls.load().await {
+                Ok(rustls) => rustls,
+                Err(err) => {
+                    tracing::error!(%err, "Couldn't load the TLS certificate or key");
+                    exit(1);
+                }
+            };
+            tls.watch(rustls.clone());
+
+            if let Some(from) = tls.redirect_http_from {
+                let shutdown = shutdown.clone();
+                shutdown_state.tasks.spawn(async move {
+                    if let Err(err) = tls::redirect_http(from, public_url, addr.port(), shutdown).await {
+                        tracing::error!(%err, "HTTP redirect listener stopped");
+                    }
+                });
+            }
+
+            // Stops taking new connections, `drain` takes care of the timeout
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                let stop = shutdown.stop.clone();
+                async move {
+                    stop.cancelled().await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+
+            tracing::info!("listening on https://{}", &addr);
+            let server = axum_server::bind_rustls(addr, rustls)
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>());
+            shutdown.drain(server).await
+        }
+    };
+
+    shutdown.finish(&shutdown_state).await;
+    if let Err(err) = res {
+        tracing::error!(%err, "Server stopped");
+        exit(1);
+    }
 }
 
 /// Axum handler to use context in server functions
 async fn server_fn_handler(
     State(state): State<AppState>,
     auth_session: AuthSession,
+    session: Session,
+    client: ClientInfo,
     path: AxumPath<String>,
     request: Request<AxumBody>,
-) -> impl IntoResponse {
-    println!("Received server fn request on {path:?}");
+) -> Response {
+    tracing::debug!(function = path.as_str(), "Server function call");
+
+    // Everything in here acts on the session, so don't let other sites make people call them
+    if let Err(reason) = csrf::check(&state.live.get(), request.headers()) {
+        tracing::warn!(function = path.as_str(), reason, "Refused cross-site request");
+        return (StatusCode::FORBIDDEN, "Cross-site request refused").into_response();
+    }
+
     // Should i be just passing the whole thing? like maybe not,, but server funcs might want to
     // refer to the config on stuff yk? /shrug
     // Ok to clone so much ?? Put in Arc maybe ??
-    handle_server_fns_with_context(
+    let res = handle_server_fns_with_context(
         move || {
             provide_context(state.clone());
-            provide_context(auth_session.clone())
+            provide_context(auth_session.clone());
+            provide_context(session.clone());
+            provide_context(client.clone())
         },
         request,
     )
     .await
+    .into_response();
+    auth_error::with_status(res).await
 }
 
 /// The same context needs to be available for both the server function and the leptos route
@@ -107,14 +268,23 @@ async fn server_fn_handler(
 async fn leptos_routes_handler(
     State(state): State<AppState>,
     auth_session: AuthSession,
+    session: Session,
+    client: ClientInfo,
     request: Request<AxumBody>,
 ) -> Response {
+    // Put on the hydration scripts so the CSP lets them run
+    let nonce = request.extensions().get::<Nonce>().cloned();
     let handler = leptos_axum::render_route_with_context(
         state.config.leptos.clone(),
         generate_route_list(App),
         move || {
             provide_context(state.clone());
-            provide_context(auth_session.clone())
+            provide_context(auth_session.clone());
+            provide_context(session.clone());
+            provide_context(client.clone());
+            if let Some(nonce) = nonce.clone() {
+                provide_context(nonce);
+            }
         },
         App,
     );
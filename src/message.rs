@@ -0,0 +1,155 @@
+use axum::async_trait;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::path::PathBuf;
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+
+use crate::mail::{MailError, Mailer};
+
+#[derive(Error, Debug)]
+pub enum MessageError {
+    #[error(transparent)]
+    Mail(#[from] MailError),
+
+    #[error("Webhook error: {0}")]
+    Webhook(#[from] reqwest::Error),
+
+    #[error("Couldn't write message: {0}")]
+    File(#[from] std::io::Error),
+}
+
+/// Something that can get a short message to a person, be it email, text or whatever else.
+#[async_trait]
+pub trait MessageSender: Debug + Send + Sync {
+    /// `subject` is only a hint, not everything has somewhere to put it.
+    async fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), MessageError>;
+}
+
+#[async_trait]
+impl MessageSender for Mailer {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), MessageError> {
+        Ok(Mailer::send(self, to, subject, body.to_owned()).await?)
+    }
+}
+
+/// What gets POSTed to the webhook, as JSON.
+#[derive(Serialize)]
+struct WebhookMessage<'a> {
+    to: &'a str,
+    subject: &'a str,
+    body: &'a str,
+}
+
+/// POSTs messages somewhere else to deal with, e.g. an SMS gateway.
+#[derive(Clone)]
+pub struct WebhookSender {
+    pub url: String,
+    pub bearer_token: Option<String>,
+    client: reqwest::Client,
+}
+
+impl Debug for WebhookSender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebhookSender")
+            .field("url", &self.url)
+            .field(
+                "bearer_token",
+                &self
+                    .bearer_token
+                    .as_ref()
+                    .map(|_| "Wouldn't you like to know"),
+            )
+            .finish()
+    }
+}
+
+impl WebhookSender {
+    pub fn new(url: String, bearer_token: Option<String>) -> Self {
+        Self {
+            url,
+            bearer_token,
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl MessageSender for WebhookSender {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), MessageError> {
+        let mut req = self
+            .client
+            .post(&self.url)
+            .json(&WebhookMessage { to, subject, body });
+        if let Some(token) = &self.bearer_token {
+            req = req.bearer_auth(token);
+        }
+
+        req.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Appends every message to a file instead of sending it. For local testing.
+#[derive(Debug, Clone)]
+pub struct FileSender {
+    pub path: PathBuf,
+}
+
+#[async_trait]
+impl MessageSender for FileSender {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), MessageError> {
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+
+        file.write_all(format!("To: {to}\nSubject: {subject}\n\n{body}\n---\n").as_bytes())
+            .await?;
+        Ok(())
+    }
+}
+
+/// How to get messages to people on one channel.
+#[derive(Deserialize, Clone)]
+#[serde(tag = "sender", rename_all = "kebab-case")]
+pub enum SenderConfig {
+    /// Whatever's set up under `[mail]`
+    Mail,
+    #[serde(rename_all = "kebab-case")]
+    Webhook {
+        url: String,
+        bearer_token: Option<String>,
+    },
+    File {
+        path: PathBuf,
+    },
+}
+
+impl Debug for SenderConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SenderConfig::Mail => f.write_str("Mail"),
+            SenderConfig::Webhook { url, .. } => {
+                f.debug_struct("Webhook").field("url", url).finish()
+            }
+            SenderConfig::File { path } => f.debug_struct("File").field("path", path).finish(),
+        }
+    }
+}
+
+impl SenderConfig {
+    pub fn build(&self, mailer: Option<&Mailer>) -> Result<Box<dyn MessageSender>, String> {
+        Ok(match self {
+            SenderConfig::Mail => match mailer {
+                Some(mailer) => Box::new(mailer.clone()),
+                None => return Err("sender = \"mail\" needs [mail] to be configured".to_owned()),
+            },
+            SenderConfig::Webhook { url, bearer_token } => {
+                Box::new(WebhookSender::new(url.clone(), bearer_token.clone()))
+            }
+            SenderConfig::File { path } => Box::new(FileSender { path: path.clone() }),
+        })
+    }
+}
@@ -0,0 +1,289 @@
+use axum::body::Body;
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use prometheus::{
+    exponential_buckets, histogram_opts, opts, Encoder, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, Registry, TextEncoder,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::shutdown::Shutdown;
+use crate::state::AppState;
+
+// Everything that ends up in the `outcome` label of `rust_auth_logins_total`
+pub const LOGIN_SUCCESS: &str = "success";
+pub const LOGIN_INVALID_CREDENTIALS: &str = "invalid_credentials";
+pub const LOGIN_DISABLED: &str = "disabled";
+pub const LOGIN_LOCKED: &str = "locked";
+pub const LOGIN_PENDING_VERIFICATION: &str = "pending_verification";
+pub const LOGIN_BACKEND_ERROR: &str = "backend_error";
+pub const LOGIN_WRONG_CODE: &str = "wrong_code";
+
+fn default_path() -> String {
+    "/metrics".to_owned()
+}
+
+fn default_listen() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 9100))
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct MetricsConfig {
+    #[serde(default = "default_path")]
+    pub path: String,
+
+    /// Their own address, e.g. one only Prometheus can reach
+    #[serde(default = "default_listen")]
+    pub listen: SocketAddr,
+
+    /// Serve them next to the app instead of on `listen`. Needs a `token`, since anyone who can
+    /// reach the app could read them otherwise.
+    #[serde(default)]
+    pub on_app_listener: bool,
+
+    /// Scrapers have to send `Authorization: Bearer <token>` if this is set.
+    pub token: Option<String>,
+}
+
+impl std::fmt::Debug for MetricsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MetricsConfig")
+            .field("path", &self.path)
+            .field("listen", &self.listen)
+            .field("on_app_listener", &self.on_app_listener)
+            .field("token", &self.token.as_ref().map(|_| "Wouldn't you like to know"))
+            .finish()
+    }
+}
+
+impl MetricsConfig {
+    /// Catches config that would parse fine but can't possibly work.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.on_app_listener && self.token.is_none() {
+            return Err("[metrics] needs a token to be served on the app's listener".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Whether the request has the token, if there is one.
+    fn allows(&self, headers: &HeaderMap) -> bool {
+        let Some(token) = &self.token else {
+            return true;
+        };
+        let sent = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        // Comparing hashes so how long it takes doesn't say how much of it was right
+        Sha256::digest(sent) == Sha256::digest(token)
+    }
+}
+
+/// Everything we count. There's only ever the one, in [`METRICS`], so it can be bumped from
+/// anywhere without passing it around.
+pub struct Metrics {
+    registry: Registry,
+    pub logins: IntCounterVec,
+    pub sign_ups: IntCounter,
+    pub second_factor_lockouts: IntCounter,
+    pub second_factor_challenges: IntCounterVec,
+    pub password_hashing: HistogramVec,
+    pub db_queries: HistogramVec,
+    pub active_sessions: IntGauge,
+    pub http_requests: IntCounterVec,
+    pub http_request_duration: HistogramVec,
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("rust_auth".to_owned()), None)
+            .expect("the prefix should be a valid metric name");
+
+        let metrics = Self {
+            logins: IntCounterVec::new(
+                opts!("logins_total", "Log in attempts, by how they went"),
+                &["outcome"],
+            )
+            .expect("metric should be valid"),
+            sign_ups: IntCounter::new("sign_ups_total", "Accounts made through the sign up page")
+                .expect("metric should be valid"),
+            second_factor_lockouts: IntCounter::new(
+                "second_factor_lockouts_total",
+                "Times someone got too many two factor codes wrong. Log ins refused because the \
+                 account's locked are under logins_total",
+            )
+            .expect("metric should be valid"),
+            second_factor_challenges: IntCounterVec::new(
+                opts!("second_factor_challenges_total", "Two factor codes sent"),
+                &["channel"],
+            )
+            .expect("metric should be valid"),
+            password_hashing: HistogramVec::new(
+                histogram_opts!(
+                    "password_hashing_seconds",
+                    "Time spent hashing and checking passwords",
+                    exponential_buckets(0.005, 2.0, 10).expect("buckets should be valid")
+                ),
+                &["operation"],
+            )
+            .expect("metric should be valid"),
+            db_queries: HistogramVec::new(
+                histogram_opts!(
+                    "db_query_seconds",
+                    "Time taken by database queries",
+                    exponential_buckets(0.0001, 4.0, 9).expect("buckets should be valid")
+                ),
+                &["query"],
+            )
+            .expect("metric should be valid"),
+            active_sessions: IntGauge::new("active_sessions", "Sessions that haven't expired yet")
+                .expect("metric should be valid"),
+            http_requests: IntCounterVec::new(
+                opts!("http_requests_total", "HTTP requests handled"),
+                &["method", "route", "status"],
+            )
+            .expect("metric should be valid"),
+            http_request_duration: HistogramVec::new(
+                histogram_opts!("http_request_duration_seconds", "Time taken to respond"),
+                &["method", "route"],
+            )
+            .expect("metric should be valid"),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 9] = [
+            Box::new(metrics.logins.clone()),
+            Box::new(metrics.sign_ups.clone()),
+            Box::new(metrics.second_factor_lockouts.clone()),
+            Box::new(metrics.second_factor_challenges.clone()),
+            Box::new(metrics.password_hashing.clone()),
+            Box::new(metrics.db_queries.clone()),
+            Box::new(metrics.active_sessions.clone()),
+            Box::new(metrics.http_requests.clone()),
+            Box::new(metrics.http_request_duration.clone()),
+        ];
+        for collector in collectors {
+            metrics
+                .registry
+                .register(collector)
+                .expect("metric names shouldn't clash");
+        }
+
+        metrics
+    }
+}
+
+/// Counts a log in attempt, `outcome` being one of the `LOGIN_*` constants.
+pub fn login(outcome: &str) {
+    METRICS.logins.with_label_values(&[outcome]).inc();
+}
+
+/// Runs some bcrypt (or older) hashing, keeping track of how long it took.
+pub fn time_hash<T>(operation: &str, f: impl FnOnce() -> T) -> T {
+    let _timer = METRICS
+        .password_hashing
+        .with_label_values(&[operation])
+        .start_timer();
+    f()
+}
+
+/// Waits for a query, keeping track of how long it took.
+pub async fn time_query<T>(query: &str, fut: impl Future<Output = T>) -> T {
+    let _timer = METRICS.db_queries.with_label_values(&[query]).start_timer();
+    fut.await
+}
+
+/// Counts requests and how long they took. Labelled with the route rather than the path, so
+/// someone requesting lots of made up paths can't make us keep track of all of them.
+pub async fn layer(request: Request<Body>, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| "other".to_owned());
+    let method = request.method().to_string();
+
+    let started = Instant::now();
+    let response = next.run(request).await;
+
+    METRICS
+        .http_requests
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .inc();
+    METRICS
+        .http_request_duration
+        .with_label_values(&[&method, &route])
+        .observe(started.elapsed().as_secs_f64());
+
+    response
+}
+
+async fn count_sessions(pool: &SqlitePool) -> Result<i64, sqlx::Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time should be after the epoch")
+        .as_secs() as i64;
+
+    time_query(
+        "count_sessions",
+        sqlx::query_scalar("SELECT COUNT(*) FROM tower_sessions WHERE expiry_date > ?")
+            .bind(now)
+            .fetch_one(pool),
+    )
+    .await
+}
+
+/// Everything in the Prometheus text format.
+pub async fn handler(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(config) = &state.config.metrics {
+        if !config.allows(&headers) {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    }
+
+    // Cheaper to count these when asked than to keep track of every session coming and going
+    match count_sessions(&state.pool).await {
+        Ok(count) => METRICS.active_sessions.set(count),
+        Err(err) => tracing::warn!(%err, "Couldn't count sessions"),
+    }
+
+    let encoder = TextEncoder::new();
+    let mut body = vec![];
+    if let Err(err) = encoder.encode(&METRICS.registry.gather(), &mut body) {
+        tracing::error!(%err, "Couldn't encode metrics");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    ([(header::CONTENT_TYPE, encoder.format_type().to_owned())], body).into_response()
+}
+
+/// Serves only the metrics, on their own address, until `shutdown` says to stop.
+pub async fn serve(
+    addr: SocketAddr,
+    path: String,
+    state: AppState,
+    shutdown: Shutdown,
+) -> io::Result<()> {
+    let app = Router::new().route(&path, get(handler)).with_state(state);
+
+    tracing::info!("serving metrics on http://{addr}{path}");
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let server =
+        axum::serve(listener, app).with_graceful_shutdown(shutdown.stop.clone().cancelled_owned());
+    shutdown.drain(server).await
+}
@@ -0,0 +1,352 @@
+#[cfg(feature = "ssr")]
+use axum_login::tower_sessions::{session, Session};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "ssr")]
+use sqlx::{FromRow, SqlitePool};
+use std::str::FromStr;
+#[cfg(feature = "ssr")]
+use thiserror::Error;
+
+#[cfg(feature = "ssr")]
+use crate::audit;
+
+/// What someone can do in an organization. Each one can do everything the ones before it can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(sqlx::Type))]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(feature = "ssr", sqlx(rename_all = "kebab-case"))]
+pub enum OrgRole {
+    Member,
+    /// Can invite people and change who's in it
+    Admin,
+    /// Can also make and remove other owners. There's always at least one.
+    Owner,
+}
+
+impl OrgRole {
+    pub const ALL: &'static [OrgRole] = &[OrgRole::Member, OrgRole::Admin, OrgRole::Owner];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OrgRole::Member => "member",
+            OrgRole::Admin => "admin",
+            OrgRole::Owner => "owner",
+        }
+    }
+}
+
+impl FromStr for OrgRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        OrgRole::ALL
+            .iter()
+            .copied()
+            .find(|r| r.as_str() == s)
+            .ok_or_else(|| format!("Unknown organization role {s:?}, expected member, admin or owner"))
+    }
+}
+
+/// Which organization a session is working in. Only ever trusted after checking they're still
+/// in it, see [`current`].
+#[cfg(feature = "ssr")]
+const CURRENT_ORG_KEY: &str = "current_org";
+
+#[cfg(feature = "ssr")]
+#[derive(Error, Debug)]
+pub enum OrgError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("Session error: {0}")]
+    Session(#[from] session::Error),
+
+    #[error("There's already an organization called {0:?}")]
+    SlugTaken(String),
+
+    #[error("No organization called {0:?}")]
+    NoSuchOrg(String),
+
+    #[error("They're not in that organization")]
+    NotAMember,
+
+    #[error("Every organization needs an owner, make someone else one first")]
+    LastOwner,
+}
+
+#[cfg(feature = "ssr")]
+#[derive(FromRow, Debug, Clone)]
+pub struct Organization {
+    pub id: i64,
+    /// Short name for URLs and the command line
+    pub slug: String,
+    pub name: String,
+}
+
+/// Someone's place in an organization
+#[cfg(feature = "ssr")]
+#[derive(FromRow, Debug, Clone)]
+pub struct Membership {
+    #[sqlx(flatten)]
+    pub org: Organization,
+    pub role: OrgRole,
+}
+
+#[cfg(feature = "ssr")]
+#[derive(FromRow, Debug, Clone)]
+pub struct Member {
+    pub user_id: i64,
+    pub username: String,
+    pub role: OrgRole,
+}
+
+/// Slugs are lower case letters, numbers and dashes, so they can go anywhere.
+#[cfg(feature = "ssr")]
+pub fn normalise_slug(slug: &str) -> Result<String, String> {
+    let slug = slug.trim().to_lowercase();
+    if slug.is_empty() || slug.len() > 64 {
+        return Err("Organization slugs need to be between 1 and 64 characters".to_owned());
+    }
+    if !slug.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err("Organization slugs can only have letters, numbers and dashes".to_owned());
+    }
+    Ok(slug)
+}
+
+#[cfg(feature = "ssr")]
+pub async fn find(pool: &SqlitePool, slug: &str) -> Result<Organization, OrgError> {
+    sqlx::query_as("SELECT id, slug, name FROM organization WHERE slug = ?")
+        .bind(slug)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| OrgError::NoSuchOrg(slug.to_owned()))
+}
+
+#[cfg(feature = "ssr")]
+pub async fn find_by_id(pool: &SqlitePool, id: i64) -> Result<Organization, OrgError> {
+    sqlx::query_as("SELECT id, slug, name FROM organization WHERE id = ?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| OrgError::NoSuchOrg(format!("#{id}")))
+}
+
+/// Every organization, by slug
+#[cfg(feature = "ssr")]
+pub async fn list(pool: &SqlitePool) -> Result<Vec<Organization>, sqlx::Error> {
+    sqlx::query_as("SELECT id, slug, name FROM organization ORDER BY slug")
+        .fetch_all(pool)
+        .await
+}
+
+/// `slug` should already have been through [`normalise_slug`].
+#[cfg(feature = "ssr")]
+pub async fn create(
+    pool: &SqlitePool,
+    slug: &str,
+    name: &str,
+    created_by: Option<i64>,
+) -> Result<Organization, OrgError> {
+    let res = sqlx::query("INSERT INTO organization (slug, name) VALUES (?, ?)")
+        .bind(slug)
+        .bind(name)
+        .execute(pool)
+        .await;
+
+    let id = match res {
+        Ok(res) => res.last_insert_rowid(),
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+            return Err(OrgError::SlugTaken(slug.to_owned()))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    audit::record(pool, created_by, audit::ORG_CREATED, slug).await;
+
+    Ok(Organization {
+        id,
+        slug: slug.to_owned(),
+        name: name.to_owned(),
+    })
+}
+
+/// Every organization someone's in, by name
+#[cfg(feature = "ssr")]
+pub async fn memberships(pool: &SqlitePool, user_id: i64) -> Result<Vec<Membership>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT organization.id, organization.slug, organization.name, org_member.role
+         FROM org_member JOIN organization ON organization.id = org_member.org_id
+         WHERE org_member.user_id = ? ORDER BY organization.name",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+}
+
+#[cfg(feature = "ssr")]
+pub async fn membership(
+    pool: &SqlitePool,
+    org_id: i64,
+    user_id: i64,
+) -> Result<Option<Membership>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT organization.id, organization.slug, organization.name, org_member.role
+         FROM org_member JOIN organization ON organization.id = org_member.org_id
+         WHERE org_member.org_id = ? AND org_member.user_id = ?",
+    )
+    .bind(org_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Everyone in the organization, by username
+#[cfg(feature = "ssr")]
+pub async fn members(pool: &SqlitePool, org_id: i64) -> Result<Vec<Member>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT user.id AS user_id, user.username, org_member.role
+         FROM org_member JOIN user ON user.id = org_member.user_id
+         WHERE org_member.org_id = ? ORDER BY user.username",
+    )
+    .bind(org_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Whether someone other than `?2` owns organization `?1`. Checked in the same statement as the
+/// change it's guarding, so two owners stepping down at once can't both get through.
+#[cfg(feature = "ssr")]
+const ANOTHER_OWNER: &str = "EXISTS (SELECT 1 FROM org_member AS other
+    WHERE other.org_id = ?1 AND other.role = 'owner' AND other.user_id != ?2)";
+
+/// Puts someone in the organization as `role`, or changes their role if they're already in it.
+/// Fails if it'd leave the organization without an owner.
+#[cfg(feature = "ssr")]
+pub async fn set_member(
+    pool: &SqlitePool,
+    org: &Organization,
+    user_id: i64,
+    role: OrgRole,
+    changed_by: Option<i64>,
+) -> Result<(), OrgError> {
+    let res = sqlx::query(&format!(
+        "INSERT INTO org_member (org_id, user_id, role) VALUES (?1, ?2, ?3)
+         ON CONFLICT (org_id, user_id) DO UPDATE SET role = excluded.role
+         WHERE excluded.role = 'owner' OR org_member.role != 'owner' OR {ANOTHER_OWNER}"
+    ))
+    .bind(org.id)
+    .bind(user_id)
+    .bind(role)
+    .execute(pool)
+    .await?;
+    if res.rows_affected() == 0 {
+        return Err(OrgError::LastOwner);
+    }
+
+    let detail = match changed_by {
+        Some(by) => format!("{} as {} by user {by}", org.slug, role.as_str()),
+        None => format!("{} as {}", org.slug, role.as_str()),
+    };
+    audit::record(pool, Some(user_id), audit::ORG_MEMBER_SET, &detail).await;
+    Ok(())
+}
+
+#[cfg(feature = "ssr")]
+pub async fn remove_member(
+    pool: &SqlitePool,
+    org: &Organization,
+    user_id: i64,
+    removed_by: Option<i64>,
+) -> Result<(), OrgError> {
+    let res = sqlx::query(&format!(
+        "DELETE FROM org_member WHERE org_id = ?1 AND user_id = ?2
+         AND (role != 'owner' OR {ANOTHER_OWNER})"
+    ))
+    .bind(org.id)
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+    if res.rows_affected() == 0 {
+        // Only to say why
+        return Err(match membership(pool, org.id, user_id).await? {
+            Some(_) => OrgError::LastOwner,
+            None => OrgError::NotAMember,
+        });
+    }
+
+    let detail = match removed_by {
+        Some(by) => format!("{} by user {by}", org.slug),
+        None => org.slug.clone(),
+    };
+    audit::record(pool, Some(user_id), audit::ORG_MEMBER_REMOVED, &detail).await;
+    Ok(())
+}
+
+/// The organization this session is working in. If they've not picked one, or have since been
+/// taken out of it, it's whichever of theirs comes first. `None` if they're not in any.
+#[cfg(feature = "ssr")]
+pub async fn current(
+    pool: &SqlitePool,
+    session: &Session,
+    user_id: i64,
+) -> Result<Option<Membership>, OrgError> {
+    if let Some(org_id) = session.get::<i64>(CURRENT_ORG_KEY).await? {
+        if let Some(membership) = membership(pool, org_id, user_id).await? {
+            return Ok(Some(membership));
+        }
+    }
+
+    let first = memberships(pool, user_id).await?.into_iter().next();
+    match &first {
+        Some(first) => session.insert(CURRENT_ORG_KEY, first.org.id).await?,
+        None => {
+            session.remove::<i64>(CURRENT_ORG_KEY).await?;
+        }
+    }
+    Ok(first)
+}
+
+/// Makes `org_id` the organization this session is working in.
+#[cfg(feature = "ssr")]
+pub async fn switch(
+    pool: &SqlitePool,
+    session: &Session,
+    user_id: i64,
+    org_id: i64,
+) -> Result<Membership, OrgError> {
+    let membership = membership(pool, org_id, user_id)
+        .await?
+        .ok_or(OrgError::NotAMember)?;
+    session.insert(CURRENT_ORG_KEY, org_id).await?;
+    Ok(membership)
+}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+    use crate::auth::NO_PASSWORD;
+    use crate::state::test_pool;
+    use crate::store::{SqlxUserStore, UserStore};
+
+    #[tokio::test]
+    async fn theres_always_an_owner() {
+        let pool = test_pool().await;
+        let store = SqlxUserStore::new(pool.clone());
+        let alice = store.create("alice", NO_PASSWORD).await.unwrap().id;
+        let bob = store.create("bob", NO_PASSWORD).await.unwrap().id;
+        let org = create(&pool, "acme", "Acme", None).await.unwrap();
+        set_member(&pool, &org, alice, OrgRole::Owner, None).await.unwrap();
+        set_member(&pool, &org, bob, OrgRole::Owner, None).await.unwrap();
+
+        set_member(&pool, &org, alice, OrgRole::Admin, None).await.unwrap();
+        assert!(matches!(
+            set_member(&pool, &org, bob, OrgRole::Member, None).await,
+            Err(OrgError::LastOwner)
+        ));
+        assert!(matches!(remove_member(&pool, &org, bob, None).await, Err(OrgError::LastOwner)));
+        let bob_now = membership(&pool, org.id, bob).await.unwrap().unwrap();
+        assert_eq!(bob_now.role, OrgRole::Owner);
+
+        remove_member(&pool, &org, alice, None).await.unwrap();
+        assert!(matches!(remove_member(&pool, &org, alice, None).await, Err(OrgError::NotAMember)));
+    }
+}
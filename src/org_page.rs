@@ -0,0 +1,578 @@
+use leptos::*;
+use leptos_router::*;
+use serde::{Deserialize, Serialize};
+
+use crate::admin::InviteSummary;
+use crate::app::{FieldError, FormError};
+use crate::auth_error::{self, AuthError};
+use crate::org::OrgRole;
+
+/// One of the organizations someone's in
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OrgSummary {
+    pub id: i64,
+    pub slug: String,
+    pub name: String,
+    /// Theirs, not anyone else's
+    pub role: OrgRole,
+    /// Whether it's the one they're working in
+    pub current: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OrgMember {
+    pub username: String,
+    pub role: OrgRole,
+}
+
+/// What the organization page shows
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OrgInfo {
+    pub org: OrgSummary,
+    pub members: Vec<OrgMember>,
+    /// Empty unless they're an admin
+    pub invites: Vec<InviteSummary>,
+}
+
+/// Gets the logged in user and the organization they're working in, as long as they're at least
+/// `role` in it. Anything that's per organization should be scoped by what this gives back.
+#[cfg(feature = "ssr")]
+pub async fn require_org(
+    role: OrgRole,
+) -> Result<(crate::auth::User, crate::org::Membership), AuthError> {
+    use crate::account::require_user;
+    use crate::org;
+    use crate::state::AppState;
+    use axum_login::tower_sessions::Session;
+
+    let user = require_user().await?;
+    let state = expect_context::<AppState>();
+    let membership = org::current(&state.pool, &expect_context::<Session>(), user.id)
+        .await?
+        .ok_or(AuthError::NoOrganization)?;
+
+    if membership.role < role {
+        return Err(AuthError::Forbidden);
+    }
+    Ok((user, membership))
+}
+
+/// Admins can look after anyone up to their own role, and make them anything up to it.
+#[cfg(feature = "ssr")]
+fn check_outranks(actor: OrgRole, target: OrgRole) -> Result<(), AuthError> {
+    if target > actor {
+        return Err(AuthError::rejected(format!(
+            "Only an {} or above can do that",
+            target.as_str()
+        )));
+    }
+    Ok(())
+}
+
+/// Empty if they're not logged in
+#[server]
+async fn my_orgs() -> Result<Vec<OrgSummary>, ServerFnError<AuthError>> {
+    use crate::auth::AuthSession;
+    use crate::org;
+    use crate::state::AppState;
+    use axum_login::tower_sessions::Session;
+
+    let Some(user) = expect_context::<AuthSession>().user else {
+        return Ok(Vec::new());
+    };
+    let state = expect_context::<AppState>();
+
+    let current = org::current(&state.pool, &expect_context::<Session>(), user.id)
+        .await
+        .map_err(AuthError::from)?;
+    let memberships = org::memberships(&state.pool, user.id)
+        .await
+        .map_err(AuthError::from)?;
+
+    Ok(memberships
+        .into_iter()
+        .map(|m| OrgSummary {
+            current: current.as_ref().is_some_and(|c| c.org.id == m.org.id),
+            id: m.org.id,
+            slug: m.org.slug,
+            name: m.org.name,
+            role: m.role,
+        })
+        .collect())
+}
+
+#[server(SwitchOrg)]
+async fn switch_org(id: i64) -> Result<(), ServerFnError<AuthError>> {
+    use crate::account::require_user;
+    use crate::org;
+    use crate::state::AppState;
+    use axum_login::tower_sessions::Session;
+
+    let user = require_user().await?;
+    let state = expect_context::<AppState>();
+    let membership = org::switch(&state.pool, &expect_context::<Session>(), user.id, id)
+        .await
+        .map_err(AuthError::from)?;
+    tracing::info!(user.username, org = membership.org.slug, "Switched organization");
+    Ok(())
+}
+
+/// `None` if they're not in any organization
+#[server]
+async fn org_details() -> Result<Option<OrgInfo>, ServerFnError<AuthError>> {
+    use crate::admin::summarise_invites;
+    use crate::invite;
+    use crate::org;
+    use crate::state::AppState;
+
+    let membership = match require_org(OrgRole::Member).await {
+        Ok((_, membership)) => membership,
+        Err(AuthError::NoOrganization) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let state = expect_context::<AppState>();
+
+    let members = org::members(&state.pool, membership.org.id)
+        .await
+        .map_err(AuthError::from)?
+        .into_iter()
+        .map(|m| OrgMember {
+            username: m.username,
+            role: m.role,
+        })
+        .collect();
+    let invites = if membership.role >= OrgRole::Admin {
+        let invites = invite::list(&state.pool, Some(membership.org.id))
+            .await
+            .map_err(AuthError::from)?;
+        summarise_invites(invites).await?
+    } else {
+        Vec::new()
+    };
+
+    Ok(Some(OrgInfo {
+        org: OrgSummary {
+            id: membership.org.id,
+            slug: membership.org.slug,
+            name: membership.org.name,
+            role: membership.role,
+            current: true,
+        },
+        members,
+        invites,
+    }))
+}
+
+#[server(SetOrgRole)]
+async fn set_org_role(username: String, role: OrgRole) -> Result<(), ServerFnError<AuthError>> {
+    use crate::org;
+    use crate::state::AppState;
+
+    let (user, membership) = require_org(OrgRole::Admin).await?;
+    let state = expect_context::<AppState>();
+
+    let member = state
+        .auth
+        .store
+        .find_by_username(&username.trim().to_lowercase())
+        .await
+        .map_err(AuthError::from)?
+        .ok_or_else(|| AuthError::rejected("No one's called that"))?;
+    let current = org::membership(&state.pool, membership.org.id, member.id)
+        .await
+        .map_err(AuthError::from)?
+        .ok_or_else(|| AuthError::rejected("They're not in this organization"))?;
+    check_outranks(membership.role, current.role)?;
+    check_outranks(membership.role, role)?;
+
+    org::set_member(&state.pool, &membership.org, member.id, role, Some(user.id))
+        .await
+        .map_err(AuthError::from)?;
+    Ok(())
+}
+
+#[server(RemoveOrgMember)]
+async fn remove_org_member(username: String) -> Result<(), ServerFnError<AuthError>> {
+    use crate::org;
+    use crate::state::AppState;
+
+    let (user, membership) = require_org(OrgRole::Admin).await?;
+    let state = expect_context::<AppState>();
+
+    let member = state
+        .auth
+        .store
+        .find_by_username(&username.trim().to_lowercase())
+        .await
+        .map_err(AuthError::from)?
+        .ok_or_else(|| AuthError::rejected("No one's called that"))?;
+    let current = org::membership(&state.pool, membership.org.id, member.id)
+        .await
+        .map_err(AuthError::from)?
+        .ok_or_else(|| AuthError::rejected("They're not in this organization"))?;
+    check_outranks(membership.role, current.role)?;
+
+    org::remove_member(&state.pool, &membership.org, member.id, Some(user.id))
+        .await
+        .map_err(AuthError::from)?;
+    Ok(())
+}
+
+/// Gives back the link to send to whoever's invited. Empty fields mean no limit.
+#[server(CreateOrgInvite)]
+async fn create_org_invite(
+    role: OrgRole,
+    max_uses: String,
+    ttl_hours: String,
+) -> Result<String, ServerFnError<AuthError>> {
+    use crate::auth::Role;
+    use crate::invite;
+    use crate::state::AppState;
+
+    let (user, membership) = require_org(OrgRole::Admin).await?;
+    let state = expect_context::<AppState>();
+    check_outranks(membership.role, role)?;
+
+    let max_uses =
+        invite::parse_limit(&max_uses).map_err(|err| AuthError::validation("max_uses", err))?;
+    let ttl_hours =
+        invite::parse_limit(&ttl_hours).map_err(|err| AuthError::validation("ttl_hours", err))?;
+
+    // Always as a plain user here, whatever they are in the organization
+    let org = Some((membership.org.id, role));
+    let code = invite::create(&state.pool, Some(user.id), Role::User, org, max_uses, ttl_hours)
+        .await
+        .map_err(AuthError::from)?;
+    tracing::info!(user.username, org = membership.org.slug, ?role, "Created an organization invite");
+
+    Ok(invite::link(&state.config.public_url(), &code))
+}
+
+#[server(RevokeOrgInvite)]
+async fn revoke_org_invite(id: i64) -> Result<(), ServerFnError<AuthError>> {
+    use crate::invite;
+    use crate::state::AppState;
+
+    let (user, membership) = require_org(OrgRole::Admin).await?;
+    let state = expect_context::<AppState>();
+
+    // Only this organization's
+    let invites = invite::list(&state.pool, Some(membership.org.id))
+        .await
+        .map_err(AuthError::from)?;
+    if !invites.iter().any(|invite| invite.id == id)
+        || !invite::revoke(&state.pool, id, Some(user.id))
+            .await
+            .map_err(AuthError::from)?
+    {
+        return Err(AuthError::rejected("No such invite").into());
+    }
+    Ok(())
+}
+
+/// For people who've already got an account. Whatever site role the invite has is ignored, it
+/// only counts when signing up.
+#[server(JoinOrg)]
+async fn join_org(invite: String) -> Result<(), ServerFnError<AuthError>> {
+    use crate::account::require_user;
+    use crate::audit;
+    use crate::invite;
+    use crate::org;
+    use crate::state::AppState;
+    use axum_login::tower_sessions::Session;
+
+    let user = require_user().await?;
+    let state = expect_context::<AppState>();
+
+    let Some(invite) = invite::redeem(&state.pool, &invite)
+        .await
+        .map_err(AuthError::from)?
+    else {
+        return Err(AuthError::validation(
+            "invite",
+            "That invite doesn't exist, has expired or has been used up",
+        )
+        .into());
+    };
+    let (Some(org_id), Some(role)) = (invite.org_id, invite.org_role) else {
+        invite::give_back(&state.pool, invite.id)
+            .await
+            .map_err(AuthError::from)?;
+        return Err(AuthError::validation("invite", "That invite isn't for an organization").into());
+    };
+
+    // Whether it got them anything
+    let joined = async {
+        let org = org::find_by_id(&state.pool, org_id)
+            .await
+            .map_err(AuthError::from)?;
+        let existing = org::membership(&state.pool, org.id, user.id)
+            .await
+            .map_err(AuthError::from)?;
+        // Joining again shouldn't take away a better role they've already got
+        if existing.is_some_and(|existing| existing.role >= role) {
+            return Ok((org, false));
+        }
+        org::set_member(&state.pool, &org, user.id, role, None)
+            .await
+            .map_err(AuthError::from)?;
+        Ok::<_, AuthError>((org, true))
+    }
+    .await;
+    // If it didn't, the invite shouldn't count
+    let org = match joined {
+        Ok((org, true)) => {
+            audit::record(
+                &state.pool,
+                Some(user.id),
+                audit::INVITE_USED,
+                &format!("invite {}", invite.id),
+            )
+            .await;
+            org
+        }
+        Ok((org, false)) => {
+            invite::give_back(&state.pool, invite.id)
+                .await
+                .map_err(AuthError::from)?;
+            org
+        }
+        Err(err) => {
+            invite::give_back(&state.pool, invite.id)
+                .await
+                .map_err(AuthError::from)?;
+            return Err(err.into());
+        }
+    };
+
+    org::switch(&state.pool, &expect_context::<Session>(), user.id, org.id)
+        .await
+        .map_err(AuthError::from)?;
+    Ok(())
+}
+
+/// Picks which organization they're working in. Nothing if they're in one or none.
+#[component]
+pub fn OrgSwitcher() -> impl IntoView {
+    let switch_action = create_server_action::<SwitchOrg>();
+    let ret = switch_action.value();
+    let orgs = create_resource(|| (), |_| async { my_orgs().await });
+
+    // Everything on the page might be for the old one
+    create_effect(move |_| {
+        if let Some(Ok(())) = ret.get() {
+            let _ = window().location().reload();
+        }
+    });
+
+    view! {
+        <Transition fallback=||()>
+        {move || orgs.get().map(|orgs| match orgs {
+            Ok(orgs) if orgs.len() > 1 => view! {
+                <ActionForm class="org-switcher" action=switch_action>
+                    <select name="id">
+                        {orgs.into_iter().map(|org| view! {
+                            <option value=org.id selected=org.current>{org.name}</option>
+                        }).collect_view()}
+                    </select>
+                    <input type="submit" value="Switch"/>
+                </ActionForm>
+            }.into_view(),
+            Ok(_) => ().into_view(),
+            Err(err) => view! { <p>{auth_error::message(&err)}</p> }.into_view(),
+        })}
+        </Transition>
+        {move || match ret.get() {
+            Some(Err(err)) => view! { <p>{auth_error::message(&err)}</p> }.into_view(),
+            _ => ().into_view(),
+        }}
+    }
+}
+
+#[component]
+fn JoinForm() -> impl IntoView {
+    let join_action = create_server_action::<JoinOrg>();
+    let ret = join_action.value();
+
+    create_effect(move |_| {
+        if let Some(Ok(())) = ret.get() {
+            let _ = window().location().reload();
+        }
+    });
+
+    view! {
+        <h2>"Join an organization"</h2>
+        <ActionForm class="credential-form" action=join_action>
+            <label for="invite">Invite code </label>
+            <input type="text" name="invite"/>
+            <FieldError ret field="invite"/>
+            <input type="submit" value="Join"/>
+        </ActionForm>
+        <FormError ret fields=&["invite"]/>
+    }
+}
+
+#[component]
+fn Members(org: OrgSummary, members: Vec<OrgMember>) -> impl IntoView {
+    let role_action = create_server_action::<SetOrgRole>();
+    let remove_action = create_server_action::<RemoveOrgMember>();
+
+    // However the last of the table's buttons went, and reloaded since anyone's role could change
+    let ret = create_rw_signal(None);
+    for value in [role_action.value(), remove_action.value()] {
+        create_effect(move |_| match value.get() {
+            Some(Ok(())) => {
+                let _ = window().location().reload();
+            }
+            Some(err) => ret.set(Some(err)),
+            None => (),
+        });
+    }
+
+    let my_role = org.role;
+    let admin = my_role >= OrgRole::Admin;
+
+    view! {
+        <h2>"Members"</h2>
+        <table class="members">
+            <tr><th>"Username"</th><th>"Role"</th><th></th></tr>
+            {members.into_iter().map(|member| {
+                let manageable = admin && member.role <= my_role;
+                let username = member.username.clone();
+                view! {
+                    <tr>
+                        <td>{member.username.clone()}</td>
+                        <td>
+                            {if manageable {
+                                view! {
+                                    <ActionForm action=role_action>
+                                        <input type="hidden" name="username" value=username/>
+                                        <select name="role">
+                                            {OrgRole::ALL.iter().filter(|r| **r <= my_role).map(|role| view! {
+                                                <option value=role.as_str() selected=*role == member.role>
+                                                    {role.as_str()}
+                                                </option>
+                                            }).collect_view()}
+                                        </select>
+                                        <input type="submit" value="Change"/>
+                                    </ActionForm>
+                                }.into_view()
+                            } else {
+                                member.role.as_str().into_view()
+                            }}
+                        </td>
+                        <td>
+                            {manageable.then(|| view! {
+                                <ActionForm action=remove_action>
+                                    <input type="hidden" name="username" value=member.username/>
+                                    <input type="submit" value="Remove"/>
+                                </ActionForm>
+                            })}
+                        </td>
+                    </tr>
+                }
+            }).collect_view()}
+        </table>
+        <FormError ret fields=&[]/>
+    }
+}
+
+#[component]
+fn OrgInvites(org: OrgSummary, invites: Vec<InviteSummary>) -> impl IntoView {
+    let create_action = create_server_action::<CreateOrgInvite>();
+    let pending = create_action.pending();
+    let ret = create_action.value();
+    let revoke_action = create_server_action::<RevokeOrgInvite>();
+    let revoke_ret = revoke_action.value();
+
+    create_effect(move |_| {
+        if let Some(Ok(())) = revoke_ret.get() {
+            let _ = window().location().reload();
+        }
+    });
+
+    view! {
+        <h2>"Invites"</h2>
+        <p>"People without an account sign up with the link. Anyone who's already got one can put the code in below."</p>
+
+        <ActionForm class="credential-form" action=create_action>
+            <label for="role">Role </label>
+            <select name="role">
+                {OrgRole::ALL.iter().filter(|r| **r <= org.role).map(|role| view! {
+                    <option value=role.as_str()>{role.as_str()}</option>
+                }).collect_view()}
+            </select>
+
+            <label for="max_uses">Uses </label>
+            <input type="number" name="max_uses" min="1" value="1"/>
+
+            <label for="ttl_hours">Expires after (hours) </label>
+            <input type="number" name="ttl_hours" min="1" value="168"/>
+
+            <input type="submit" value="Create invite"/>
+        </ActionForm>
+
+        <p>{move || pending.get().then_some("Working... 🛌")}</p>
+        {move || match ret.get() {
+            Some(Ok(link)) => view! {
+                <p>"Here's the link, it won't be shown again: " <code>{link}</code></p>
+            }.into_view(),
+            Some(Err(err)) => view! { <p>{auth_error::message(&err)}</p> }.into_view(),
+            None => ().into_view(),
+        }}
+
+        <table class="invites">
+            <tr><th>"Role"</th><th>"Made by"</th><th>"Used"</th><th>"Expires"</th><th></th></tr>
+            {invites.into_iter().map(|invite| view! {
+                <tr class:spent=invite.spent>
+                    <td>{invite.org.map(|(_, role)| role)}</td>
+                    <td>{invite.created_by.unwrap_or_else(|| "CLI".to_owned())}</td>
+                    <td>{invite.uses}</td>
+                    <td>{invite.expires}</td>
+                    <td>
+                        <ActionForm action=revoke_action>
+                            <input type="hidden" name="id" value=invite.id/>
+                            <input type="submit" value="Revoke"/>
+                        </ActionForm>
+                    </td>
+                </tr>
+            }).collect_view()}
+        </table>
+        {move || match revoke_ret.get() {
+            Some(Err(err)) => view! { <p>{auth_error::message(&err)}</p> }.into_view(),
+            _ => ().into_view(),
+        }}
+    }
+}
+
+/// The organization they're working in, and who's in it
+#[component]
+pub fn Organization() -> impl IntoView {
+    let details = create_blocking_resource(|| (), |_| async { org_details().await });
+
+    view! {
+        <Suspense fallback=||()>
+        {move || details.get().map(|details| match details {
+            Ok(Some(OrgInfo { org, members, invites })) => view! {
+                <h1>{org.name.clone()}</h1>
+                <OrgSwitcher/>
+                <p>"You're " {org.role.as_str()} " here."</p>
+                <Members org=org.clone() members/>
+                {(org.role >= OrgRole::Admin).then(|| view! { <OrgInvites org invites/> })}
+                <JoinForm/>
+            }.into_view(),
+            Ok(None) => view! {
+                <h1>"Organizations"</h1>
+                <p>"You're not in one yet."</p>
+                <JoinForm/>
+            }.into_view(),
+            Err(ServerFnError::WrappedServerError(AuthError::NotLoggedIn | AuthError::NeedsSecondFactor)) => view! {
+                <h1>"Organizations"</h1>
+                <p>"You need to " <A href="/login">"log in"</A> " first."</p>
+            }.into_view(),
+            Err(err) => view! { <p>{auth_error::message(&err)}</p> }.into_view(),
+        })}
+        </Suspense>
+        <A href="/"> Back to homepage </A>
+    }
+}
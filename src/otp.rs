@@ -0,0 +1,713 @@
+use axum_login::tower_sessions::{session, Session};
+use rand::{Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, SqlitePool};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+use crate::audit;
+use crate::auth::User;
+use crate::mail::Mailer;
+use crate::message::{MessageError, MessageSender, SenderConfig};
+use crate::metrics::METRICS;
+use crate::reload::Live;
+use crate::state::Config;
+
+/// Where a user's codes get sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum OtpChannel {
+    Email,
+    Sms,
+}
+
+impl OtpChannel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Email => "email",
+            Self::Sms => "sms",
+        }
+    }
+}
+
+/// What a code is for, so a code sent while setting things up can't be used to log in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(rename_all = "lowercase")]
+pub enum Purpose {
+    Login,
+    Enroll,
+}
+
+#[derive(Error, Debug)]
+pub enum OtpError {
+    #[error("Please wait {0} seconds before asking for another code")]
+    TooSoon(i64),
+
+    #[error("That code has expired, ask for a new one")]
+    Expired,
+
+    #[error("Too many wrong codes, ask for a new one")]
+    TooManyAttempts,
+
+    #[error("Wrong code, {0} tries left")]
+    WrongCode(i64),
+
+    #[error("Too many wrong codes, try again in {} minutes", (.0 + 59) / 60)]
+    LockedOut(i64),
+
+    #[error("That recovery code isn't right")]
+    WrongRecoveryCode,
+
+    #[error("There's no code waiting to be entered, ask for a new one")]
+    NoChallenge,
+
+    #[error("Codes can't be sent by {0:?} here")]
+    ChannelUnavailable(OtpChannel),
+
+    #[error("Couldn't send code: {0}")]
+    Send(#[from] MessageError),
+
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("Session error: {0}")]
+    Session(#[from] session::Error),
+}
+
+fn default_code_ttl_minutes() -> i64 {
+    10
+}
+
+fn default_max_attempts() -> i64 {
+    5
+}
+
+fn default_resend_interval_seconds() -> i64 {
+    60
+}
+
+fn default_lockout_after() -> i64 {
+    10
+}
+
+fn default_lockout_minutes() -> i64 {
+    15
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct TwoFactorConfig {
+    #[serde(default = "default_code_ttl_minutes")]
+    pub code_ttl_minutes: i64,
+
+    /// Wrong guesses allowed per code
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: i64,
+
+    /// How long to wait between sending codes
+    #[serde(default = "default_resend_interval_seconds")]
+    pub resend_interval_seconds: i64,
+
+    /// Wrong guesses allowed across every code sent (and recovery codes), before they're locked
+    /// out for `lockout_minutes`. Each lockout after that is twice as long, up to a day.
+    #[serde(default = "default_lockout_after")]
+    pub lockout_after: i64,
+    #[serde(default = "default_lockout_minutes")]
+    pub lockout_minutes: i64,
+
+    pub email: Option<SenderConfig>,
+    pub sms: Option<SenderConfig>,
+}
+
+impl TwoFactorConfig {
+    pub fn lockout(&self) -> Lockout {
+        Lockout {
+            after: self.lockout_after,
+            minutes: self.lockout_minutes,
+        }
+    }
+}
+
+/// Longest anyone's locked out for, however many times they've been locked out before
+const MAX_LOCKOUT_SECONDS: i64 = 24 * 60 * 60;
+
+/// Makes people wait once they've had too many wrong guesses at a code, whichever code it was,
+/// recovery codes included.
+/// A code only gets a few tries, but a new one can be sent every minute, so without this there'd
+/// be no end to the guessing.
+///
+/// Guesses are counted before they're checked, the same as [`Otp::verify`] does for a code, and
+/// the count only goes away once they get one right.
+#[derive(Debug, Clone, Copy)]
+pub struct Lockout {
+    pub after: i64,
+    pub minutes: i64,
+}
+
+impl Default for Lockout {
+    fn default() -> Self {
+        Self {
+            after: default_lockout_after(),
+            minutes: default_lockout_minutes(),
+        }
+    }
+}
+
+impl Lockout {
+    /// Refuses if they're locked out right now.
+    pub async fn check(pool: &SqlitePool, user_id: i64, purpose: Purpose) -> Result<(), OtpError> {
+        let locked_until: Option<i64> = sqlx::query_scalar(
+            "SELECT locked_until FROM otp_failure
+             WHERE user_id = ? AND purpose = ? AND locked_until > ?",
+        )
+        .bind(user_id)
+        .bind(purpose)
+        .bind(now())
+        .fetch_optional(pool)
+        .await?
+        .flatten();
+
+        match locked_until {
+            Some(until) => Err(OtpError::LockedOut(until - now())),
+            None => Ok(()),
+        }
+    }
+
+    /// Counts a guess, unless they've run out. Gives back how many they've had, to pass to
+    /// [`Lockout::failed`] if it's wrong.
+    pub async fn attempt(
+        &self,
+        pool: &SqlitePool,
+        user_id: i64,
+        purpose: Purpose,
+    ) -> Result<i64, OtpError> {
+        // A lockout that's over starts them on a new round of guesses
+        let counted: Option<i64> = sqlx::query_scalar(
+            "INSERT INTO otp_failure (user_id, purpose, failures) VALUES (?1, ?2, 1)
+             ON CONFLICT (user_id, purpose) DO UPDATE SET
+                 failures = CASE WHEN locked_until <= ?3 THEN 1 ELSE failures + 1 END,
+                 locked_until = CASE WHEN locked_until <= ?3 THEN NULL ELSE locked_until END
+             WHERE failures < ?4 OR locked_until <= ?3
+             RETURNING failures",
+        )
+        .bind(user_id)
+        .bind(purpose)
+        .bind(now())
+        .bind(self.after)
+        .fetch_optional(pool)
+        .await?;
+
+        match counted {
+            Some(failures) => Ok(failures),
+            None => {
+                Self::check(pool, user_id, purpose).await?;
+                // Out of guesses, but the one that used them up hasn't locked them out yet
+                Err(OtpError::LockedOut(self.minutes * 60))
+            }
+        }
+    }
+
+    /// Call when the guess [`Lockout::attempt`] counted as `failures` was wrong. If it was their
+    /// last, they're locked out and this says for how long.
+    pub async fn failed(
+        &self,
+        pool: &SqlitePool,
+        user_id: i64,
+        purpose: Purpose,
+        failures: i64,
+    ) -> Result<(), OtpError> {
+        if failures < self.after {
+            return Ok(());
+        }
+
+        let locked_until: i64 = sqlx::query_scalar(
+            "UPDATE otp_failure SET lockouts = lockouts + 1,
+                 locked_until = ?1 + MIN(?2 << MIN(lockouts, 16), ?3)
+             WHERE user_id = ?4 AND purpose = ?5
+             RETURNING locked_until",
+        )
+        .bind(now())
+        .bind(self.minutes * 60)
+        .bind(MAX_LOCKOUT_SECONDS)
+        .bind(user_id)
+        .bind(purpose)
+        .fetch_one(pool)
+        .await?;
+        let seconds = locked_until - now();
+
+        audit::record(
+            pool,
+            Some(user_id),
+            audit::SECOND_FACTOR_FAILED,
+            &format!("locked out for {} minutes", seconds / 60),
+        )
+        .await;
+        METRICS.second_factor_lockouts.inc();
+        Err(OtpError::LockedOut(seconds))
+    }
+
+    /// Forgets their wrong guesses once they've got one right.
+    pub async fn succeeded(
+        pool: &SqlitePool,
+        user_id: i64,
+        purpose: Purpose,
+    ) -> Result<(), OtpError> {
+        sqlx::query("DELETE FROM otp_failure WHERE user_id = ? AND purpose = ?")
+            .bind(user_id)
+            .bind(purpose)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+}
+
+/// The code someone's been sent and not entered yet. One per user and purpose at a time. How
+/// many wrong tries it's had is only looked at in the database, see [`Otp::verify`].
+#[derive(FromRow, Debug)]
+pub struct Challenge {
+    pub user_id: i64,
+    pub purpose: Purpose,
+    pub channel: OtpChannel,
+    pub destination: String,
+    code_hash: String,
+    salt: String,
+    pub sent_at: i64,
+    expires_at: i64,
+}
+
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("it should be after 1970")
+        .as_secs() as i64
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn hash_code(salt: &str, code: &str) -> String {
+    hex(&Sha256::new()
+        .chain_update(salt)
+        .chain_update(code.trim())
+        .finalize())
+}
+
+/// Sends one time codes by email or text and checks them.
+#[derive(Debug, Clone)]
+pub struct Otp {
+    /// The limits can be reloaded, so they're read from here each time. See [`Otp::config`].
+    live: Live<Config>,
+    pub pool: SqlitePool,
+    email: Option<Arc<dyn MessageSender>>,
+    sms: Option<Arc<dyn MessageSender>>,
+}
+
+impl Otp {
+    /// Sets up the senders from `[two-factor]` in `live`.
+    pub fn new(live: Live<Config>, pool: SqlitePool, mailer: Option<&Mailer>) -> Result<Self, String> {
+        let current = live.get();
+        let config = current
+            .two_factor
+            .as_ref()
+            .ok_or_else(|| "[two-factor] isn't configured".to_owned())?;
+        let email = config.email.as_ref().map(|c| c.build(mailer)).transpose()?;
+        let sms = config.sms.as_ref().map(|c| c.build(mailer)).transpose()?;
+        if email.is_none() && sms.is_none() {
+            return Err(
+                "[two-factor] needs at least one of [two-factor.email] or [two-factor.sms]"
+                    .to_owned(),
+            );
+        }
+
+        Ok(Self {
+            live,
+            pool,
+            email: email.map(Arc::from),
+            sms: sms.map(Arc::from),
+        })
+    }
+
+    /// `[two-factor]` as it is right now.
+    pub fn config(&self) -> TwoFactorConfig {
+        self.live
+            .get()
+            .two_factor
+            .clone()
+            .expect("reloading never takes [two-factor] away")
+    }
+
+    /// Which channels people can pick from
+    pub fn channels(&self) -> Vec<OtpChannel> {
+        [
+            (OtpChannel::Email, self.email.is_some()),
+            (OtpChannel::Sms, self.sms.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(channel, available)| available.then_some(channel))
+        .collect()
+    }
+
+    fn sender(&self, channel: OtpChannel) -> Result<&dyn MessageSender, OtpError> {
+        match channel {
+            OtpChannel::Email => self.email.as_deref(),
+            OtpChannel::Sms => self.sms.as_deref(),
+        }
+        .ok_or(OtpError::ChannelUnavailable(channel))
+    }
+
+    async fn challenge(
+        &self,
+        user_id: i64,
+        purpose: Purpose,
+    ) -> Result<Option<Challenge>, OtpError> {
+        Ok(
+            sqlx::query_as("SELECT * FROM otp_challenge WHERE user_id = ? AND purpose = ?")
+                .bind(user_id)
+                .bind(purpose)
+                .fetch_optional(&self.pool)
+                .await?,
+        )
+    }
+
+    /// Sends a fresh code, replacing any the user had for `purpose`. Refuses if they've had one
+    /// for it too recently.
+    pub async fn send_code(
+        &self,
+        user_id: i64,
+        purpose: Purpose,
+        channel: OtpChannel,
+        destination: &str,
+    ) -> Result<(), OtpError> {
+        let sender = self.sender(channel)?;
+        let config = self.config();
+        // No new codes to guess at until they've waited
+        Lockout::check(&self.pool, user_id, purpose).await?;
+
+        let last_sent: Option<i64> =
+            sqlx::query_scalar("SELECT sent_at FROM otp_challenge WHERE user_id = ? AND purpose = ?")
+                .bind(user_id)
+                .bind(purpose)
+                .fetch_optional(&self.pool)
+                .await?;
+        if let Some(last_sent) = last_sent {
+            let wait = last_sent + config.resend_interval_seconds - now();
+            if wait > 0 {
+                return Err(OtpError::TooSoon(wait));
+            }
+        }
+
+        let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+        let mut salt = [0; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let salt = hex(&salt);
+
+        sqlx::query(
+            "INSERT OR REPLACE INTO otp_challenge
+             (user_id, purpose, channel, destination, code_hash, salt, attempts, sent_at, expires_at)
+             VALUES (?, ?, ?, ?, ?, ?, 0, ?, ?)",
+        )
+        .bind(user_id)
+        .bind(purpose)
+        .bind(channel)
+        .bind(destination)
+        .bind(hash_code(&salt, &code))
+        .bind(&salt)
+        .bind(now())
+        .bind(now() + config.code_ttl_minutes * 60)
+        .execute(&self.pool)
+        .await?;
+
+        let body = format!(
+            "Your code is {code}. It works for {} minutes. Don't share it with anyone!",
+            config.code_ttl_minutes
+        );
+        sender.send(destination, "Your log in code", &body).await?;
+        METRICS
+            .second_factor_challenges
+            .with_label_values(&[channel.as_str()])
+            .inc();
+
+        Ok(())
+    }
+
+    /// Sends another code to wherever the last one went.
+    pub async fn resend(&self, user_id: i64, purpose: Purpose) -> Result<(), OtpError> {
+        let challenge = self
+            .challenge(user_id, purpose)
+            .await?
+            .ok_or(OtpError::NoChallenge)?;
+
+        self.send_code(user_id, purpose, challenge.channel, &challenge.destination)
+            .await
+    }
+
+    /// Checks a code. It's used up if it's right, and counts as an attempt if it isn't.
+    pub async fn verify(
+        &self,
+        user_id: i64,
+        purpose: Purpose,
+        code: &str,
+    ) -> Result<Challenge, OtpError> {
+        let challenge = self
+            .challenge(user_id, purpose)
+            .await?
+            .ok_or(OtpError::NoChallenge)?;
+
+        if challenge.expires_at <= now() {
+            return Err(OtpError::Expired);
+        }
+
+        // Checked and counted in one go, so guessing lots at once doesn't get more tries
+        let max_attempts = self.config().max_attempts;
+        let counted = sqlx::query(
+            "UPDATE otp_challenge SET attempts = attempts + 1
+             WHERE user_id = ? AND purpose = ? AND attempts < ?",
+        )
+        .bind(user_id)
+        .bind(purpose)
+        .bind(max_attempts)
+        .execute(&self.pool)
+        .await?;
+        if counted.rows_affected() == 0 {
+            return Err(OtpError::TooManyAttempts);
+        }
+        let lockout = self.config().lockout();
+        let failures = lockout.attempt(&self.pool, user_id, purpose).await?;
+
+        let attempt = hash_code(&challenge.salt, code);
+        let matches = attempt.len() == challenge.code_hash.len()
+            && attempt
+                .bytes()
+                .zip(challenge.code_hash.bytes())
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0;
+
+        if !matches {
+            lockout.failed(&self.pool, user_id, purpose, failures).await?;
+
+            // Other guesses might have been counted since we read it
+            let attempts: Option<i64> = sqlx::query_scalar(
+                "SELECT attempts FROM otp_challenge WHERE user_id = ? AND purpose = ?",
+            )
+            .bind(user_id)
+            .bind(purpose)
+            .fetch_optional(&self.pool)
+            .await?;
+            let left = max_attempts - attempts.unwrap_or(max_attempts);
+            if left == 0 {
+                audit::record(
+                    &self.pool,
+                    Some(user_id),
+                    audit::SECOND_FACTOR_FAILED,
+                    "too many wrong codes",
+                )
+                .await;
+                return Err(OtpError::TooManyAttempts);
+            }
+            return Err(OtpError::WrongCode(left));
+        }
+
+        sqlx::query("DELETE FROM otp_challenge WHERE user_id = ? AND purpose = ?")
+            .bind(user_id)
+            .bind(purpose)
+            .execute(&self.pool)
+            .await?;
+        Lockout::succeeded(&self.pool, user_id, purpose).await?;
+
+        Ok(challenge)
+    }
+}
+
+const PENDING_LOGIN_KEY: &str = "pending_login";
+
+/// How long someone has between their password and their code
+const PENDING_LOGIN_SECONDS: i64 = 15 * 60;
+
+/// Someone who's got their password right but still owes us a second factor.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+struct PendingLogin {
+    user_id: i64,
+    started_at: i64,
+}
+
+/// Remembers that this session is halfway through logging in as `user_id`.
+pub async fn start_pending_login(session: &Session, user_id: i64) -> Result<(), session::Error> {
+    session
+        .insert(
+            PENDING_LOGIN_KEY,
+            PendingLogin {
+                user_id,
+                started_at: now(),
+            },
+        )
+        .await
+}
+
+/// Who this session is halfway through logging in as, if anyone.
+pub async fn pending_login(session: &Session) -> Result<Option<i64>, session::Error> {
+    Ok(session
+        .get::<PendingLogin>(PENDING_LOGIN_KEY)
+        .await?
+        .filter(|p| p.started_at + PENDING_LOGIN_SECONDS > now())
+        .map(|p| p.user_id))
+}
+
+pub async fn finish_pending_login(session: &Session) -> Result<(), session::Error> {
+    session.remove::<PendingLogin>(PENDING_LOGIN_KEY).await?;
+    Ok(())
+}
+
+/// Call once someone's got their first factor right. If they've got a second factor, this sends
+/// them a code and returns true, and they shouldn't be logged in yet.
+///
+/// Codes can't be sent if two factor has since been turned off in the config, but they can still
+/// get in with a recovery code.
+pub async fn begin_second_factor(
+    otp: Option<&Otp>,
+    session: &Session,
+    user: &User,
+) -> Result<bool, OtpError> {
+    let (Some(channel), Some(destination)) = (user.otp_channel, &user.otp_destination) else {
+        return Ok(false);
+    };
+
+    start_pending_login(session, user.id).await?;
+    if let Some(otp) = otp {
+        match otp
+            .send_code(user.id, Purpose::Login, channel, destination)
+            .await
+        {
+            // They just got a log in code, that'll do. Other purposes don't count towards this.
+            // If they're locked out, they'll be told so when they put a code in.
+            Ok(()) | Err(OtpError::TooSoon(_) | OtpError::LockedOut(_)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::NO_PASSWORD;
+    use crate::state::{test_pool, test_state};
+    use crate::store::{SqlxUserStore, UserStore};
+
+    async fn alice(pool: &SqlitePool) -> i64 {
+        SqlxUserStore::new(pool.clone())
+            .create("alice", NO_PASSWORD)
+            .await
+            .unwrap()
+            .id
+    }
+
+    /// Sends codes to a file nobody reads, and lets them be resent straight away.
+    async fn otp() -> Otp {
+        let path = std::env::temp_dir().join(format!("rust-auth-otp-{}", rand::random::<u64>()));
+        let state = test_state(&format!(
+            r#"
+            [two-factor]
+            max-attempts = 5
+            resend-interval-seconds = 0
+            lockout-after = 10
+            [two-factor.email]
+            sender = "file"
+            path = {:?}
+            "#,
+            path.display().to_string()
+        ))
+        .await;
+        state.otp.unwrap()
+    }
+
+    async fn guess(lockout: &Lockout, pool: &SqlitePool, user_id: i64) -> Result<(), OtpError> {
+        let failures = lockout.attempt(pool, user_id, Purpose::Login).await?;
+        lockout.failed(pool, user_id, Purpose::Login, failures).await
+    }
+
+    #[tokio::test]
+    async fn new_codes_dont_mean_more_guesses() {
+        let otp = otp().await;
+        let alice = alice(&otp.pool).await;
+
+        let send = || otp.send_code(alice, Purpose::Login, OtpChannel::Email, "a@example.org");
+        send().await.unwrap();
+        for left in (0..4).rev() {
+            assert!(matches!(otp.verify(alice, Purpose::Login, "nope").await, Err(OtpError::WrongCode(l)) if l == left + 1));
+        }
+        assert!(matches!(otp.verify(alice, Purpose::Login, "nope").await, Err(OtpError::TooManyAttempts)));
+
+        send().await.unwrap();
+        for _ in 0..4 {
+            assert!(matches!(otp.verify(alice, Purpose::Login, "nope").await, Err(OtpError::WrongCode(_))));
+        }
+        assert!(matches!(otp.verify(alice, Purpose::Login, "nope").await, Err(OtpError::LockedOut(_))));
+
+        // No more codes to guess at until they've waited
+        assert!(matches!(send().await, Err(OtpError::LockedOut(_))));
+        assert!(matches!(otp.verify(alice, Purpose::Login, "nope").await, Err(OtpError::TooManyAttempts)));
+        // Setting up two factor is counted separately
+        otp.send_code(alice, Purpose::Enroll, OtpChannel::Email, "a@example.org").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn locks_out_after_too_many_wrong_guesses() {
+        let pool = test_pool().await;
+        let alice = alice(&pool).await;
+        let lockout = Lockout { after: 3, minutes: 15 };
+
+        guess(&lockout, &pool, alice).await.unwrap();
+        guess(&lockout, &pool, alice).await.unwrap();
+        match guess(&lockout, &pool, alice).await {
+            Err(OtpError::LockedOut(seconds)) => assert!((14 * 60..=15 * 60).contains(&seconds)),
+            res => panic!("expected a lockout, got {res:?}"),
+        }
+
+        // Not even a right guess gets a look in
+        assert!(matches!(lockout.attempt(&pool, alice, Purpose::Login).await, Err(OtpError::LockedOut(_))));
+        assert!(matches!(Lockout::check(&pool, alice, Purpose::Login).await, Err(OtpError::LockedOut(_))));
+    }
+
+    #[tokio::test]
+    async fn lockouts_get_longer() {
+        let pool = test_pool().await;
+        let alice = alice(&pool).await;
+        let lockout = Lockout { after: 2, minutes: 15 };
+
+        let mut lengths = vec![];
+        for _ in 0..3 {
+            guess(&lockout, &pool, alice).await.unwrap();
+            match guess(&lockout, &pool, alice).await {
+                Err(OtpError::LockedOut(seconds)) => lengths.push((seconds + 59) / 60),
+                res => panic!("expected a lockout, got {res:?}"),
+            }
+            // Waited it out
+            sqlx::query("UPDATE otp_failure SET locked_until = unixepoch() - 1")
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        assert_eq!(lengths, [15, 30, 60]);
+    }
+
+    #[tokio::test]
+    async fn getting_one_right_starts_over() {
+        let pool = test_pool().await;
+        let alice = alice(&pool).await;
+        let lockout = Lockout { after: 3, minutes: 15 };
+
+        guess(&lockout, &pool, alice).await.unwrap();
+        guess(&lockout, &pool, alice).await.unwrap();
+        lockout.attempt(&pool, alice, Purpose::Login).await.unwrap();
+        Lockout::succeeded(&pool, alice, Purpose::Login).await.unwrap();
+
+        guess(&lockout, &pool, alice).await.unwrap();
+        guess(&lockout, &pool, alice).await.unwrap();
+        Lockout::check(&pool, alice, Purpose::Login).await.unwrap();
+    }
+}
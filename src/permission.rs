@@ -0,0 +1,54 @@
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Something a user can be allowed to do. Roles come with a set of these (see `Role::permissions`)
+/// and individual users and groups can be granted more.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(sqlx::Type))]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(feature = "ssr", sqlx(rename_all = "kebab-case"))]
+pub enum Permission {
+    CreateInvites,
+    ImportUsers,
+    /// Make groups, change who's in them and what they can do
+    ManageGroups,
+    /// Log in as someone else for a while, to see what they see
+    ImpersonateUsers,
+    /// See what's been sent to webhooks, retry it and send tests
+    ManageWebhooks,
+}
+
+impl Permission {
+    pub const ALL: &'static [Permission] = &[
+        Permission::CreateInvites,
+        Permission::ImportUsers,
+        Permission::ManageGroups,
+        Permission::ImpersonateUsers,
+        Permission::ManageWebhooks,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permission::CreateInvites => "create-invites",
+            Permission::ImportUsers => "import-users",
+            Permission::ManageGroups => "manage-groups",
+            Permission::ImpersonateUsers => "impersonate-users",
+            Permission::ManageWebhooks => "manage-webhooks",
+        }
+    }
+}
+
+impl FromStr for Permission {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Permission::ALL
+            .iter()
+            .copied()
+            .find(|p| p.as_str() == s)
+            .ok_or_else(|| {
+                let all: Vec<_> = Permission::ALL.iter().map(|p| p.as_str()).collect();
+                format!("Unknown permission {s:?}, expected one of {}", all.join(", "))
+            })
+    }
+}
@@ -0,0 +1,247 @@
+use leptos::*;
+use leptos_router::*;
+use serde::{Deserialize, Serialize};
+
+use crate::app::{FieldError, FormError};
+use crate::auth_error::{self, AuthError};
+
+/// Longer than this and it won't fit anywhere it's shown
+const MAX_DISPLAY_NAME: usize = 64;
+
+/// What the profile page shows
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProfileInfo {
+    pub username: String,
+    pub display_name: Option<String>,
+    /// Where to get it from, not the file name
+    pub avatar_url: Option<String>,
+    pub timezone: Option<String>,
+    pub locale: Option<String>,
+}
+
+/// `None` for empty, so clearing a field clears it in the database too
+#[cfg(feature = "ssr")]
+fn optional(value: &str) -> Option<&str> {
+    Some(value.trim()).filter(|value| !value.is_empty())
+}
+
+#[cfg(feature = "ssr")]
+fn check_display_name(name: Option<&str>) -> Result<(), AuthError> {
+    let Some(name) = name else {
+        return Ok(());
+    };
+    if name.chars().count() > MAX_DISPLAY_NAME {
+        return Err(AuthError::validation(
+            "display_name",
+            format!("Keep it to {MAX_DISPLAY_NAME} characters or less"),
+        ));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(AuthError::validation("display_name", "That has characters we can't show"));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "ssr")]
+fn check_timezone(timezone: Option<&str>) -> Result<(), AuthError> {
+    match timezone {
+        Some(timezone) if timezone.parse::<chrono_tz::Tz>().is_err() => Err(AuthError::validation(
+            "timezone",
+            "We don't know that timezone, use a name like Europe/London",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Loosely BCP 47: a 2 or 3 letter language, then any number of short subtags, like `en`,
+/// `pt-BR` or `zh-Hant-TW`.
+#[cfg(feature = "ssr")]
+fn check_locale(locale: Option<&str>) -> Result<(), AuthError> {
+    let Some(locale) = locale else {
+        return Ok(());
+    };
+
+    let mut subtags = locale.split('-');
+    let language = subtags.next().unwrap_or_default();
+    let valid = (2..=3).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_alphabetic())
+        && subtags.all(|subtag| {
+            (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+        });
+
+    if valid {
+        Ok(())
+    } else {
+        Err(AuthError::validation("locale", "Use a language tag like en-GB"))
+    }
+}
+
+/// `None` if they're not logged in
+#[server]
+async fn get_profile() -> Result<Option<ProfileInfo>, ServerFnError<AuthError>> {
+    use crate::account::require_user;
+    use crate::avatar;
+
+    let user = match require_user().await {
+        Ok(user) => user,
+        Err(AuthError::NotLoggedIn | AuthError::NeedsSecondFactor) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    Ok(Some(ProfileInfo {
+        username: user.username,
+        display_name: user.display_name,
+        avatar_url: user.avatar.as_deref().map(avatar::url),
+        timezone: user.timezone,
+        locale: user.locale,
+    }))
+}
+
+#[server(UpdateProfile)]
+async fn update_profile(
+    display_name: String,
+    timezone: String,
+    locale: String,
+) -> Result<(), ServerFnError<AuthError>> {
+    use crate::account::require_user;
+    use crate::state::AppState;
+
+    let user = require_user().await?;
+    let state = expect_context::<AppState>();
+
+    let (display_name, timezone, locale) =
+        (optional(&display_name), optional(&timezone), optional(&locale));
+    check_display_name(display_name)?;
+    check_timezone(timezone)?;
+    check_locale(locale)?;
+
+    state
+        .auth
+        .store
+        .set_profile(user.id, display_name, timezone, locale)
+        .await
+        .map_err(AuthError::from)?;
+    Ok(())
+}
+
+#[server(RemoveAvatar)]
+async fn remove_avatar() -> Result<(), ServerFnError<AuthError>> {
+    use crate::account::require_user;
+    use crate::avatar;
+    use crate::state::AppState;
+
+    let user = require_user().await?;
+    let state = expect_context::<AppState>();
+
+    let Some(name) = user.avatar else {
+        return Ok(());
+    };
+    state
+        .auth
+        .store
+        .set_avatar(user.id, None)
+        .await
+        .map_err(AuthError::from)?;
+    avatar::remove(&state.config.avatars, &name).await;
+    Ok(())
+}
+
+/// Uploads go to a plain handler (see `avatar::upload_handler`), which sends them back here with
+/// how it went in `?avatar=`.
+#[component]
+fn Avatar(url: Option<String>) -> impl IntoView {
+    let remove_action = create_server_action::<RemoveAvatar>();
+    let remove_ret = remove_action.value();
+
+    let query = use_query_map();
+    let upload_message = move || {
+        query.with(|q| match q.get("avatar").map(String::as_str) {
+            None => None,
+            Some("saved") => Some("Saved your new avatar."),
+            Some("too-big") => Some("That image is too big."),
+            Some("wrong-type") => Some("Avatars need to be PNG, JPEG, GIF or WebP."),
+            Some("unreadable") => Some("Couldn't read that image, it might be broken."),
+            Some("missing") => Some("Pick an image first."),
+            Some(_) => Some("Couldn't save your avatar, try again later."),
+        })
+    };
+
+    view! {
+        <h2>"Avatar"</h2>
+        {match url {
+            Some(url) => view! { <img class="avatar" src=url alt="Your avatar"/> }.into_view(),
+            None => view! { <p>"You haven't got one yet."</p> }.into_view(),
+        }}
+
+        <form class="credential-form" method="post" action="/account/avatar" enctype="multipart/form-data">
+            <input type="file" name="avatar" accept="image/png,image/jpeg,image/gif,image/webp"/>
+            <input type="submit" value="Upload"/>
+        </form>
+        <p>{upload_message}</p>
+
+        <ActionForm class="credential-form" action=remove_action>
+            <input type="submit" value="Remove avatar"/>
+        </ActionForm>
+        {move || match remove_ret.get() {
+            Some(Ok(())) => view! { <p>"Removed, it'll be gone next time you load the page."</p> }.into_view(),
+            Some(Err(err)) => view! { <p>{auth_error::message(&err)}</p> }.into_view(),
+            None => ().into_view(),
+        }}
+    }
+}
+
+#[component]
+fn ProfileForm(profile: ProfileInfo) -> impl IntoView {
+    let update_action = create_server_action::<UpdateProfile>();
+    let pending = update_action.pending();
+    let ret = update_action.value();
+
+    view! {
+        <h2>"Details"</h2>
+        <ActionForm class="credential-form" action=update_action>
+            <label for="display_name">Display name </label>
+            <input type="text" name="display_name" placeholder=profile.username
+                maxlength=MAX_DISPLAY_NAME value=profile.display_name.unwrap_or_default()/>
+            <FieldError ret field="display_name"/>
+
+            <label for="timezone">Timezone </label>
+            <input type="text" name="timezone" placeholder="Europe/London"
+                value=profile.timezone.unwrap_or_default()/>
+            <FieldError ret field="timezone"/>
+
+            <label for="locale">Language </label>
+            <input type="text" name="locale" placeholder="en-GB"
+                value=profile.locale.unwrap_or_default()/>
+            <FieldError ret field="locale"/>
+
+            <input type="submit" value="Save"/>
+        </ActionForm>
+
+        <p>{move || pending.get().then_some("Working... 🛌")}</p>
+        <p>{move || matches!(ret.get(), Some(Ok(()))).then_some("Saved!")}</p>
+        <FormError ret fields=&["display_name", "timezone", "locale"]/>
+    }
+}
+
+/// Where users change how they show up
+#[component]
+pub fn Profile() -> impl IntoView {
+    let profile = create_blocking_resource(|| (), |_| async { get_profile().await });
+
+    view! {
+        <h1>"Your profile"</h1>
+        <Suspense fallback=||()>
+        {move || profile.get().map(|profile| match profile {
+            Ok(Some(profile)) => view! {
+                <Avatar url=profile.avatar_url.clone()/>
+                <ProfileForm profile/>
+            }.into_view(),
+            Ok(None) => view! {
+                <p>"You need to " <A href="/login">"log in"</A> " first."</p>
+            }.into_view(),
+            Err(err) => view! { <p>{auth_error::message(&err)}</p> }.into_view(),
+        })}
+        </Suspense>
+        <A href="/account"> Back to your account </A>
+    }
+}
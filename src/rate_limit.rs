@@ -0,0 +1,30 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::otp::now;
+
+/// Counts requests per key over the last hour. Only kept in memory, so a restart forgets it.
+#[derive(Debug, Default)]
+pub struct RateLimit {
+    hits: Mutex<HashMap<String, Vec<i64>>>,
+}
+
+impl RateLimit {
+    /// Counts a request for `key`, unless it's had `max` in the last hour already. If it has,
+    /// gives back how many seconds until it can have another.
+    pub fn hit(&self, key: &str, max: usize) -> Result<(), i64> {
+        let now = now();
+        let mut hits = self.hits.lock().expect("rate limit lock shouldn't be poisoned");
+        hits.retain(|_, times| {
+            times.retain(|&time| time > now - 3600);
+            !times.is_empty()
+        });
+
+        let times = hits.entry(key.to_owned()).or_default();
+        if times.len() >= max {
+            return Err(times[0] + 3600 - now);
+        }
+        times.push(now);
+        Ok(())
+    }
+}
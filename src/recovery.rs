@@ -0,0 +1,182 @@
+use rand::seq::SliceRandom;
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+
+use crate::audit;
+use crate::otp::{Lockout, OtpError, Purpose};
+
+/// How many codes everyone gets
+pub const CODE_COUNT: usize = 10;
+
+/// No 0/o, 1/l/i, so they survive being written down.
+const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const HALF_LEN: usize = 5;
+
+/// Codes are random enough (~50 bits) that a plain hash is fine, no need for bcrypt here.
+fn hash(code: &str) -> String {
+    // People will type them in however they like
+    let normalised: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    Sha256::digest(normalised.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn generate_code() -> String {
+    let mut rng = rand::thread_rng();
+    let mut half = || -> String {
+        (0..HALF_LEN)
+            .map(|_| *ALPHABET.choose(&mut rng).expect("alphabet isn't empty") as char)
+            .collect()
+    };
+
+    format!("{}-{}", half(), half())
+}
+
+/// Throws away any codes the user had and gives them a fresh set. This is the only time the codes
+/// are ever seen in plain text.
+pub async fn regenerate(pool: &SqlitePool, user_id: i64) -> Result<Vec<String>, sqlx::Error> {
+    let codes: Vec<String> = (0..CODE_COUNT).map(|_| generate_code()).collect();
+
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM recovery_code WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    for code in &codes {
+        sqlx::query("INSERT INTO recovery_code (user_id, code_hash) VALUES (?, ?)")
+            .bind(user_id)
+            .bind(hash(code))
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    audit::record(pool, Some(user_id), audit::RECOVERY_CODES_GENERATED, "").await;
+    Ok(codes)
+}
+
+/// Uses up one of the user's codes. Returns whether it was any good. Goes through [`redeem`] so
+/// wrong ones are counted.
+async fn consume(pool: &SqlitePool, user_id: i64, code: &str) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query(
+        "UPDATE recovery_code SET used_at = unixepoch()
+         WHERE user_id = ? AND code_hash = ? AND used_at IS NULL",
+    )
+    .bind(user_id)
+    .bind(hash(code))
+    .execute(pool)
+    .await?;
+
+    let used = res.rows_affected() == 1;
+    if used {
+        let left = remaining(pool, user_id).await?;
+        audit::record(
+            pool,
+            Some(user_id),
+            audit::RECOVERY_CODE_USED,
+            &format!("{left} left"),
+        )
+        .await;
+    }
+
+    Ok(used)
+}
+
+/// Uses up one of the user's codes to log in. Wrong ones count towards the same lockout as
+/// wrong log in codes, so they can't be guessed at forever either.
+pub async fn redeem(
+    pool: &SqlitePool,
+    lockout: &Lockout,
+    user_id: i64,
+    code: &str,
+) -> Result<(), OtpError> {
+    let failures = lockout.attempt(pool, user_id, Purpose::Login).await?;
+    if !consume(pool, user_id, code).await? {
+        lockout.failed(pool, user_id, Purpose::Login, failures).await?;
+        return Err(OtpError::WrongRecoveryCode);
+    }
+
+    Lockout::succeeded(pool, user_id, Purpose::Login).await
+}
+
+/// How many unused codes the user has.
+pub async fn remaining(pool: &SqlitePool, user_id: i64) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar("SELECT COUNT(*) FROM recovery_code WHERE user_id = ? AND used_at IS NULL")
+        .bind(user_id)
+        .fetch_one(pool)
+        .await
+}
+
+/// Gets rid of all the user's codes, e.g. when they turn their second factor off.
+pub async fn clear(pool: &SqlitePool, user_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM recovery_code WHERE user_id = ?")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::NO_PASSWORD;
+    use crate::state::test_pool;
+    use crate::store::{SqlxUserStore, UserStore};
+
+    async fn alice(pool: &SqlitePool) -> i64 {
+        SqlxUserStore::new(pool.clone())
+            .create("alice", NO_PASSWORD)
+            .await
+            .unwrap()
+            .id
+    }
+
+    #[tokio::test]
+    async fn codes_work_once() {
+        let pool = test_pool().await;
+        let alice = alice(&pool).await;
+        let codes = regenerate(&pool, alice).await.unwrap();
+        assert_eq!(codes.len(), CODE_COUNT);
+
+        // However it's typed in
+        let code = codes[0].to_uppercase().replace('-', " ");
+        redeem(&pool, &Lockout::default(), alice, &code).await.unwrap();
+        assert!(matches!(
+            redeem(&pool, &Lockout::default(), alice, &codes[0]).await,
+            Err(OtpError::WrongRecoveryCode)
+        ));
+        assert_eq!(remaining(&pool, alice).await.unwrap(), CODE_COUNT as i64 - 1);
+    }
+
+    #[tokio::test]
+    async fn guessing_stops_after_the_limit() {
+        let pool = test_pool().await;
+        let alice = alice(&pool).await;
+        let codes = regenerate(&pool, alice).await.unwrap();
+        let lockout = Lockout { after: 5, minutes: 15 };
+
+        for _ in 0..4 {
+            assert!(matches!(
+                redeem(&pool, &lockout, alice, "aaaaa-aaaaa").await,
+                Err(OtpError::WrongRecoveryCode)
+            ));
+        }
+        assert!(matches!(
+            redeem(&pool, &lockout, alice, "aaaaa-aaaaa").await,
+            Err(OtpError::LockedOut(_))
+        ));
+
+        // Even the right code's turned away now, and isn't used up
+        assert!(matches!(
+            redeem(&pool, &lockout, alice, &codes[0]).await,
+            Err(OtpError::LockedOut(_))
+        ));
+        assert_eq!(remaining(&pool, alice).await.unwrap(), CODE_COUNT as i64);
+    }
+}
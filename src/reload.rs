@@ -0,0 +1,260 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::config;
+use crate::logging;
+use crate::state::{AppState, Config};
+
+/// How often to check whether the config file's changed
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A value that can be swapped for a new one while everything's running. Clones all see the same
+/// one, and whoever's reading gets a snapshot that won't change under them.
+#[derive(Debug)]
+pub struct Live<T>(Arc<RwLock<Arc<T>>>);
+
+impl<T> Clone for Live<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Live<T> {
+    pub fn new(value: T) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(value))))
+    }
+
+    pub fn get(&self) -> Arc<T> {
+        self.0.read().expect("live value lock shouldn't be poisoned").clone()
+    }
+
+    pub fn set(&self, value: T) {
+        *self.0.write().expect("live value lock shouldn't be poisoned") = Arc::new(value);
+    }
+}
+
+type Setting = (&'static str, fn(&Config) -> String);
+
+/// What can change without a restart. Anything that reads these should go through
+/// [`AppState::live`](crate::state::AppState) rather than `config`.
+const RELOADABLE: &[Setting] = &[
+    ("auth.registration", |c| format!("{:?}", c.auth.registration)),
+    ("auth.impersonation-minutes", |c| c.auth.impersonation_minutes.to_string()),
+    ("logging.level", |c| c.logging.level.clone()),
+    ("security-headers", |c| format!("{:?}", c.security_headers)),
+    ("server.trusted-origins", |c| format!("{:?}", c.server.trusted_origins)),
+    ("server.trusted-proxies", |c| format!("{:?}", c.server.trusted_proxies)),
+    // Secrets aren't in the Debug output, so changing just one isn't logged, but it still applies
+    ("webhooks", |c| format!("{:?}", c.webhooks)),
+    ("magic-link.max-per-address", |c| {
+        format!("{:?}", c.magic_link.as_ref().map(|m| m.max_per_address))
+    }),
+    ("magic-link.max-per-ip", |c| format!("{:?}", c.magic_link.as_ref().map(|m| m.max_per_ip))),
+    ("two-factor.code-ttl-minutes", |c| {
+        format!("{:?}", c.two_factor.as_ref().map(|t| t.code_ttl_minutes))
+    }),
+    ("two-factor.max-attempts", |c| {
+        format!("{:?}", c.two_factor.as_ref().map(|t| t.max_attempts))
+    }),
+    ("two-factor.resend-interval-seconds", |c| {
+        format!("{:?}", c.two_factor.as_ref().map(|t| t.resend_interval_seconds))
+    }),
+    ("two-factor.lockout-after", |c| {
+        format!("{:?}", c.two_factor.as_ref().map(|t| t.lockout_after))
+    }),
+    ("two-factor.lockout-minutes", |c| {
+        format!("{:?}", c.two_factor.as_ref().map(|t| t.lockout_minutes))
+    }),
+];
+
+/// Everything else. Only compared so we can say a restart's needed, and never logged since some
+/// of it's secret.
+const FIXED: &[Setting] = &[
+    ("database", |c| format!("{:?}", c.database)),
+    ("leptos", |c| format!("{:?}", c.leptos)),
+    ("server.public-url", |c| format!("{:?}", c.server.public_url)),
+    ("server.drain-timeout-seconds", |c| c.server.drain_timeout_seconds.to_string()),
+    ("auth.backends", |c| format!("{:?}", c.auth.backends)),
+    ("ldap", |c| format!("{:?}", c.ldap)),
+    ("htpasswd", |c| format!("{:?}", c.htpasswd)),
+    ("mail", |c| format!("{:?}", c.mail)),
+    ("magic-link.signing-key", |c| {
+        format!("{:?}", c.magic_link.as_ref().map(|m| &m.signing_key))
+    }),
+    ("magic-link.ttl-minutes", |c| format!("{:?}", c.magic_link.as_ref().map(|m| m.ttl_minutes))),
+    ("two-factor.email", |c| format!("{:?}", c.two_factor.as_ref().map(|t| &t.email))),
+    ("two-factor.sms", |c| format!("{:?}", c.two_factor.as_ref().map(|t| &t.sms))),
+    ("tls", |c| format!("{:?}", c.tls)),
+    ("logging.format", |c| format!("{:?}", c.logging.format)),
+    ("metrics", |c| format!("{:?}", c.metrics)),
+    ("avatars", |c| format!("{:?}", c.avatars)),
+];
+
+/// The current config with the reloadable parts of `new` in it.
+fn merge(current: &Config, new: &Config) -> Config {
+    let mut merged = current.clone();
+    merged.auth.registration = new.auth.registration;
+    merged.auth.impersonation_minutes = new.auth.impersonation_minutes;
+    merged.logging.level = new.logging.level.clone();
+    merged.security_headers = new.security_headers.clone();
+    merged.server.trusted_origins = new.server.trusted_origins.clone();
+    merged.server.trusted_proxies = new.server.trusted_proxies.clone();
+    merged.webhooks = new.webhooks.clone();
+    if let (Some(merged), Some(new)) = (&mut merged.magic_link, &new.magic_link) {
+        merged.max_per_address = new.max_per_address;
+        merged.max_per_ip = new.max_per_ip;
+    }
+    if let (Some(merged), Some(new)) = (&mut merged.two_factor, &new.two_factor) {
+        merged.code_ttl_minutes = new.code_ttl_minutes;
+        merged.max_attempts = new.max_attempts;
+        merged.resend_interval_seconds = new.resend_interval_seconds;
+        merged.lockout_after = new.lockout_after;
+        merged.lockout_minutes = new.lockout_minutes;
+    }
+    merged
+}
+
+/// Reads the config again and swaps in whatever can be changed while we're running. If it
+/// doesn't load or isn't valid, nothing changes.
+pub fn reload(state: &AppState, path: Option<&Path>) -> Result<(), String> {
+    let new = config::load(path)?;
+    let current = state.live.get();
+    let merged = merge(&current, &new);
+
+    for (setting, value) in FIXED {
+        if value(&current) != value(&new) {
+            tracing::warn!(setting, "Changed in the config, but needs a restart to take effect");
+        }
+    }
+
+    // The only things that can still be wrong, so check them before saying anything's changed
+    let level_changed = merged.logging.level != current.logging.level;
+    if level_changed {
+        logging::check_level(&merged.logging.level)?;
+    }
+    for webhook in &merged.webhooks {
+        webhook.validate()?;
+    }
+
+    let mut changed = false;
+    for (setting, value) in RELOADABLE {
+        let (from, to) = (value(&current), value(&merged));
+        if from != to {
+            tracing::info!(setting, from, to, "Config changed");
+            changed = true;
+        }
+    }
+    // Not in `RELOADABLE` since they can't be logged
+    let secrets = |c: &Config| c.webhooks.iter().map(|w| w.secret.clone()).collect::<Vec<_>>();
+    if secrets(&current) != secrets(&merged) {
+        tracing::info!(setting = "webhooks.secret", "Config changed");
+        changed = true;
+    }
+    if !changed {
+        tracing::info!("Reloaded config, nothing that can be changed while running has");
+        return Ok(());
+    }
+
+    if level_changed {
+        logging::set_level(&merged.logging.level)?;
+    }
+    // Two factor and sign in links read their limits from here too, so it all changes at once
+    state.live.set(merged);
+    Ok(())
+}
+
+/// Reloads the config whenever the file changes or we get a SIGHUP.
+pub fn watch(state: AppState, path: Option<PathBuf>) {
+    tokio::spawn(async move {
+        let file = config::path(path.as_deref());
+        let modified = || std::fs::metadata(&file).and_then(|m| m.modified()).ok();
+
+        #[cfg(unix)]
+        let mut hangup = {
+            use tokio::signal::unix::{signal, SignalKind};
+            match signal(SignalKind::hangup()) {
+                Ok(hangup) => Some(hangup),
+                Err(err) => {
+                    tracing::error!(%err, "Couldn't listen for SIGHUP");
+                    None
+                }
+            }
+        };
+
+        let mut last = modified();
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        loop {
+            #[cfg(unix)]
+            let hangup = async {
+                match &mut hangup {
+                    Some(hangup) => hangup.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+            #[cfg(not(unix))]
+            let hangup = std::future::pending::<Option<()>>();
+
+            let trigger = tokio::select! {
+                _ = interval.tick() => {
+                    let now = modified();
+                    if now.is_none() || now == last {
+                        continue;
+                    }
+                    // Even if it doesn't load, there's no point trying again until it changes
+                    last = now;
+                    "file changed"
+                }
+                _ = hangup => "SIGHUP",
+            };
+
+            tracing::info!(trigger, path = %file.display(), "Reloading config");
+            if let Err(err) = reload(&state, path.as_deref()) {
+                tracing::error!(%err, "Couldn't reload config, keeping the old one");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_state;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const CONFIG: &str = r#"
+        [mail]
+        from = "Rust Auth <noreply@example.org>"
+        transport = "file"
+        dir = "/tmp/rust-auth-mail"
+
+        [magic-link]
+        signing-key = "not a very secret key, but long enough"
+        max-per-ip = 1
+
+        [two-factor]
+        max-attempts = 5
+        [two-factor.email]
+        sender = "mail"
+        "#;
+
+    #[tokio::test]
+    async fn limits_change_without_a_restart() {
+        let state = test_state(CONFIG).await;
+        let links = state.magic_links.clone().unwrap();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        links.allow_request(&state.live.get(), "a@example.org", ip).unwrap();
+        assert!(links.allow_request(&state.live.get(), "b@example.org", ip).is_err());
+
+        let path = std::env::temp_dir().join(format!("rust-auth-{}.toml", rand::random::<u64>()));
+        let changed = CONFIG
+            .replace("max-per-ip = 1", "max-per-ip = 2")
+            .replace("max-attempts = 5", "max-attempts = 3");
+        std::fs::write(&path, format!("[database]\nurl = \"sqlite::memory:\"\n{changed}")).unwrap();
+        reload(&state, Some(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        links.allow_request(&state.live.get(), "b@example.org", ip).unwrap();
+        assert_eq!(state.otp.as_ref().unwrap().config().max_attempts, 3);
+    }
+}
@@ -0,0 +1,239 @@
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::http::{HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use leptos::nonce::Nonce;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::state::AppState;
+
+/// `{nonce}` gets swapped for a fresh nonce on every response, which Leptos also puts on its
+/// hydration scripts. WebAssembly needs `'wasm-unsafe-eval'` to start.
+fn default_content_security_policy() -> Option<String> {
+    Some(
+        "default-src 'self'; script-src 'self' 'nonce-{nonce}' 'wasm-unsafe-eval'; \
+         style-src 'self' 'nonce-{nonce}'; img-src 'self' data:; object-src 'none'; \
+         base-uri 'none'; form-action 'self'; frame-ancestors 'none'"
+            .to_owned(),
+    )
+}
+
+fn default_hsts_max_age_seconds() -> Option<u64> {
+    // A year
+    Some(365 * 24 * 60 * 60)
+}
+
+fn default_frame_options() -> Option<String> {
+    Some("DENY".to_owned())
+}
+
+fn default_referrer_policy() -> Option<String> {
+    Some("strict-origin-when-cross-origin".to_owned())
+}
+
+fn default_permissions_policy() -> Option<String> {
+    Some("camera=(), microphone=(), geolocation=(), payment=(), usb=()".to_owned())
+}
+
+/// Headers added to every response. Leaving one out of the config keeps the default, setting it
+/// to `""` stops it being sent.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct SecurityHeadersConfig {
+    #[serde(default = "default_content_security_policy")]
+    pub content_security_policy: Option<String>,
+
+    /// Only sent when `server.public-url` is https, browsers ignore it otherwise. 0 turns it off.
+    #[serde(default = "default_hsts_max_age_seconds")]
+    pub hsts_max_age_seconds: Option<u64>,
+
+    #[serde(default = "default_frame_options")]
+    pub frame_options: Option<String>,
+
+    #[serde(default = "default_referrer_policy")]
+    pub referrer_policy: Option<String>,
+
+    #[serde(default = "default_permissions_policy")]
+    pub permissions_policy: Option<String>,
+
+    /// Different headers for paths starting with the key, e.g. `"/api"`. The longest match wins.
+    #[serde(default)]
+    pub routes: HashMap<String, RouteOverrides>,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        Self {
+            content_security_policy: default_content_security_policy(),
+            hsts_max_age_seconds: default_hsts_max_age_seconds(),
+            frame_options: default_frame_options(),
+            referrer_policy: default_referrer_policy(),
+            permissions_policy: default_permissions_policy(),
+            routes: HashMap::new(),
+        }
+    }
+}
+
+/// Anything left out falls back to the top level setting.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct RouteOverrides {
+    pub content_security_policy: Option<String>,
+    pub frame_options: Option<String>,
+    pub referrer_policy: Option<String>,
+    pub permissions_policy: Option<String>,
+}
+
+impl SecurityHeadersConfig {
+    /// The headers for `path`, before the nonce goes in. Empty values are dropped later.
+    fn for_path(&self, path: &str) -> [(&'static str, Option<String>); 4] {
+        let overrides = self
+            .routes
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, overrides)| overrides.clone())
+            .unwrap_or_default();
+
+        [
+            (
+                "content-security-policy",
+                overrides
+                    .content_security_policy
+                    .or_else(|| self.content_security_policy.clone()),
+            ),
+            (
+                "x-frame-options",
+                overrides.frame_options.or_else(|| self.frame_options.clone()),
+            ),
+            (
+                "referrer-policy",
+                overrides.referrer_policy.or_else(|| self.referrer_policy.clone()),
+            ),
+            (
+                "permissions-policy",
+                overrides
+                    .permissions_policy
+                    .or_else(|| self.permissions_policy.clone()),
+            ),
+        ]
+    }
+}
+
+/// Makes a nonce for the request, which the handlers that render pages pick up from the request
+/// extensions, then adds the headers to whatever comes back. Headers a handler already set are
+/// left alone.
+pub async fn layer(State(state): State<AppState>, mut request: Request<Body>, next: Next) -> Response {
+    let nonce = Nonce::new();
+    request.extensions_mut().insert(nonce.clone());
+    let path = request.uri().path().to_owned();
+
+    let mut response = next.run(request).await;
+
+    let live = state.live.get();
+    let config = &live.security_headers;
+    let headers = response.headers_mut();
+    for (name, value) in config.for_path(&path) {
+        let Some(value) = value.filter(|v| !v.is_empty()) else {
+            continue;
+        };
+        let name = HeaderName::from_static(name);
+        if headers.contains_key(&name) {
+            continue;
+        }
+
+        match HeaderValue::from_str(&value.replace("{nonce}", &nonce)) {
+            Ok(value) => {
+                headers.insert(name, value);
+            }
+            Err(err) => tracing::error!(%err, %name, "Bad security header value"),
+        }
+    }
+
+    let max_age = config.hsts_max_age_seconds.unwrap_or(0);
+    if max_age > 0 && state.config.public_url().starts_with("https://") {
+        headers
+            .entry("strict-transport-security")
+            .or_insert_with(|| {
+                HeaderValue::from_str(&format!("max-age={max_age}; includeSubDomains"))
+                    .expect("a number makes a fine header")
+            });
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_state;
+    use axum::middleware::from_fn_with_state;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    fn overrides(frame_options: &str) -> RouteOverrides {
+        RouteOverrides {
+            frame_options: Some(frame_options.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    fn header<'a>(headers: &'a [(&'static str, Option<String>); 4], name: &str) -> Option<&'a str> {
+        headers.iter().find(|(n, _)| *n == name).and_then(|(_, v)| v.as_deref())
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let mut config = SecurityHeadersConfig::default();
+        config.routes.insert("/api".to_owned(), overrides("SAMEORIGIN"));
+        config.routes.insert("/api/embed".to_owned(), overrides(""));
+
+        assert_eq!(header(&config.for_path("/"), "x-frame-options"), Some("DENY"));
+        assert_eq!(header(&config.for_path("/api/login"), "x-frame-options"), Some("SAMEORIGIN"));
+        assert_eq!(header(&config.for_path("/api/embed/x"), "x-frame-options"), Some(""));
+        // Anything the route doesn't mention comes from the top level
+        assert_eq!(
+            header(&config.for_path("/api/login"), "referrer-policy"),
+            Some("strict-origin-when-cross-origin")
+        );
+    }
+
+    #[tokio::test]
+    async fn every_response_gets_its_own_nonce() {
+        let state = test_state(
+            r#"
+            [security-headers.routes."/quiet"]
+            referrer-policy = ""
+            "#,
+        )
+        .await;
+        let app = Router::new()
+            .route("/", get(|| async { "hi" }))
+            .route("/quiet", get(|| async { "hi" }))
+            .route("/framed", get(|| async { ([("x-frame-options", "SAMEORIGIN")], "hi") }))
+            .layer(from_fn_with_state(state.clone(), layer))
+            .with_state(state);
+        let get = |path| {
+            let app = app.clone();
+            async move {
+                let request = Request::get(path).body(Body::empty()).unwrap();
+                app.oneshot(request).await.unwrap().headers().clone()
+            }
+        };
+
+        let policy = |headers: &axum::http::HeaderMap| {
+            headers["content-security-policy"].to_str().unwrap().to_owned()
+        };
+        let (first, second) = (policy(&get("/").await), policy(&get("/").await));
+        assert!(!first.contains("{nonce}"));
+        assert!(first.contains("'nonce-"));
+        assert_ne!(first, second);
+
+        // Blank means not at all, and handlers get the last word
+        assert!(!get("/quiet").await.contains_key("referrer-policy"));
+        assert_eq!(get("/framed").await["x-frame-options"], "SAMEORIGIN");
+    }
+}
@@ -0,0 +1,110 @@
+use std::future::IntoFuture;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::state::AppState;
+
+/// Resolves once we've been asked to stop, by Ctrl+C or SIGTERM.
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            tracing::error!(%err, "Couldn't listen for Ctrl+C");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(err) => {
+                tracing::error!(%err, "Couldn't listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// Tells the servers when to stop, and when everything has to be done by. There's one deadline
+/// for all of it, so finishing requests and then background tasks can't take twice as long.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    pub stop: CancellationToken,
+    timeout: Duration,
+    deadline: Arc<OnceLock<Instant>>,
+}
+
+impl Shutdown {
+    /// Stops once [`signal`] goes off, giving everything `timeout` from then.
+    pub fn on_signal(timeout: Duration) -> Self {
+        let shutdown = Self {
+            stop: CancellationToken::new(),
+            timeout,
+            deadline: Default::default(),
+        };
+
+        let started = shutdown.clone();
+        tokio::spawn(async move {
+            signal().await;
+            tracing::info!("Shutting down, finishing requests that are already going");
+            started.deadline();
+            started.stop.cancel();
+        });
+        shutdown
+    }
+
+    /// Starts counting from the first time it's asked for, if a server stopped by itself.
+    fn deadline(&self) -> Instant {
+        *self.deadline.get_or_init(|| Instant::now() + self.timeout)
+    }
+
+    /// Runs a server that's been told to shut down gracefully on `stop`, but only lets the
+    /// requests it's in the middle of go on until the deadline once that happens. Otherwise one
+    /// stuck connection would keep us around forever.
+    pub async fn drain<E>(&self, server: impl IntoFuture<Output = Result<(), E>>) -> Result<(), E> {
+        let server = server.into_future();
+        tokio::pin!(server);
+
+        tokio::select! {
+            res = &mut server => return res,
+            _ = self.stop.cancelled() => {}
+        }
+
+        match tokio::time::timeout_at(self.deadline(), server).await {
+            Ok(res) => res,
+            Err(_) => {
+                tracing::warn!("Gave up waiting for requests after {}s", self.timeout.as_secs());
+                Ok(())
+            }
+        }
+    }
+
+    /// Once the server's stopped, lets anything still going in the background (like emails being
+    /// sent, or the other listeners) finish, then closes the pool so SQLite gets everything
+    /// written out.
+    pub async fn finish(&self, state: &AppState) {
+        state.tasks.close();
+        if tokio::time::timeout_at(self.deadline(), state.tasks.wait()).await.is_err() {
+            tracing::warn!(
+                tasks = state.tasks.len(),
+                "Gave up waiting for background tasks after {}s",
+                self.timeout.as_secs()
+            );
+        }
+
+        state.pool.close().await;
+        tracing::info!("Shut down");
+    }
+}
@@ -0,0 +1,495 @@
+use axum::async_trait;
+use sqlx::SqlitePool;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::sync::Mutex;
+use thiserror::Error;
+
+use crate::auth::{AccountStatus, Role, User};
+use crate::group;
+use crate::metrics::time_query;
+use crate::otp::OtpChannel;
+use crate::permission::Permission;
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("User already exists")]
+    UsernameTaken,
+
+    #[error("Email address is already in use")]
+    EmailTaken,
+
+    #[error("No user with id {0}")]
+    NoSuchUser(i64),
+}
+
+/// Somewhere to keep users. The auth backend only ever talks to one of these, so it doesn't care
+/// whether they live in SQLite or in a HashMap.
+#[async_trait]
+pub trait UserStore: Debug + Send + Sync {
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>, StoreError>;
+
+    async fn find_by_id(&self, id: i64) -> Result<Option<User>, StoreError>;
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, StoreError>;
+
+    /// Fails with [`StoreError::UsernameTaken`] if the username is already in use.
+    async fn create(&self, username: &str, pw_hash: &str) -> Result<User, StoreError> {
+        self.create_with_email(username, pw_hash, None).await
+    }
+
+    /// Same, but also fails with [`StoreError::EmailTaken`] if someone has that address. It goes
+    /// in with everything else, so nobody can take it in between.
+    async fn create_with_email(
+        &self,
+        username: &str,
+        pw_hash: &str,
+        email: Option<&str>,
+    ) -> Result<User, StoreError>;
+
+    async fn update_password(&self, id: i64, pw_hash: &str) -> Result<(), StoreError>;
+
+    /// `locked_until` only counts for [`AccountStatus::Locked`], and `reason` is shown to them.
+    async fn set_status(
+        &self,
+        id: i64,
+        status: AccountStatus,
+        locked_until: Option<i64>,
+        reason: Option<&str>,
+    ) -> Result<(), StoreError>;
+
+    async fn set_role(&self, id: i64, role: Role) -> Result<(), StoreError>;
+
+    async fn set_auth_source(&self, id: i64, source: &str) -> Result<(), StoreError>;
+
+    async fn set_email(&self, id: i64, email: Option<&str>) -> Result<(), StoreError>;
+
+    /// Everything on the profile page except the avatar. `None` clears them.
+    async fn set_profile(
+        &self,
+        id: i64,
+        display_name: Option<&str>,
+        timezone: Option<&str>,
+        locale: Option<&str>,
+    ) -> Result<(), StoreError>;
+
+    async fn set_avatar(&self, id: i64, avatar: Option<&str>) -> Result<(), StoreError>;
+
+    /// `None` turns their second factor off
+    async fn set_second_factor(
+        &self,
+        id: i64,
+        factor: Option<(OtpChannel, &str)>,
+    ) -> Result<(), StoreError>;
+
+    /// Only what they've been granted, not what comes with their role
+    async fn permissions(&self, id: i64) -> Result<Vec<Permission>, StoreError>;
+
+    async fn grant(&self, id: i64, permission: Permission) -> Result<(), StoreError>;
+
+    async fn revoke(&self, id: i64, permission: Permission) -> Result<(), StoreError>;
+
+    /// What they get from the groups they're in, and the groups those are in
+    async fn group_permissions(&self, id: i64) -> Result<Vec<Permission>, StoreError>;
+}
+
+/// The real deal. Keeps users in the `user` table.
+#[derive(Debug, Clone)]
+pub struct SqlxUserStore {
+    pub pool: SqlitePool,
+}
+
+impl SqlxUserStore {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+}
+
+/// Turns "nothing changed" into an error so callers find out they've got the wrong id.
+fn expect_one_row(id: i64, rows_affected: u64) -> Result<(), StoreError> {
+    if rows_affected == 0 {
+        Err(StoreError::NoSuchUser(id))
+    } else {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl UserStore for SqlxUserStore {
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>, StoreError> {
+        let query = sqlx::query_as("SELECT * FROM user WHERE username = ?")
+            .bind(username)
+            .fetch_optional(&self.pool);
+        Ok(time_query("find_by_username", query).await?)
+    }
+
+    async fn find_by_id(&self, id: i64) -> Result<Option<User>, StoreError> {
+        // Happens on every request with a session, so it's the one to keep an eye on
+        let query = sqlx::query_as("SELECT * FROM user WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool);
+        Ok(time_query("find_by_id", query).await?)
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, StoreError> {
+        let query = sqlx::query_as("SELECT * FROM user WHERE email = ?")
+            .bind(email)
+            .fetch_optional(&self.pool);
+        Ok(time_query("find_by_email", query).await?)
+    }
+
+    async fn create_with_email(
+        &self,
+        username: &str,
+        pw_hash: &str,
+        email: Option<&str>,
+    ) -> Result<User, StoreError> {
+        // NOTE: Not using RETURNING here, sqlite doesn't commit until the statement's been
+        // stepped to the end and fetch_one stops after the first row.
+        let res = sqlx::query("INSERT INTO user (username, password_hash, email) VALUES (?, ?, ?)")
+            .bind(username)
+            .bind(pw_hash)
+            .bind(email)
+            .execute(&self.pool)
+            .await;
+
+        match res {
+            Ok(res) => {
+                let id = res.last_insert_rowid();
+                self.find_by_id(id).await?.ok_or(StoreError::NoSuchUser(id))
+            }
+            // The UNIQUE constraints do the checking for us. SQLite only says which one in the
+            // message, e.g. "UNIQUE constraint failed: user.email".
+            Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+                if err.message().contains("user.email") {
+                    Err(StoreError::EmailTaken)
+                } else {
+                    Err(StoreError::UsernameTaken)
+                }
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn update_password(&self, id: i64, pw_hash: &str) -> Result<(), StoreError> {
+        let res = sqlx::query("UPDATE user SET password_hash = ? WHERE id = ?")
+            .bind(pw_hash)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        expect_one_row(id, res.rows_affected())
+    }
+
+    async fn set_status(
+        &self,
+        id: i64,
+        status: AccountStatus,
+        locked_until: Option<i64>,
+        reason: Option<&str>,
+    ) -> Result<(), StoreError> {
+        let res = sqlx::query(
+            "UPDATE user SET status = ?, locked_until = ?, status_reason = ? WHERE id = ?",
+        )
+        .bind(status)
+        .bind(locked_until)
+        .bind(reason)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+
+        expect_one_row(id, res.rows_affected())
+    }
+
+    async fn set_role(&self, id: i64, role: Role) -> Result<(), StoreError> {
+        let res = sqlx::query("UPDATE user SET role = ? WHERE id = ?")
+            .bind(role)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        expect_one_row(id, res.rows_affected())
+    }
+
+    async fn set_auth_source(&self, id: i64, source: &str) -> Result<(), StoreError> {
+        let res = sqlx::query("UPDATE user SET auth_source = ? WHERE id = ?")
+            .bind(source)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        expect_one_row(id, res.rows_affected())
+    }
+
+    async fn set_email(&self, id: i64, email: Option<&str>) -> Result<(), StoreError> {
+        let res = sqlx::query("UPDATE user SET email = ? WHERE id = ?")
+            .bind(email)
+            .bind(id)
+            .execute(&self.pool)
+            .await;
+
+        match res {
+            Ok(res) => expect_one_row(id, res.rows_affected()),
+            Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+                Err(StoreError::EmailTaken)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn set_profile(
+        &self,
+        id: i64,
+        display_name: Option<&str>,
+        timezone: Option<&str>,
+        locale: Option<&str>,
+    ) -> Result<(), StoreError> {
+        let res =
+            sqlx::query("UPDATE user SET display_name = ?, timezone = ?, locale = ? WHERE id = ?")
+                .bind(display_name)
+                .bind(timezone)
+                .bind(locale)
+                .bind(id)
+                .execute(&self.pool)
+                .await?;
+
+        expect_one_row(id, res.rows_affected())
+    }
+
+    async fn set_avatar(&self, id: i64, avatar: Option<&str>) -> Result<(), StoreError> {
+        let res = sqlx::query("UPDATE user SET avatar = ? WHERE id = ?")
+            .bind(avatar)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        expect_one_row(id, res.rows_affected())
+    }
+
+    async fn set_second_factor(
+        &self,
+        id: i64,
+        factor: Option<(OtpChannel, &str)>,
+    ) -> Result<(), StoreError> {
+        let (channel, destination) = factor.unzip();
+        let res = sqlx::query("UPDATE user SET otp_channel = ?, otp_destination = ? WHERE id = ?")
+            .bind(channel)
+            .bind(destination)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        expect_one_row(id, res.rows_affected())
+    }
+
+    async fn permissions(&self, id: i64) -> Result<Vec<Permission>, StoreError> {
+        let query = sqlx::query_scalar("SELECT permission FROM user_permission WHERE user_id = ?")
+            .bind(id)
+            .fetch_all(&self.pool);
+        Ok(time_query("permissions", query).await?)
+    }
+
+    async fn grant(&self, id: i64, permission: Permission) -> Result<(), StoreError> {
+        if self.find_by_id(id).await?.is_none() {
+            return Err(StoreError::NoSuchUser(id));
+        }
+
+        sqlx::query("INSERT OR IGNORE INTO user_permission (user_id, permission) VALUES (?, ?)")
+            .bind(id)
+            .bind(permission)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn revoke(&self, id: i64, permission: Permission) -> Result<(), StoreError> {
+        sqlx::query("DELETE FROM user_permission WHERE user_id = ? AND permission = ?")
+            .bind(id)
+            .bind(permission)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn group_permissions(&self, id: i64) -> Result<Vec<Permission>, StoreError> {
+        let query = group::user_permissions(&self.pool, id);
+        Ok(time_query("group_permissions", query).await?)
+    }
+}
+
+/// Keeps everything in memory, so it's all gone once it's dropped. Handy for poking at the auth
+/// logic without a database file, or for embedding somewhere that brings its own storage.
+#[derive(Debug, Default)]
+pub struct MemoryUserStore {
+    users: Mutex<HashMap<i64, User>>,
+    permissions: Mutex<HashMap<i64, HashSet<Permission>>>,
+}
+
+impl MemoryUserStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `f` on the user with the given id, if there is one.
+    fn modify(&self, id: i64, f: impl FnOnce(&mut User)) -> Result<(), StoreError> {
+        let mut users = self.users.lock().expect("user store lock shouldn't be poisoned");
+        let user = users.get_mut(&id).ok_or(StoreError::NoSuchUser(id))?;
+        f(user);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl UserStore for MemoryUserStore {
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>, StoreError> {
+        let users = self.users.lock().expect("user store lock shouldn't be poisoned");
+        Ok(users.values().find(|u| u.username == username).cloned())
+    }
+
+    async fn find_by_id(&self, id: i64) -> Result<Option<User>, StoreError> {
+        let users = self.users.lock().expect("user store lock shouldn't be poisoned");
+        Ok(users.get(&id).cloned())
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, StoreError> {
+        let users = self.users.lock().expect("user store lock shouldn't be poisoned");
+        Ok(users
+            .values()
+            .find(|u| u.email.as_deref() == Some(email))
+            .cloned())
+    }
+
+    async fn create_with_email(
+        &self,
+        username: &str,
+        pw_hash: &str,
+        email: Option<&str>,
+    ) -> Result<User, StoreError> {
+        let mut users = self.users.lock().expect("user store lock shouldn't be poisoned");
+        if users.values().any(|u| u.username == username) {
+            return Err(StoreError::UsernameTaken);
+        }
+        if email.is_some() && users.values().any(|u| u.email.as_deref() == email) {
+            return Err(StoreError::EmailTaken);
+        }
+
+        // Nothing gets deleted, so this behaves like the AUTOINCREMENT column
+        let id = users.keys().max().map_or(1, |max| max + 1);
+        let user = User {
+            id,
+            username: username.to_owned(),
+            pw_hash: pw_hash.to_owned(),
+            status: AccountStatus::default(),
+            locked_until: None,
+            status_reason: None,
+            role: Role::default(),
+            auth_source: None,
+            email: email.map(str::to_owned),
+            display_name: None,
+            avatar: None,
+            timezone: None,
+            locale: None,
+            otp_channel: None,
+            otp_destination: None,
+        };
+        users.insert(id, user.clone());
+
+        Ok(user)
+    }
+
+    async fn update_password(&self, id: i64, pw_hash: &str) -> Result<(), StoreError> {
+        self.modify(id, |u| u.pw_hash = pw_hash.to_owned())
+    }
+
+    async fn set_status(
+        &self,
+        id: i64,
+        status: AccountStatus,
+        locked_until: Option<i64>,
+        reason: Option<&str>,
+    ) -> Result<(), StoreError> {
+        self.modify(id, |u| {
+            u.status = status;
+            u.locked_until = locked_until;
+            u.status_reason = reason.map(str::to_owned);
+        })
+    }
+
+    async fn set_role(&self, id: i64, role: Role) -> Result<(), StoreError> {
+        self.modify(id, |u| u.role = role)
+    }
+
+    async fn set_auth_source(&self, id: i64, source: &str) -> Result<(), StoreError> {
+        self.modify(id, |u| u.auth_source = Some(source.to_owned()))
+    }
+
+    async fn set_email(&self, id: i64, email: Option<&str>) -> Result<(), StoreError> {
+        let mut users = self.users.lock().expect("user store lock shouldn't be poisoned");
+        if email.is_some() && users.values().any(|u| u.id != id && u.email.as_deref() == email) {
+            return Err(StoreError::EmailTaken);
+        }
+
+        let user = users.get_mut(&id).ok_or(StoreError::NoSuchUser(id))?;
+        user.email = email.map(str::to_owned);
+        Ok(())
+    }
+
+    async fn set_profile(
+        &self,
+        id: i64,
+        display_name: Option<&str>,
+        timezone: Option<&str>,
+        locale: Option<&str>,
+    ) -> Result<(), StoreError> {
+        self.modify(id, |u| {
+            u.display_name = display_name.map(str::to_owned);
+            u.timezone = timezone.map(str::to_owned);
+            u.locale = locale.map(str::to_owned);
+        })
+    }
+
+    async fn set_avatar(&self, id: i64, avatar: Option<&str>) -> Result<(), StoreError> {
+        self.modify(id, |u| u.avatar = avatar.map(str::to_owned))
+    }
+
+    async fn set_second_factor(
+        &self,
+        id: i64,
+        factor: Option<(OtpChannel, &str)>,
+    ) -> Result<(), StoreError> {
+        self.modify(id, |u| {
+            u.otp_channel = factor.map(|(channel, _)| channel);
+            u.otp_destination = factor.map(|(_, destination)| destination.to_owned());
+        })
+    }
+
+    async fn permissions(&self, id: i64) -> Result<Vec<Permission>, StoreError> {
+        let permissions = self.permissions.lock().expect("user store lock shouldn't be poisoned");
+        Ok(permissions.get(&id).into_iter().flatten().copied().collect())
+    }
+
+    async fn grant(&self, id: i64, permission: Permission) -> Result<(), StoreError> {
+        // Checks they exist
+        self.modify(id, |_| ())?;
+
+        let mut permissions = self.permissions.lock().expect("user store lock shouldn't be poisoned");
+        permissions.entry(id).or_default().insert(permission);
+        Ok(())
+    }
+
+    async fn revoke(&self, id: i64, permission: Permission) -> Result<(), StoreError> {
+        let mut permissions = self.permissions.lock().expect("user store lock shouldn't be poisoned");
+        if let Some(granted) = permissions.get_mut(&id) {
+            granted.remove(&permission);
+        }
+        Ok(())
+    }
+
+    /// Groups only live in the database (see group.rs), so there's nothing to get here
+    async fn group_permissions(&self, _id: i64) -> Result<Vec<Permission>, StoreError> {
+        Ok(Vec::new())
+    }
+}
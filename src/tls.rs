@@ -0,0 +1,174 @@
+use axum::extract::Request;
+use axum::http::header;
+use axum::response::Redirect;
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use serde::Deserialize;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use crate::shutdown::Shutdown;
+
+fn default_reload_interval_seconds() -> u64 {
+    30
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct TlsConfig {
+    /// PEM, with any intermediates after the certificate
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+
+    /// How often to check whether the files have changed, e.g. after a renewal
+    #[serde(default = "default_reload_interval_seconds")]
+    pub reload_interval_seconds: u64,
+
+    /// Also listen here for plain HTTP, and send everyone to HTTPS
+    pub redirect_http_from: Option<SocketAddr>,
+}
+
+impl TlsConfig {
+    pub async fn load(&self) -> io::Result<RustlsConfig> {
+        RustlsConfig::from_pem_file(&self.cert_path, &self.key_path).await
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        Some((modified(&self.cert_path)?, modified(&self.key_path)?))
+    }
+
+    /// Keeps an eye on the certificate and key, and swaps them in when they change. Connections
+    /// that are already open keep the old ones.
+    pub fn watch(&self, rustls: RustlsConfig) {
+        let config = self.clone();
+        tokio::spawn(async move {
+            let mut last = config.modified();
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(config.reload_interval_seconds.max(1)));
+            loop {
+                interval.tick().await;
+                let now = config.modified();
+                if now.is_none() || now == last {
+                    continue;
+                }
+
+                // Renewals can write the cert and key one at a time, so a mismatch here just
+                // means trying again next time
+                match rustls
+                    .reload_from_pem_file(&config.cert_path, &config.key_path)
+                    .await
+                {
+                    Ok(()) => {
+                        tracing::info!(path = ?config.cert_path, "Reloaded TLS certificate");
+                        last = now;
+                    }
+                    Err(err) => tracing::error!(%err, "Couldn't reload TLS certificate"),
+                }
+            }
+        });
+    }
+}
+
+/// `host` without its port, leaving IPv6 addresses like `[::1]` in one piece.
+fn without_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        // More colons before the last one means it's part of an address, unless that's bracketed
+        Some((name, port))
+            if (name.ends_with(']') || !name.contains(':'))
+                && !port.is_empty()
+                && port.chars().all(|c| c.is_ascii_digit()) =>
+        {
+            name
+        }
+        _ => host,
+    }
+}
+
+/// Where to send a plain HTTP request. `public_url` wins if it's https, otherwise it's the same
+/// host on the port we're serving HTTPS on.
+fn https_url(request: &Request, public_url: &str, https_port: u16) -> Option<String> {
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |p| p.as_str());
+
+    if public_url.starts_with("https://") {
+        return Some(format!("{public_url}{path}"));
+    }
+
+    // Ignore whatever port they came in on
+    let host = without_port(request.headers().get(header::HOST)?.to_str().ok()?);
+
+    Some(match https_port {
+        443 => format!("https://{host}{path}"),
+        port => format!("https://{host}:{port}{path}"),
+    })
+}
+
+/// Listens for plain HTTP on `addr` and redirects everything to HTTPS, until `shutdown` says to
+/// stop.
+pub async fn redirect_http(
+    addr: SocketAddr,
+    public_url: String,
+    https_port: u16,
+    shutdown: Shutdown,
+) -> io::Result<()> {
+    let app = Router::new().fallback(move |request: Request| {
+        let public_url = public_url.clone();
+        async move {
+            match https_url(&request, &public_url, https_port) {
+                Some(url) => Ok(Redirect::permanent(&url)),
+                None => Err((axum::http::StatusCode::BAD_REQUEST, "Missing Host header")),
+            }
+        }
+    });
+
+    tracing::info!("redirecting http://{addr} to https");
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let server =
+        axum::serve(listener, app).with_graceful_shutdown(shutdown.stop.clone().cancelled_owned());
+    shutdown.drain(server).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+
+    fn request(host: &str) -> Request {
+        Request::builder()
+            .uri("/login?next=%2F")
+            .header(header::HOST, host)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn strips_ports() {
+        assert_eq!(without_port("example.org"), "example.org");
+        assert_eq!(without_port("example.org:8080"), "example.org");
+        assert_eq!(without_port("[::1]"), "[::1]");
+        assert_eq!(without_port("[2001:db8::1]:8080"), "[2001:db8::1]");
+    }
+
+    #[test]
+    fn redirects_to_the_https_port() {
+        let url = |host| https_url(&request(host), "http://localhost:3000", 8443);
+        assert_eq!(url("example.org").unwrap(), "https://example.org:8443/login?next=%2F");
+        assert_eq!(url("example.org:8080").unwrap(), "https://example.org:8443/login?next=%2F");
+        assert_eq!(url("[::1]").unwrap(), "https://[::1]:8443/login?next=%2F");
+        assert_eq!(url("[::1]:8080").unwrap(), "https://[::1]:8443/login?next=%2F");
+
+        let url = https_url(&request("[::1]:8080"), "http://localhost:3000", 443);
+        assert_eq!(url.unwrap(), "https://[::1]/login?next=%2F");
+    }
+
+    #[test]
+    fn prefers_an_https_public_url() {
+        let url = https_url(&request("10.0.0.1:8080"), "https://auth.example.org", 8443);
+        assert_eq!(url.unwrap(), "https://auth.example.org/login?next=%2F");
+    }
+}
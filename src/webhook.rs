@@ -0,0 +1,605 @@
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::FromRow;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::auth::User;
+use crate::otp::now;
+use crate::state::AppState;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// `t=<unix time>,v1=<hex HMAC-SHA256 of "<t>.<body>">`
+pub const SIGNATURE_HEADER: &str = "Webhook-Signature";
+/// Same for every endpoint an event goes to, and every retry, so receivers can spot repeats
+pub const ID_HEADER: &str = "Webhook-Id";
+pub const EVENT_HEADER: &str = "Webhook-Event";
+
+/// After this many goes it's marked failed, and only goes again if someone retries it by hand
+const MAX_ATTEMPTS: i64 = 10;
+/// Doubles after every failed go, up to `MAX_BACKOFF_SECONDS`
+const BASE_BACKOFF_SECONDS: i64 = 30;
+const MAX_BACKOFF_SECONDS: i64 = 6 * 60 * 60;
+/// How often to look for retries that have come due. New events don't wait for this.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+const TIMEOUT: Duration = Duration::from_secs(10);
+const BATCH: i64 = 20;
+/// Signatures older than this are turned down by [`verify`], so old requests can't be replayed
+const TOLERANCE_SECONDS: i64 = 5 * 60;
+
+/// Something other services might want to know about. The names are what goes in the payload and
+/// the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WebhookEvent {
+    #[serde(rename = "user.signed_up")]
+    UserSignedUp,
+    /// However they did it: password, sign in link or second factor
+    #[serde(rename = "user.logged_in")]
+    UserLoggedIn,
+    #[serde(rename = "user.password_changed")]
+    UserPasswordChanged,
+    /// Accounts can't be deleted, so this is as close as it gets
+    #[serde(rename = "user.disabled")]
+    UserDisabled,
+    /// Sent from the admin page to check an endpoint's working
+    #[serde(rename = "webhook.test")]
+    Test,
+}
+
+impl WebhookEvent {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WebhookEvent::UserSignedUp => "user.signed_up",
+            WebhookEvent::UserLoggedIn => "user.logged_in",
+            WebhookEvent::UserPasswordChanged => "user.password_changed",
+            WebhookEvent::UserDisabled => "user.disabled",
+            WebhookEvent::Test => "webhook.test",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Signs everything sent here, so they can check it came from us
+    pub secret: String,
+    /// Which events to send, everything if it's empty. Tests always go.
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+}
+
+impl Debug for WebhookConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebhookConfig")
+            .field("url", &self.url)
+            .field("secret", &"Wouldn't you like to know")
+            .field("events", &self.events)
+            .finish()
+    }
+}
+
+impl WebhookConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let url = reqwest::Url::parse(&self.url)
+            .map_err(|err| format!("webhooks: {:?} isn't a URL: {err}", self.url))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(format!("webhooks: {:?} needs to be http or https", self.url));
+        }
+        if self.secret.is_empty() {
+            return Err(format!("webhooks: {:?} needs a secret", self.url));
+        }
+        Ok(())
+    }
+
+    fn wants(&self, event: WebhookEvent) -> bool {
+        event == WebhookEvent::Test || self.events.is_empty() || self.events.contains(&event)
+    }
+}
+
+/// Wakes up the delivery worker, and the client it sends with.
+#[derive(Debug, Clone)]
+pub struct Webhooks {
+    wake: Arc<Notify>,
+    client: reqwest::Client,
+}
+
+impl Default for Webhooks {
+    fn default() -> Self {
+        Self {
+            wake: Arc::new(Notify::new()),
+            client: reqwest::Client::builder()
+                .timeout(TIMEOUT)
+                .build()
+                .expect("a client with just a timeout should always build"),
+        }
+    }
+}
+
+impl Webhooks {
+    /// For when something's been queued that should go straight away
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(rename_all = "kebab-case")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    /// Gave up after `MAX_ATTEMPTS`
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Failed => "failed",
+        }
+    }
+}
+
+#[derive(FromRow, Debug, Clone)]
+pub struct Delivery {
+    pub id: i64,
+    pub event_id: String,
+    pub event: String,
+    pub url: String,
+    pub payload: String,
+    pub status: DeliveryStatus,
+    pub attempts: i64,
+    pub next_attempt_at: i64,
+    /// HTTP status from the last go, if it got that far
+    pub last_response: Option<i64>,
+    pub last_error: Option<String>,
+    pub created_at: i64,
+    pub delivered_at: Option<i64>,
+}
+
+/// What's sent as the body
+#[derive(Serialize)]
+struct Payload<'a> {
+    id: &'a str,
+    event: &'a str,
+    created_at: i64,
+    data: serde_json::Value,
+}
+
+/// The bits of a user that are safe to send about
+pub fn user_data(user: &User) -> serde_json::Value {
+    serde_json::json!({
+        "user_id": user.id,
+        "username": user.username,
+        "email": user.email,
+        "role": user.role.as_str(),
+    })
+}
+
+/// Someone made themselves an account on the sign up page
+pub async fn signed_up(state: &AppState, user: &User) {
+    enqueue(state, WebhookEvent::UserSignedUp, user_data(user)).await;
+}
+
+/// `method` is how they got in: `password`, `magic-link` or `second-factor`
+pub async fn logged_in(state: &AppState, user: &User, method: &str) {
+    let mut data = user_data(user);
+    data["method"] = method.into();
+    enqueue(state, WebhookEvent::UserLoggedIn, data).await;
+}
+
+/// They changed their password from their account page
+pub async fn password_changed(state: &AppState, user: &User) {
+    enqueue(state, WebhookEvent::UserPasswordChanged, user_data(user)).await;
+}
+
+/// Someone disabled their account, giving `reason` if they said why
+pub async fn disabled(state: &AppState, user: &User, reason: Option<&str>) {
+    let mut data = user_data(user);
+    data["reason"] = reason.into();
+    enqueue(state, WebhookEvent::UserDisabled, data).await;
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn mac(secret: &str, timestamp: i64, body: &str) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(format!("{timestamp}.{body}").as_bytes());
+    mac
+}
+
+/// What goes in [`SIGNATURE_HEADER`]
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let signature = mac(secret, timestamp, body).finalize().into_bytes();
+    format!("t={timestamp},v1={}", hex(&signature))
+}
+
+/// Checks a [`SIGNATURE_HEADER`] is from someone with `secret` and recent. For receivers, like
+/// `rust-auth receive-webhooks`.
+pub fn verify(secret: &str, header: &str, body: &str) -> Result<(), &'static str> {
+    let mut timestamp = None;
+    let mut signatures = vec![];
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", value)) => timestamp = value.parse::<i64>().ok(),
+            Some(("v1", value)) => signatures.extend(unhex(value)),
+            _ => {}
+        }
+    }
+
+    let timestamp = timestamp.ok_or("no timestamp")?;
+    if (now() - timestamp).abs() > TOLERANCE_SECONDS {
+        return Err("too old");
+    }
+    if signatures
+        .iter()
+        .any(|signature| mac(secret, timestamp, body).verify_slice(signature).is_ok())
+    {
+        Ok(())
+    } else {
+        Err("signature doesn't match")
+    }
+}
+
+/// Queues `event` for every endpoint that wants it. Like the audit log, failing to queue
+/// shouldn't stop whatever it's about, so errors are only logged.
+pub async fn enqueue(state: &AppState, event: WebhookEvent, data: serde_json::Value) {
+    let config = state.live.get();
+    let endpoints: Vec<_> = config.webhooks.iter().filter(|w| w.wants(event)).collect();
+    if endpoints.is_empty() {
+        return;
+    }
+
+    let mut id = [0; 16];
+    rand::thread_rng().fill_bytes(&mut id);
+    let id = hex(&id);
+    let payload = Payload {
+        id: &id,
+        event: event.as_str(),
+        created_at: now(),
+        data,
+    };
+    let payload = match serde_json::to_string(&payload) {
+        Ok(payload) => payload,
+        Err(err) => {
+            tracing::error!(%err, event = event.as_str(), "Couldn't make webhook payload");
+            return;
+        }
+    };
+
+    for endpoint in endpoints {
+        let res = sqlx::query(
+            "INSERT INTO webhook_delivery (event_id, event, url, payload) VALUES (?, ?, ?, ?)",
+        )
+        .bind(&id)
+        .bind(event.as_str())
+        .bind(&endpoint.url)
+        .bind(&payload)
+        .execute(&state.pool)
+        .await;
+
+        if let Err(err) = res {
+            tracing::error!(%err, event = event.as_str(), endpoint.url, "Couldn't queue webhook");
+        }
+    }
+    state.webhooks.wake();
+}
+
+/// Newest first
+pub async fn list(state: &AppState, limit: i64) -> Result<Vec<Delivery>, sqlx::Error> {
+    sqlx::query_as("SELECT * FROM webhook_delivery ORDER BY id DESC LIMIT ?")
+        .bind(limit)
+        .fetch_all(&state.pool)
+        .await
+}
+
+/// Puts a delivery back in the queue to go straight away, with all its tries back, whatever
+/// happened to it before. Gives back whether there was one to retry.
+pub async fn retry(state: &AppState, id: i64) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query(
+        "UPDATE webhook_delivery SET status = 'pending', attempts = 0, next_attempt_at = unixepoch()
+         WHERE id = ?",
+    )
+    .bind(id)
+    .execute(&state.pool)
+    .await?;
+
+    state.webhooks.wake();
+    Ok(res.rows_affected() == 1)
+}
+
+fn backoff(attempts: i64) -> i64 {
+    let doublings = attempts.saturating_sub(1).clamp(0, 20) as u32;
+    BASE_BACKOFF_SECONDS
+        .saturating_mul(2_i64.pow(doublings))
+        .min(MAX_BACKOFF_SECONDS)
+}
+
+/// One go at sending it. Gives back the status code, or why it didn't get one.
+async fn send(state: &AppState, delivery: &Delivery) -> Result<u16, String> {
+    let config = state.live.get();
+    // Secrets come from the config each time, so changing one doesn't strand what's queued
+    let Some(endpoint) = config.webhooks.iter().find(|w| w.url == delivery.url) else {
+        return Err("Endpoint isn't in the config any more".to_owned());
+    };
+
+    let res = state
+        .webhooks
+        .client
+        .post(&delivery.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(ID_HEADER, &delivery.event_id)
+        .header(EVENT_HEADER, &delivery.event)
+        .header(SIGNATURE_HEADER, sign(&endpoint.secret, now(), &delivery.payload))
+        .body(delivery.payload.clone())
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+    Ok(res.status().as_u16())
+}
+
+async fn attempt(state: &AppState, delivery: &Delivery) -> Result<(), sqlx::Error> {
+    let res = send(state, delivery).await;
+    let attempts = delivery.attempts + 1;
+
+    let (response, error) = match res {
+        Ok(status) if (200..300).contains(&status) => {
+            sqlx::query(
+                "UPDATE webhook_delivery
+                 SET status = 'delivered', attempts = ?, last_response = ?, last_error = NULL,
+                     delivered_at = unixepoch()
+                 WHERE id = ?",
+            )
+            .bind(attempts)
+            .bind(status)
+            .bind(delivery.id)
+            .execute(&state.pool)
+            .await?;
+            tracing::debug!(delivery.id, delivery.event, delivery.url, "Delivered webhook");
+            return Ok(());
+        }
+        Ok(status) => (Some(status), format!("Responded with {status}")),
+        Err(err) => (None, err),
+    };
+
+    let status = if attempts >= MAX_ATTEMPTS {
+        DeliveryStatus::Failed
+    } else {
+        DeliveryStatus::Pending
+    };
+    tracing::warn!(
+        delivery.id,
+        delivery.event,
+        delivery.url,
+        attempts,
+        error,
+        status = status.as_str(),
+        "Webhook didn't go through"
+    );
+    sqlx::query(
+        "UPDATE webhook_delivery
+         SET status = ?, attempts = ?, next_attempt_at = ?, last_response = ?, last_error = ?
+         WHERE id = ?",
+    )
+    .bind(status)
+    .bind(attempts)
+    .bind(now() + backoff(attempts))
+    .bind(response)
+    .bind(error)
+    .bind(delivery.id)
+    .execute(&state.pool)
+    .await?;
+    Ok(())
+}
+
+/// Sends everything that's due, a batch at a time, until it's all gone or we're told to `stop`.
+async fn deliver_due(state: &AppState, stop: &CancellationToken) -> Result<(), sqlx::Error> {
+    loop {
+        let due: Vec<Delivery> = sqlx::query_as(
+            "SELECT * FROM webhook_delivery WHERE status = 'pending' AND next_attempt_at <= ?
+             ORDER BY next_attempt_at, id LIMIT ?",
+        )
+        .bind(now())
+        .bind(BATCH)
+        .fetch_all(&state.pool)
+        .await?;
+
+        for delivery in &due {
+            if stop.is_cancelled() {
+                return Ok(());
+            }
+            attempt(state, delivery).await?;
+        }
+        if (due.len() as i64) < BATCH {
+            return Ok(());
+        }
+    }
+}
+
+/// Prints whatever's sent to it and checks it's signed by one of `secrets`, or doesn't check if
+/// there aren't any. Answers with `status`, or 401 if the signature's wrong, so retries can be
+/// tried out too. Runs until Ctrl+C.
+pub async fn receive(
+    addr: std::net::SocketAddr,
+    secrets: Vec<String>,
+    status: axum::http::StatusCode,
+) -> std::io::Result<()> {
+    use axum::http::{HeaderMap, StatusCode, Uri};
+    use axum::Router;
+
+    let handler = move |uri: Uri, headers: HeaderMap, body: String| {
+        let secrets = secrets.clone();
+        async move {
+            let header = |name| headers.get(name).and_then(|v| v.to_str().ok()).unwrap_or("");
+            println!(
+                "{} {} to {uri} (id {})",
+                crate::otp::now(),
+                header(EVENT_HEADER),
+                header(ID_HEADER)
+            );
+
+            // Good if any of them match, otherwise why the first one didn't
+            let verified = secrets
+                .iter()
+                .map(|secret| verify(secret, header(SIGNATURE_HEADER), &body))
+                .reduce(|first, next| first.or(next));
+            match verified {
+                None => println!("  signature: not checked, no secret"),
+                Some(Ok(())) => println!("  signature: ok"),
+                Some(Err(why)) => println!("  signature: BAD, {why}"),
+            }
+            match serde_json::from_str::<serde_json::Value>(&body) {
+                Ok(json) => println!("{json:#}"),
+                Err(_) => println!("{body}"),
+            }
+
+            match verified {
+                Some(Err(_)) => StatusCode::UNAUTHORIZED,
+                _ => status,
+            }
+        }
+    };
+
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    println!("Listening for webhooks on http://{addr}, answering with {status}");
+    axum::serve(listener, Router::new().fallback(handler))
+        .with_graceful_shutdown(crate::shutdown::signal())
+        .await
+}
+
+/// Works through the queue in the background until `stop`. The delivery it's in the middle of
+/// is finished and recorded before it stops, so it's not sent again next time. Anything still
+/// waiting is picked up then.
+pub fn spawn_worker(state: AppState, stop: CancellationToken) {
+    state.tasks.clone().spawn(async move {
+        loop {
+            if let Err(err) = deliver_due(&state, &stop).await {
+                tracing::error!(%err, "Couldn't work through the webhook queue");
+            }
+            tokio::select! {
+                _ = stop.cancelled() => return,
+                _ = state.webhooks.wake.notified() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::NO_PASSWORD;
+    use crate::state::test_state;
+
+    const CONFIG: &str = r#"
+        [[webhooks]]
+        url = "http://127.0.0.1:9/everything"
+        secret = "hunter2"
+
+        [[webhooks]]
+        url = "http://127.0.0.1:9/disabled"
+        secret = "hunter2"
+        events = ["user.disabled"]
+    "#;
+
+    /// What's been queued, as (event, url, data)
+    async fn queued(state: &AppState) -> Vec<(String, String, serde_json::Value)> {
+        list(state, 100)
+            .await
+            .unwrap()
+            .into_iter()
+            .rev()
+            .map(|delivery| {
+                let payload: serde_json::Value = serde_json::from_str(&delivery.payload).unwrap();
+                assert_eq!(payload["event"], delivery.event);
+                (delivery.event, delivery.url, payload["data"].clone())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn password_changes_go_to_whoever_wants_them() {
+        let state = test_state(CONFIG).await;
+        let alice = state.auth.store.create("alice", NO_PASSWORD).await.unwrap();
+
+        password_changed(&state, &alice).await;
+        let queued = queued(&state).await;
+        assert_eq!(queued.len(), 1);
+        let (event, url, data) = &queued[0];
+        assert_eq!(event, "user.password_changed");
+        assert_eq!(url, "http://127.0.0.1:9/everything");
+        assert_eq!(data["username"], "alice");
+        assert_eq!(data["role"], "user");
+    }
+
+    #[tokio::test]
+    async fn disabling_says_why() {
+        let state = test_state(CONFIG).await;
+        let alice = state.auth.store.create("alice", NO_PASSWORD).await.unwrap();
+
+        disabled(&state, &alice, Some("Left the company")).await;
+        let queued = queued(&state).await;
+        assert_eq!(queued.len(), 2);
+        for (event, _, data) in &queued {
+            assert_eq!(event, "user.disabled");
+            assert_eq!(data["user_id"], alice.id);
+            assert_eq!(data["reason"], "Left the company");
+        }
+    }
+
+    #[test]
+    fn signatures_round_trip() {
+        let body = r#"{"event":"webhook.test"}"#;
+        let header = sign("hunter2", now(), body);
+        assert_eq!(verify("hunter2", &header, body), Ok(()));
+
+        assert_eq!(verify("hunter3", &header, body), Err("signature doesn't match"));
+        assert_eq!(verify("hunter2", &header, "{}"), Err("signature doesn't match"));
+        assert_eq!(verify("hunter2", "v1=00", body), Err("no timestamp"));
+        // Someone replaying an old one
+        let old = sign("hunter2", now() - TOLERANCE_SECONDS - 1, body);
+        assert_eq!(verify("hunter2", &old, body), Err("too old"));
+    }
+
+    #[test]
+    fn any_signature_will_do() {
+        // Several v1s are fine as long as one of them matches
+        let body = "{}";
+        let timestamp = now();
+        let old = sign("old secret", timestamp, body);
+        let new = sign("new secret", timestamp, body);
+        let both = format!("{new},{}", old.split_once(',').unwrap().1);
+        assert_eq!(verify("old secret", &both, body), Ok(()));
+        assert_eq!(verify("new secret", &both, body), Ok(()));
+    }
+
+    #[test]
+    fn backing_off() {
+        let schedule: Vec<i64> = (1..=6).map(backoff).collect();
+        assert_eq!(schedule, [30, 60, 120, 240, 480, 960]);
+        assert_eq!(backoff(0), BASE_BACKOFF_SECONDS);
+        assert_eq!(backoff(MAX_ATTEMPTS * 10), MAX_BACKOFF_SECONDS);
+        assert_eq!(backoff(i64::MAX), MAX_BACKOFF_SECONDS);
+    }
+}
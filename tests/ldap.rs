@@ -0,0 +1,120 @@
+//! Mostly runs against a real directory, so those are ignored by default. Start the one from the README
+//! (or point `LDAP_TEST_URL` at another) and run `cargo test --features ssr -- --ignored`.
+#![cfg(feature = "ssr")]
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use rust_auth::auth::{Authenticator, Credentials, Role, NO_PASSWORD};
+use rust_auth::ldap::{LdapAuthenticator, LdapConfig, LdapSearchConfig};
+use rust_auth::store::{MemoryUserStore, UserStore};
+
+/// The admin account the `osixia/openldap` image comes with.
+const ADMIN_DN: &str = "cn=admin,dc=example,dc=org";
+const ADMIN_PASSWORD: &str = "admin";
+
+fn config() -> LdapConfig {
+    LdapConfig {
+        url: std::env::var("LDAP_TEST_URL").unwrap_or_else(|_| "ldap://localhost:389".to_owned()),
+        bind_dn_template: Some("cn={username},dc=example,dc=org".to_owned()),
+        search: None,
+        starttls: false,
+        no_tls_verify: false,
+        group_attribute: "memberOf".to_owned(),
+        group_roles: HashMap::new(),
+        timeout_seconds: 5,
+    }
+}
+
+fn creds(username: &str, password: &str) -> Credentials {
+    Credentials {
+        username: username.to_owned(),
+        password: password.to_owned(),
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs an LDAP server"]
+async fn provisions_directory_users() {
+    let store: Arc<dyn UserStore> = Arc::new(MemoryUserStore::new());
+    let ldap = LdapAuthenticator::new(config(), store.clone());
+
+    let user = ldap.authenticate(&creds("admin", ADMIN_PASSWORD)).await.unwrap().unwrap();
+    assert_eq!(user.username, "admin");
+    assert_eq!(user.pw_hash, NO_PASSWORD);
+
+    // Same user the second time round
+    let again = ldap.authenticate(&creds("Admin", ADMIN_PASSWORD)).await.unwrap().unwrap();
+    assert_eq!(again.id, user.id);
+}
+
+#[tokio::test]
+#[ignore = "needs an LDAP server"]
+async fn rejects_wrong_passwords() {
+    let ldap = LdapAuthenticator::new(config(), Arc::new(MemoryUserStore::new()));
+
+    assert!(ldap.authenticate(&creds("admin", "nope")).await.unwrap().is_none());
+    assert!(ldap.authenticate(&creds("admin", "")).await.unwrap().is_none());
+    assert!(ldap.authenticate(&creds("nobody", "nope")).await.unwrap().is_none());
+}
+
+#[tokio::test]
+#[ignore = "needs an LDAP server"]
+async fn leaves_local_accounts_alone() {
+    let store: Arc<dyn UserStore> = Arc::new(MemoryUserStore::new());
+    let local = store.create("admin", &bcrypt::hash("local", 4).unwrap()).await.unwrap();
+    store.set_role(local.id, Role::Admin).await.unwrap();
+
+    // admin isn't in this group, so if we did link them up they'd be demoted
+    let mut config = config();
+    config.group_roles.insert("cn=nobody,dc=example,dc=org".to_owned(), Role::User);
+    let ldap = LdapAuthenticator::new(config, store.clone());
+
+    assert!(ldap.authenticate(&creds("admin", ADMIN_PASSWORD)).await.unwrap().is_none());
+    let local = store.find_by_id(local.id).await.unwrap().unwrap();
+    assert_eq!(local.role, Role::Admin);
+}
+
+#[tokio::test]
+#[ignore = "needs an LDAP server"]
+async fn searches_with_a_service_account() {
+    let mut config = config();
+    config.bind_dn_template = None;
+    config.search = Some(LdapSearchConfig {
+        base_dn: "dc=example,dc=org".to_owned(),
+        filter: "(cn={username})".to_owned(),
+        bind_dn: Some(ADMIN_DN.to_owned()),
+        bind_password: Some(ADMIN_PASSWORD.to_owned()),
+    });
+    config.validate().unwrap();
+    let ldap = LdapAuthenticator::new(config, Arc::new(MemoryUserStore::new()));
+
+    assert!(ldap.authenticate(&creds("admin", ADMIN_PASSWORD)).await.unwrap().is_some());
+    assert!(ldap.authenticate(&creds("admin", "nope")).await.unwrap().is_none());
+}
+
+#[tokio::test]
+#[ignore = "needs an LDAP server"]
+async fn checks_passwords_without_provisioning() {
+    let store: Arc<dyn UserStore> = Arc::new(MemoryUserStore::new());
+    let ldap = LdapAuthenticator::new(config(), store.clone());
+    let user = ldap.authenticate(&creds("admin", ADMIN_PASSWORD)).await.unwrap().unwrap();
+
+    assert!(ldap.verify(&user, ADMIN_PASSWORD).await.unwrap());
+    assert!(!ldap.verify(&user, "nope").await.unwrap());
+    assert!(!ldap.verify(&user, "").await.unwrap());
+}
+
+#[tokio::test]
+async fn gives_up_on_servers_that_dont_answer() {
+    // Connections get accepted, but nothing's ever said back
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut config = config();
+    config.url = format!("ldap://{}", listener.local_addr().unwrap());
+    config.timeout_seconds = 1;
+    let ldap = LdapAuthenticator::new(config, Arc::new(MemoryUserStore::new()));
+
+    let started = std::time::Instant::now();
+    assert!(ldap.authenticate(&creds("admin", ADMIN_PASSWORD)).await.is_err());
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+}